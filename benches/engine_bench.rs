@@ -0,0 +1,236 @@
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use kvs::{FlushMode, KvStore, KvsEngine, ShardedKvStore, SledKvsEngine, StoreConfig};
+use rand::prelude::*;
+use std::fs;
+use std::hint::black_box;
+use std::thread;
+use tempfile::TempDir;
+
+fn set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    group.bench_function("kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 0..100 {
+                    store.set(format!("key{}", i), "value".to_owned()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("sled", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(db, _temp_dir)| {
+                for i in 0..100 {
+                    db.set(format!("key{}", i), "value".to_owned()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("sled_background_flush", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let config = StoreConfig {
+                    flush_mode: FlushMode::Background,
+                    ..StoreConfig::default()
+                };
+                (SledKvsEngine::open_with_config(temp_dir.path(), config).unwrap(), temp_dir)
+            },
+            |(db, _temp_dir)| {
+                for i in 0..100 {
+                    db.set(format!("key{}", i), "value".to_owned()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// Sets with 1KB keys, where copying the key is a noticeable part of the cost.
+fn set_long_key_bench(c: &mut Criterion) {
+    let keys: Vec<String> = (0..100).map(|i| format!("{:01024}", i)).collect();
+    c.bench_function("set_long_key_kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), keys.clone(), temp_dir)
+            },
+            |(store, keys, _temp_dir)| {
+                for key in keys {
+                    store.set(key, "value".to_owned()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    let mut rng = StdRng::seed_from_u64(42);
+    group.bench_function("kvs", |b| {
+        b.iter(|| {
+            let key = format!("key{}", rng.random_range(0..1000));
+            black_box(store.get(key).unwrap());
+        })
+    });
+
+    let temp_dir = TempDir::new().unwrap();
+    let db = SledKvsEngine::open(temp_dir.path()).unwrap();
+    for i in 0..1000 {
+        db.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    let mut rng = StdRng::seed_from_u64(42);
+    group.bench_function("sled", |b| {
+        b.iter(|| {
+            let key = format!("key{}", rng.random_range(0..1000));
+            black_box(db.get(key).unwrap());
+        })
+    });
+    group.finish();
+}
+
+// Random reads of 1MB values. Run with and without `--features mmap` to compare
+// the memory-mapped read path against buffered reads.
+fn get_large_bench(c: &mut Criterion) {
+    const KEYS: usize = 32;
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let value = "x".repeat(1024 * 1024);
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), value.clone()).unwrap();
+    }
+
+    let mut rng = StdRng::seed_from_u64(42);
+    c.bench_function("get_large_kvs", |b| {
+        b.iter(|| {
+            let key = format!("key{}", rng.random_range(0..KEYS));
+            black_box(store.get(key).unwrap());
+        })
+    });
+}
+
+// Opening a compacted store of 500k keys, once from the hint file written by
+// compaction and once by replaying the whole log.
+fn open_bench(c: &mut Criterion) {
+    const KEYS: usize = 500_000;
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    store.compact().unwrap();
+    drop(store);
+
+    let mut group = c.benchmark_group("open");
+    group.sample_size(10);
+    group.bench_function("kvs_hint", |b| {
+        b.iter(|| black_box(KvStore::open(temp_dir.path()).unwrap()))
+    });
+    fs::remove_file(temp_dir.path().join("wal.hint")).unwrap();
+    group.bench_function("kvs_full_scan", |b| {
+        b.iter(|| black_box(KvStore::open(temp_dir.path()).unwrap()))
+    });
+    group.finish();
+}
+
+// Restoring a dump of 10k keys, once with a `set` per key and once with
+// `bulk_load`.
+fn bulk_load_bench(c: &mut Criterion) {
+    const KEYS: usize = 10_000;
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    let mut dump = Vec::new();
+    store.export(&mut dump).unwrap();
+    drop(store);
+
+    let mut group = c.benchmark_group("import");
+    group.sample_size(10);
+    group.bench_function("kvs_set", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 0..KEYS {
+                    store.set(format!("key{}", i), "value".to_owned()).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("kvs_bulk_load", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| store.bulk_load(dump.as_slice()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+// Sets from 8 threads to distinct keys, with the store split into 1, 4 and 16
+// shards.
+fn concurrent_set_bench(c: &mut Criterion) {
+    const THREADS: usize = 8;
+    let mut group = c.benchmark_group("concurrent_set");
+    for shards in [1, 4, 16] {
+        group.bench_function(format!("kvs_{}_shards", shards), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    (ShardedKvStore::open(temp_dir.path(), shards).unwrap(), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    thread::scope(|s| {
+                        for t in 0..THREADS {
+                            let store = &store;
+                            s.spawn(move || {
+                                for i in 0..100 {
+                                    store.set(format!("key{}-{}", t, i), "value".to_owned()).unwrap();
+                                }
+                            });
+                        }
+                    })
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    set_long_key_bench,
+    get_bench,
+    get_large_bench,
+    open_bench,
+    bulk_load_bench,
+    concurrent_set_bench
+);
+criterion_main!(benches);
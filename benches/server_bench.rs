@@ -0,0 +1,159 @@
+use criterion::{BenchmarkGroup, Criterion, Throughput, criterion_group, criterion_main};
+use criterion::measurement::WallTime;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{AnyEngine, Engine, KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Starts `server` on a free port and returns its address.
+fn start<E: KvsEngine, P: ThreadPool + Send + 'static>(mut server: KvsServer<E, P>) -> SocketAddr {
+    let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    thread::spawn(move || server.run(addr));
+    addr
+}
+
+fn connect_to(addr: SocketAddr) -> KvsClient {
+    loop {
+        match KvsClient::connect(addr) {
+            Ok(client) => return client,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+// Starts a server with the given buffer sizes and returns a connected client.
+fn connect(temp_dir: &TempDir, read: usize, write: usize) -> KvsClient {
+    let server = KvsServer::new(
+        SledKvsEngine::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+    )
+    .with_buffer_sizes(read, write);
+    connect_to(start(server))
+}
+
+// Prints the median and 99th percentile of `latencies`, which Criterion's
+// report doesn't include.
+fn report_percentiles(name: &str, latencies: &mut [Duration]) {
+    // Benchmarks filtered out on the command line don't run at all.
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!("{}: p50 {:?}, p99 {:?}", name, percentile(50), percentile(99));
+}
+
+// End-to-end latency of single client requests to a `KvStore` server, for
+// different sizes of the shared queue pool. Every request is timed on its own so
+// that the percentiles can be reported next to Criterion's estimates.
+fn network_latency_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("network_latency");
+    for threads in [1, 2, 4, 8] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = KvsServer::new(
+            KvStore::open(temp_dir.path()).unwrap(),
+            SharedQueueThreadPool::new(threads).unwrap(),
+        );
+        let mut client = connect_to(start(server));
+        for i in 0..1000 {
+            client.set(format!("key{}", i), "value".to_owned()).unwrap();
+        }
+
+        for op in ["set", "get"] {
+            let name = format!("{}/{}", op, threads);
+            let mut latencies = Vec::new();
+            group.bench_function(&name, |b| {
+                b.iter_custom(|iters| {
+                    let mut total = Duration::ZERO;
+                    for i in 0..iters {
+                        let key = format!("key{}", i % 1000);
+                        let start = Instant::now();
+                        match op {
+                            "set" => client.set(key, "value".to_owned()).unwrap(),
+                            _ => assert!(client.get(key).unwrap().is_some()),
+                        }
+                        let latency = start.elapsed();
+                        latencies.push(latency);
+                        total += latency;
+                    }
+                    total
+                })
+            });
+            report_percentiles(&format!("network_latency/{}", name), &mut latencies);
+        }
+    }
+    group.finish();
+}
+
+// Gets of a 1MB value with the default 8KB buffers and with 256KB buffers.
+// The sled engine is used so that the cost of the store does not hide the I/O.
+fn large_value_bench(c: &mut Criterion) {
+    let value = "x".repeat(1024 * 1024);
+    let mut group = c.benchmark_group("server_large_value");
+    for (name, size) in [("8KB", 8 * 1024), ("256KB", 256 * 1024)] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut client = connect(&temp_dir, size, size);
+        client.set("key".to_owned(), value.clone()).unwrap();
+        group.bench_function(name, |b| b.iter(|| client.get("key".to_owned()).unwrap()));
+    }
+    group.finish();
+}
+
+const CLIENTS: usize = 8;
+const OPS_PER_CLIENT: usize = 100;
+const KEYS: usize = 1000;
+
+// Runs one round of `CLIENTS` threads, each sending `OPS_PER_CLIENT` requests
+// over its own connection: three gets for every set.
+fn hammer(clients: &mut [KvsClient]) {
+    thread::scope(|scope| {
+        for (c, client) in clients.iter_mut().enumerate() {
+            scope.spawn(move || {
+                for i in 0..OPS_PER_CLIENT {
+                    let key = format!("key{}", (c * OPS_PER_CLIENT + i) % KEYS);
+                    if i % 4 == 0 {
+                        client.set(key, "value".to_owned()).unwrap();
+                    } else {
+                        client.get(key).unwrap();
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn bench_pool<P: ThreadPool + Send + 'static>(
+    group: &mut BenchmarkGroup<WallTime>,
+    engine: Engine,
+    pool_name: &str,
+) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine_store = AnyEngine::open(engine, temp_dir.path()).unwrap();
+    // Every server starts from the same keys.
+    for i in 0..KEYS {
+        engine_store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    // A connection holds on to its worker until it closes, so every client
+    // needs a thread of its own.
+    let addr = start(KvsServer::new(engine_store, P::new(CLIENTS as u32).unwrap()));
+    let mut clients: Vec<KvsClient> = (0..CLIENTS).map(|_| connect_to(addr)).collect();
+    group.bench_function(format!("{}/{}", engine, pool_name), |b| b.iter(|| hammer(&mut clients)));
+}
+
+// Aggregate throughput of concurrent clients sending a mix of gets and sets,
+// for each thread pool and engine. Criterion reports it in requests per second.
+fn pool_throughput_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool_throughput");
+    group.throughput(Throughput::Elements((CLIENTS * OPS_PER_CLIENT) as u64));
+    for engine in [Engine::Kvs, Engine::Sled] {
+        bench_pool::<NaiveThreadPool>(&mut group, engine, "naive");
+        bench_pool::<SharedQueueThreadPool>(&mut group, engine, "shared_queue");
+        bench_pool::<RayonThreadPool>(&mut group, engine, "rayon");
+    }
+    group.finish();
+}
+
+criterion_group!(benches, large_value_bench, network_latency_bench, pool_throughput_bench);
+criterion_main!(benches);
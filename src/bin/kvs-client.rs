@@ -1,6 +1,11 @@
-use clap::{Parser, Subcommand};
-use kvs::{KvsClient, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{KvsClient, KvsError, Result};
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
 
 #[derive(Debug, Parser)]
 #[command(version)]
@@ -16,6 +21,35 @@ struct Args {
         default_value = "127.0.0.1:4000",
     )]
     addr: SocketAddr,
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_enum,
+        name = "FORMAT",
+        help = "Sets the output format",
+        default_value = "text"
+    )]
+    output: Output,
+    #[arg(long, global = true, name = "TOKEN", help = "Authenticates with the server using a token")]
+    token: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Text,
+    Json,
+}
+
+impl Output {
+    /// Prints the result of a `get`.
+    fn value(self, value: Option<String>) {
+        match (self, value) {
+            (Output::Text, Some(value)) => println!("{}", value),
+            (Output::Text, None) => println!("Key not found"),
+            (Output::Json, value) => println!("{}", json!({ "value": value })),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -37,25 +71,136 @@ enum Commands {
         #[arg(name = "KEY", help = "A string key")]
         key: String
     },
+    #[command(about = "Print the server's metrics in the Prometheus text format", name = "metrics")]
+    Metrics,
+    #[command(about = "Print the server's version, engine, uptime and request count", name = "info")]
+    Info,
+    #[command(about = "Print whether the server is ready for traffic and its engine works", name = "health")]
+    Health,
+    #[command(about = "Run the set/get/rm commands listed in a file, one per line", name = "exec")]
+    Exec {
+        #[arg(short, long, name = "FILE", help = "A file of commands")]
+        file: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    match (args.output, run(args)) {
+        (Output::Json, Err(e)) => {
+            eprintln!("{}", json!({ "error": e.to_string() }));
+            exit(1);
+        }
+        (_, result) => result,
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    let output = args.output;
     let mut client = KvsClient::connect(args.addr)?;
+    if let Some(token) = args.token {
+        client.authenticate(token)?;
+    }
     match args.cmd {
         Commands::Set { key, value } => {
             client.set(key, value)?;
         }
         Commands::Get { key } => {
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+            output.value(client.get(key)?);
+        }
+        Commands::Remove { key } => match client.remove(key) {
+            Ok(()) => {}
+            // Exit like `kvs rm` does; in JSON mode `main` reports the error.
+            Err(KvsError::KeyNotFound) if output == Output::Text => {
+                eprintln!("Key not found");
+                exit(1);
+            }
+            Err(e) => return Err(e),
+        },
+        Commands::Metrics => {
+            let metrics = client.metrics()?;
+            match output {
+                Output::Text => print!("{}", metrics),
+                Output::Json => println!("{}", json!({ "metrics": metrics })),
             }
         }
-        Commands::Remove { key } => {
-            client.remove(key)?;
+        Commands::Info => {
+            let info = client.server_info()?;
+            match output {
+                Output::Text => {
+                    println!("version: {}", info.version);
+                    println!("engine: {}", info.engine);
+                    println!("uptime: {}s", info.uptime_secs);
+                    println!("requests: {}", info.total_requests);
+                }
+                Output::Json => println!("{}", serde_json::to_string(&info)?),
+            }
+        }
+        Commands::Health => {
+            let health = client.health()?;
+            match output {
+                Output::Text => {
+                    println!("ready: {}", health.ready);
+                    println!("engine_ok: {}", health.engine_ok);
+                }
+                Output::Json => println!("{}", serde_json::to_string(&health)?),
+            }
+        }
+        Commands::Exec { file } => {
+            let (succeeded, failed) = exec_file(&mut client, file, output)?;
+            match output {
+                Output::Text => eprintln!("{} succeeded, {} failed", succeeded, failed),
+                Output::Json => eprintln!("{}", json!({ "succeeded": succeeded, "failed": failed })),
+            }
+            if failed > 0 {
+                exit(1);
+            }
         }
     }
     Ok(())
+}
+
+/// Runs every command in the file over a single connection.
+///
+/// Each non-empty line is `set KEY VALUE`, `get KEY` or `rm KEY`; everything after
+/// the key of a `set` is taken as the value. Lines starting with `#` are ignored.
+/// Returns the number of commands that succeeded and failed.
+fn exec_file(client: &mut KvsClient, file: PathBuf, output: Output) -> Result<(usize, usize)> {
+    let (mut succeeded, mut failed) = (0, 0);
+    for (line_no, line) in BufReader::new(File::open(file)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match exec_line(client, line, output) {
+            Ok(()) => succeeded += 1,
+            Err(e) => {
+                match output {
+                    Output::Text => eprintln!("line {}: {}", line_no + 1, e),
+                    Output::Json => {
+                        eprintln!("{}", json!({ "line": line_no + 1, "error": e.to_string() }))
+                    }
+                }
+                failed += 1;
+            }
+        }
+    }
+    Ok((succeeded, failed))
+}
+
+fn exec_line(client: &mut KvsClient, line: &str, output: Output) -> Result<()> {
+    let mut parts = line.splitn(3, char::is_whitespace);
+    let cmd = parts.next().unwrap_or_default();
+    let key = parts.next().map(str::to_owned);
+    let rest = parts.next().map(str::trim_start);
+    match (cmd, key, rest) {
+        ("set", Some(key), Some(value)) => client.set(key, value.to_owned()),
+        ("get", Some(key), None) => {
+            output.value(client.get(key)?);
+            Ok(())
+        }
+        ("rm", Some(key), None) => client.remove(key),
+        _ => Err(KvsError::StringError(format!("Invalid command: {}", line))),
+    }
 }
\ No newline at end of file
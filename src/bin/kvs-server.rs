@@ -1,23 +1,41 @@
 use clap::Parser;
-use env_logger::Env;
-use kvs::{Engine, KvStore, KvsError, KvsServer, Result, SledKvsEngine};
+use env_logger::{Env, Target};
+#[cfg(unix)]
+use kvs::ShutdownHandle;
+use kvs::{
+    AccessLog, AnyEngine, Engine, KvsError, KvsServer, Result, ServerConfig, WireProtocol, detect_engine, persist_engine,
+};
 use log::info;
+#[cfg(unix)]
+use log::{error, warn};
 use std::env::current_dir;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
 use std::net::SocketAddr;
-use kvs::thread_pool::{RayonThreadPool, ThreadPool};
+use std::path::PathBuf;
+use std::time::Duration;
+#[cfg(unix)]
+use std::{process, thread};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolKind};
+use std::net::{IpAddr, Ipv4Addr};
+
+const DEFAULT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4000);
+const DEFAULT_LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Number of rotated log files that are kept besides the current one.
+const LOG_FILE_BACKUPS: u32 = 5;
 
 #[derive(Debug, Parser)]
 #[command(version)]
 struct Args {
+    #[arg(long, name = "CONFIG", help = "Reads settings from a config file, which options override")]
+    config: Option<PathBuf>,
     #[arg(
         short,
         long,
         name = "IP:PORT",
-        help = "Sets the server address",
-        default_value = "127.0.0.1:4000"
+        help = "Sets the server address, and can be repeated to listen on several [default: 127.0.0.1:4000]"
     )]
-    addr: SocketAddr,
+    addr: Vec<SocketAddr>,
     #[arg(
         short,
         long,
@@ -26,52 +44,284 @@ struct Args {
         help = "Sets the storage engine"
     )]
     engine: Option<Engine>,
+    #[arg(long, name = "N", help = "Sets the number of threads serving connections [default: number of CPUs]")]
+    threads: Option<u32>,
+    #[arg(long, value_enum, name = "POOL", help = "Sets the thread pool implementation [default: rayon]")]
+    pool: Option<ThreadPoolKind>,
+    #[arg(
+        long,
+        name = "JOBS",
+        help = "Answers new connections with a busy error while this many wait for a shared-queue thread"
+    )]
+    queue_capacity: Option<usize>,
+    #[arg(long, help = "Logs every request at info level")]
+    access_log: bool,
+    #[arg(
+        long,
+        name = "FILE",
+        conflicts_with = "access_log",
+        help = "Appends every request to an access log file"
+    )]
+    access_log_file: Option<PathBuf>,
+    #[arg(long, name = "BYTES", help = "Disconnects clients that send a larger request [default: 64MB]")]
+    max_request_size: Option<u64>,
+    #[arg(long, name = "KEY-BYTES", help = "Rejects writes of keys longer than this many bytes")]
+    max_key_len: Option<usize>,
+    #[arg(long, name = "VALUE-BYTES", help = "Rejects writes of values longer than this many bytes")]
+    max_value_len: Option<usize>,
+    #[arg(long, name = "COUNT", help = "Refuses connections beyond this many open ones")]
+    max_connections: Option<usize>,
+    #[arg(long, name = "CONNECTIONS", help = "Sets how many connections are queued before they are accepted")]
+    backlog: Option<u32>,
+    #[arg(long, name = "SECS", help = "Closes connections that have been idle this long")]
+    idle_timeout: Option<u64>,
+    #[arg(long, name = "INTERVAL", help = "Logs connection and request counts this often")]
+    stats_interval: Option<u64>,
+    #[arg(long, name = "TOKEN", help = "Requires clients to authenticate with this token")]
+    auth_token: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        name = "PROTOCOL",
+        help = "Sets the protocol spoken with clients [default: json]"
+    )]
+    protocol: Option<WireProtocol>,
+    #[arg(long, name = "PATH", help = "Writes the log to a file instead of stderr")]
+    log_file: Option<PathBuf>,
+    #[arg(
+        long,
+        name = "SIZE",
+        help = "Rotates the log file once it reaches this many bytes [default: 10485760]"
+    )]
+    log_file_max_size: Option<u64>,
+}
+
+impl Args {
+    /// Returns the settings given on the command line.
+    fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            addr: self.addr.clone(),
+            engine: self.engine,
+            threads: self.threads,
+            pool: self.pool,
+            queue_capacity: self.queue_capacity,
+            access_log: self.access_log.then_some(true),
+            access_log_file: self.access_log_file.clone(),
+            max_request_size: self.max_request_size,
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+            max_connections: self.max_connections,
+            backlog: self.backlog,
+            idle_timeout: self.idle_timeout,
+            stats_interval: self.stats_interval,
+            auth_token: self.auth_token.clone(),
+            protocol: self.protocol,
+            log_file: self.log_file.clone(),
+            log_file_max_size: self.log_file_max_size,
+        }
+    }
 }
 
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let args = Args::parse();
-    let engine = get_engine(args.engine)?;
-    let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
+    // Before any other thread is started, so that they all inherit the mask.
+    #[cfg(unix)]
+    block_shutdown_signals()?;
+    let config = match &args.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    let config = config.merge(args.server_config());
+
+    let mut logger = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if let Some(path) = &config.log_file {
+        let max_size = config.log_file_max_size.unwrap_or(DEFAULT_LOG_FILE_MAX_SIZE);
+        logger.target(Target::Pipe(Box::new(RotatingFile::open(path.clone(), max_size)?)));
+    }
+    logger.init();
+
+    let threads = config.threads.unwrap_or(num_cpus::get() as u32);
+    if threads == 0 {
+        return Err(KvsError::StringError("The number of threads must be at least 1".to_owned()));
+    }
+    match (config.pool.unwrap_or_default(), config.queue_capacity) {
+        (ThreadPoolKind::SharedQueue, Some(capacity)) => {
+            run(&config, threads, SharedQueueThreadPool::with_queue_capacity(threads, capacity)?)
+        }
+        (ThreadPoolKind::SharedQueue, None) => run(&config, threads, SharedQueueThreadPool::new(threads)?),
+        (_, Some(_)) => Err(KvsError::StringError(
+            "The queue capacity only applies to the shared-queue pool".to_owned(),
+        )),
+        (ThreadPoolKind::Naive, None) => run(&config, threads, NaiveThreadPool::new(threads)?),
+        (ThreadPoolKind::Rayon, None) => run(&config, threads, RayonThreadPool::new(threads)?),
+    }
+}
+
+fn run<P: ThreadPool>(config: &ServerConfig, threads: u32, pool: P) -> Result<()> {
+    let engine = get_engine(config.engine)?;
+    let addrs = if config.addr.is_empty() { vec![DEFAULT_ADDR] } else { config.addr.clone() };
+    let protocol = config.protocol.unwrap_or_default();
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", args.addr);
+    info!("Thread pool: {:?} with {} threads", config.pool.unwrap_or_default(), threads);
+    for addr in &addrs {
+        info!("Listening on {} ({:?} protocol)", addr, protocol);
+    }
 
-    match engine {
-        Engine::Kvs => {
-            let mut server = KvsServer::new(KvStore::open(current_dir()?)?, pool);
-            server.run(args.addr)?;
-        }
-        Engine::Sled => {
-            let mut server = KvsServer::new(SledKvsEngine::open(current_dir()?)?, pool);
-            server.run(args.addr)?;
-        }
+    let mut server = KvsServer::new(AnyEngine::open(engine, current_dir()?)?, pool)
+        .with_protocol(protocol);
+    let access_log = match &config.access_log_file {
+        Some(path) => Some(AccessLog::File(path.clone())),
+        None if config.access_log == Some(true) => Some(AccessLog::Log),
+        None => None,
+    };
+    if let Some(access_log) = access_log {
+        server = server.with_access_log(access_log)?;
+    }
+    if let Some(token) = &config.auth_token {
+        server = server.with_auth(token.clone());
+    }
+    if let Some(bytes) = config.max_request_size {
+        server = server.with_max_request_size(bytes);
     }
-    Ok(())
+    if let Some(len) = config.max_key_len {
+        server = server.with_max_key_len(len);
+    }
+    if let Some(len) = config.max_value_len {
+        server = server.with_max_value_len(len);
+    }
+    if let Some(max) = config.max_connections {
+        server = server.with_max_connections(max);
+    }
+    if let Some(backlog) = config.backlog {
+        server = server.with_backlog(backlog);
+    }
+    if let Some(secs) = config.idle_timeout {
+        server = server.with_idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = config.stats_interval {
+        server = server.with_stats_interval(Duration::from_secs(secs));
+    }
+    #[cfg(unix)]
+    shutdown_on_signal(server.shutdown_handle());
+    server.run(addrs.as_slice())
 }
 
-fn get_engine(engine: Option<Engine>) -> Result<Engine> {
-    let engine_path = current_dir()?.join(".engine");
-    match engine {
-        Some(engine) => {
-            if engine_path.exists() {
-                let last_engine: Engine = serde_json::from_reader(File::open(&engine_path)?)?;
-                if engine != last_engine {
-                    return Err(KvsError::EngineMismatch);
-                }
+/// Blocks SIGINT and SIGTERM in this thread and the threads it starts, so that
+/// they can be waited for with `sigwait` rather than killing the process.
+#[cfg(unix)]
+fn block_shutdown_signals() -> Result<()> {
+    let signals = shutdown_signals();
+    // SAFETY: `signals` is initialized, and the old mask isn't asked for.
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno).into()),
+    }
+}
+
+#[cfg(unix)]
+fn shutdown_signals() -> libc::sigset_t {
+    // SAFETY: `sigset_t` is plain data, and is initialized by `sigemptyset`.
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        signals
+    }
+}
+
+/// Shuts the server down gracefully on the first SIGINT or SIGTERM, and exits
+/// right away on the second.
+#[cfg(unix)]
+fn shutdown_on_signal(handle: ShutdownHandle) {
+    let signals = shutdown_signals();
+    thread::spawn(move || {
+        let mut received = false;
+        loop {
+            let mut signal = 0;
+            // SAFETY: both pointers are valid for the duration of the call.
+            if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                error!("Waiting for signals failed");
+                return;
+            }
+            let name = if signal == libc::SIGINT { "SIGINT" } else { "SIGTERM" };
+            if received {
+                warn!("Received {} again, exiting without waiting for connections", name);
+                process::exit(1);
             }
-            serde_json::to_writer(File::create(&engine_path)?, &engine)?;
-            Ok(engine)
+            info!("Received {}, shutting down", name);
+            received = true;
+            handle.shutdown();
         }
-        None => {
-            if engine_path.exists() {
-                let last_engine: Engine = serde_json::from_reader(File::open(&engine_path)?)?;
-                return Ok(last_engine);
+    });
+}
+
+/// A log file that is renamed to `<path>.1` once it reaches `max_size`,
+/// shifting the older ones up to `<path>.<LOG_FILE_BACKUPS>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn backup(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..LOG_FILE_BACKUPS).rev() {
+            let from = self.backup(n);
+            if from.exists() {
+                fs::rename(from, self.backup(n + 1))?;
             }
-            let default_engine = Engine::Kvs;
-            serde_json::to_writer(File::create(&engine_path)?, &default_engine)?;
-            Ok(default_engine)
         }
+        fs::rename(&self.path, self.backup(1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
     }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Every log record is written at once, so records aren't split
+        // between files.
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn get_engine(engine: Option<Engine>) -> Result<Engine> {
+    let dir = current_dir()?;
+    let engine = match engine {
+        Some(engine) => engine,
+        None => detect_engine(&dir)?.unwrap_or(Engine::Kvs),
+    };
+    persist_engine(&dir, engine)?;
+    Ok(engine)
 }
\ No newline at end of file
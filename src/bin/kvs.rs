@@ -0,0 +1,84 @@
+use clap::{Parser, Subcommand};
+use kvs::{KvStore, KvsError, Result};
+use std::env::current_dir;
+use std::process::exit;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Inspect and modify the kvs store in the current directory")]
+struct Args {
+    #[command(subcommand)]
+    cmd: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    #[command(about = "Set the value of a string key to a string", name = "set")]
+    Set {
+        #[arg(name = "KEY", help = "A string key")]
+        key: String,
+        #[arg(name = "VALUE", help = "The string value of the key")]
+        value: String,
+    },
+    #[command(about = "Get the string value of a given string key", name = "get")]
+    Get {
+        #[arg(name = "KEY", help = "A string key")]
+        key: String,
+    },
+    #[command(about = "Remove a given string key", name = "rm")]
+    Remove {
+        #[arg(name = "KEY", help = "A string key")]
+        key: String,
+    },
+    #[command(about = "Print every key/value pair whose key starts with a prefix", name = "scan")]
+    Scan {
+        #[arg(name = "PREFIX", help = "A key prefix", default_value = "")]
+        prefix: String,
+    },
+    #[command(about = "Rewrite the log to drop stale entries", name = "compact")]
+    Compact,
+    #[command(about = "Print statistics about the store", name = "stats")]
+    Stats,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let store = KvStore::open(current_dir()?)?;
+    match args.cmd {
+        Commands::Set { key, value } => {
+            store.set(key, value)?;
+        }
+        Commands::Get { key } => {
+            if let Some(value) = store.get(key)? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+        }
+        Commands::Remove { key } => match store.remove(key) {
+            Ok(()) => {}
+            Err(KvsError::KeyNotFound) => {
+                println!("Key not found");
+                exit(1);
+            }
+            Err(e) => return Err(e),
+        },
+        Commands::Scan { prefix } => {
+            for (key, value) in store.scan_prefix(&prefix)? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        Commands::Compact => {
+            let before = store.stats()?.total_log_bytes;
+            store.compact()?;
+            let after = store.stats()?.total_log_bytes;
+            println!("Compacted log from {} to {} bytes", before, after);
+        }
+        Commands::Stats => {
+            let stats = store.stats()?;
+            println!("live keys: {}", stats.live_keys);
+            println!("stale bytes: {}", stats.stale_bytes);
+            println!("total log bytes: {}", stats.total_log_bytes);
+        }
+    }
+    Ok(())
+}
@@ -1,55 +1,470 @@
-use crate::protocol::{Request, Response};
+use crate::protocol::{Health, Request, Response, ServerInfo};
 use crate::{KvsError, Result};
 use serde::Deserialize;
+use log::debug;
 use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct KvsClient {
     reader: Deserializer<IoRead<BufReader<TcpStream>>>,
     writer: BufWriter<TcpStream>,
+    addr: SocketAddr,
+    reconnect: Option<ReconnectPolicy>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    auth_token: Option<String>,
+    nodelay: bool,
+    /// Set when a request failed after it may have reached the server, so that
+    /// its response could still arrive and be read as the answer to the next one.
+    broken: bool,
+}
+
+/// Controls how a `KvsClient` reconnects after its connection breaks.
+///
+/// On a transport error the client reconnects up to `retries` times, sleeping
+/// `backoff` before the first attempt and doubling the delay after each failure,
+/// and then retries the failed operation once.
+///
+/// A request is only retried if it could not have been applied by the server:
+/// `get` and `ping` are retried whenever the connection breaks, while `set` and
+/// `remove` are only retried if the failure happened before any bytes of the
+/// request were written to the server. Otherwise the error is returned, as the
+/// server may or may not have applied the request.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl ReconnectPolicy {
+    pub fn new(retries: u32, backoff: Duration) -> Self {
+        ReconnectPolicy { retries, backoff }
+    }
+}
+
+/// The stage of a request in which a transport error occurred.
+enum Failure {
+    /// The request was not sent, so the server cannot have applied it.
+    Send(KvsError),
+    /// The request may have reached the server.
+    Receive(KvsError),
 }
 
 impl KvsClient {
+    /// Connects to the server.
+    ///
+    /// Fails with `KvsError::Connection` if the server refuses the connection.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let reader = TcpStream::connect(addr)?;
+        let stream = TcpStream::connect(addr).map_err(|e| map_io_error(e.into()))?;
+        KvsClient::from_stream(stream)
+    }
+
+    /// Connects to the server, giving up with `KvsError::Timeout` if no connection
+    /// could be established within `timeout`, or with `KvsError::Connection` if
+    /// the server refuses it.
+    pub fn connect_with_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return KvsClient::from_stream(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .map(|e| map_io_error(e.into()))
+            .unwrap_or_else(|| KvsError::StringError("No address to connect to".to_owned())))
+    }
+
+    fn from_stream(reader: TcpStream) -> Result<Self> {
+        let addr = reader.peer_addr()?;
+        reader.set_nodelay(true)?;
         let writer = reader.try_clone()?;
         Ok(KvsClient {
             reader: Deserializer::from_reader(BufReader::new(reader)),
             writer: BufWriter::new(writer),
+            addr,
+            reconnect: None,
+            read_timeout: None,
+            write_timeout: None,
+            auth_token: None,
+            nodelay: true,
+            broken: false,
         })
     }
 
+    /// Sets how long to wait for a response before failing with `KvsError::Timeout`.
+    ///
+    /// `None` waits forever, which is the default.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.writer.get_ref().set_read_timeout(timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Sets how long to wait for a request to be sent before failing with `KvsError::Timeout`.
+    ///
+    /// `None` waits forever, which is the default.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.writer.get_ref().set_write_timeout(timeout)?;
+        self.write_timeout = timeout;
+        Ok(())
+    }
+
+    /// Sets whether `TCP_NODELAY` is set on the connection.
+    ///
+    /// It is on by default, so that small requests are sent immediately instead
+    /// of being delayed by Nagle's algorithm.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        self.writer.get_ref().set_nodelay(nodelay)?;
+        self.nodelay = nodelay;
+        Ok(())
+    }
+
+    /// Returns whether `TCP_NODELAY` is set on the connection.
+    pub fn nodelay(&self) -> Result<bool> {
+        Ok(self.writer.get_ref().nodelay()?)
+    }
+
+    /// Enables transparent reconnection using the given policy.
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Authenticates the connection with a server that requires a token.
+    ///
+    /// The token is remembered and sent again whenever the client reconnects.
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        self.request(Request::Auth { token: token.clone() })?.into_result()?;
+        self.auth_token = Some(token);
+        Ok(())
+    }
+
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let req = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-        let resp = Response::deserialize(&mut self.reader)?;
-        match resp {
-            Response::Ok(value) => Ok(value),
-            Response::Err(msg) => Err(KvsError::StringError(msg)),
-        }
+        self.request(Request::Get { key })?.into_result()
+    }
+
+    /// Gets the values of several keys in one round trip, in the order of
+    /// `keys`, with `None` for the keys that don't exist.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.request(Request::GetMany { keys })?.into_values()
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let req = Request::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-        let resp = Response::deserialize(&mut self.reader)?;
-        match resp {
-            Response::Ok(_) => Ok(()),
-            Response::Err(msg) => Err(KvsError::StringError(msg)),
-        }
+        self.request(Request::Set { key, value })?.into_result()?;
+        Ok(())
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        let req = Request::Remove { key };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-        let resp = Response::deserialize(&mut self.reader)?;
-        match resp {
-            Response::Ok(_) => Ok(()),
-            Response::Err(msg) => Err(KvsError::StringError(msg)),
+        self.request(Request::Remove { key })?.into_result()?;
+        Ok(())
+    }
+
+    /// Returns whether `key` exists, without transferring its value.
+    pub fn contains(&mut self, key: String) -> Result<bool> {
+        self.request(Request::Contains { key })?.into_bool()
+    }
+
+    /// Checks that the server is reachable and the connection is still usable.
+    pub fn ping(&mut self) -> Result<()> {
+        self.request(Request::Ping)?.into_result()?;
+        Ok(())
+    }
+
+    /// Returns the server's request counters and latency histograms in the
+    /// Prometheus text exposition format.
+    pub fn metrics(&mut self) -> Result<String> {
+        let metrics = self.request(Request::Metrics)?.into_result()?;
+        Ok(metrics.unwrap_or_default())
+    }
+
+    /// Asks the server for its version, engine, uptime and request count.
+    pub fn server_info(&mut self) -> Result<ServerInfo> {
+        self.request(Request::ServerInfo)?.into_server_info()
+    }
+
+    /// Asks the server whether it is up and ready for traffic.
+    pub fn health(&mut self) -> Result<Health> {
+        self.request(Request::Health)?.into_health()
+    }
+
+    /// Closes the connection, returning any error from flushing or shutting
+    /// down the stream that dropping the client would swallow.
+    ///
+    /// The client is consumed, so it can't be used afterwards:
+    ///
+    /// ```compile_fail
+    /// # use kvs::KvsClient;
+    /// # fn main() -> kvs::Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// client.close()?;
+    /// client.ping()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn close(mut self) -> Result<()> {
+        self.writer.flush().map_err(|e| map_io_error(e.into()))?;
+        match self.writer.get_ref().shutdown(Shutdown::Both) {
+            // The server has hung up already.
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result.map_err(|e| map_io_error(e.into())),
+        }
+    }
+
+    /// Sends a request and waits for its response, reconnecting according to
+    /// the reconnect policy if the connection turns out to be broken.
+    fn request(&mut self, req: Request) -> Result<Response> {
+        let err = match self.exchange(&req) {
+            Ok(resp) => return Ok(resp),
+            Err(Failure::Send(e)) if is_transport_error(&e) => e,
+            Err(Failure::Receive(e)) if is_transport_error(&e) && is_idempotent(&req) => e,
+            Err(Failure::Send(e) | Failure::Receive(e)) => return Err(e),
+        };
+        let Some(policy) = self.reconnect.filter(|policy| policy.retries > 0) else {
+            return Err(err);
+        };
+
+        debug!("Connection to {} broken ({}), reconnecting", self.addr, err);
+        self.reopen(policy)?;
+        self.exchange(&req).map_err(|(Failure::Send(e) | Failure::Receive(e))| e)
+    }
+
+    fn exchange(&mut self, req: &Request) -> std::result::Result<Response, Failure> {
+        if self.broken {
+            debug!("Replacing the connection to {} after a failed request", self.addr);
+            self.replace_connection().map_err(Failure::Send)?;
+        }
+        serde_json::to_writer(&mut self.writer, req)
+            .map_err(|e| Failure::Send(map_io_error(e.into())))?;
+        let unsent = self.writer.buffer().len();
+        if let Err(e) = self.writer.flush() {
+            let e = map_io_error(e.into());
+            // A partial flush means the server may have seen part of the request.
+            if self.writer.buffer().len() == unsent {
+                return Err(Failure::Send(e));
+            }
+            self.broken = true;
+            return Err(Failure::Receive(e));
+        }
+        Response::deserialize(&mut self.reader).map_err(|e| {
+            // After a timeout the response may still arrive, so the connection
+            // can't be used for another request.
+            self.broken = true;
+            Failure::Receive(map_io_error(e.into()))
+        })
+    }
+
+    fn reopen(&mut self, policy: ReconnectPolicy) -> Result<()> {
+        let mut backoff = policy.backoff;
+        let mut attempt = 0;
+        loop {
+            thread::sleep(backoff);
+            match self.replace_connection() {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt + 1 >= policy.retries => return Err(e),
+                Err(e) => debug!("Reconnect to {} failed: {}", self.addr, e),
+            }
+            attempt += 1;
+            backoff *= 2;
+        }
+    }
+
+    /// Opens a new connection with the same options and token as the old one.
+    fn replace_connection(&mut self) -> Result<()> {
+        let client = KvsClient::connect(self.addr)?;
+        self.reader = client.reader;
+        self.writer = client.writer;
+        self.broken = false;
+        self.set_read_timeout(self.read_timeout)?;
+        self.set_write_timeout(self.write_timeout)?;
+        self.set_nodelay(self.nodelay)?;
+        self.reauthenticate()
+    }
+
+    /// Sends the remembered token on a fresh connection.
+    fn reauthenticate(&mut self) -> Result<()> {
+        let Some(token) = self.auth_token.clone() else {
+            return Ok(());
+        };
+        match self.exchange(&Request::Auth { token }) {
+            Ok(resp) => resp.into_result().map(|_| ()),
+            Err(Failure::Send(e) | Failure::Receive(e)) => Err(e),
+        }
+    }
+}
+
+fn is_transport_error(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(_) | KvsError::Connection(_) => true,
+        KvsError::Serde(e) => e.is_io() || e.is_eof(),
+        _ => false,
+    }
+}
+
+/// Turns I/O errors caused by an expired socket timeout into `KvsError::Timeout`,
+/// and those caused by the connection failing into `KvsError::Connection`, so
+/// that callers can tell them apart from other errors.
+fn map_io_error(err: KvsError) -> KvsError {
+    if let KvsError::Serde(e) = &err
+        && e.is_eof()
+    {
+        return KvsError::Connection("Connection closed by the server".to_owned());
+    }
+    let kind = match &err {
+        KvsError::Io(e) => Some(e.kind()),
+        KvsError::Serde(e) => e.io_error_kind(),
+        _ => None,
+    };
+    match kind {
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => KvsError::Timeout,
+        Some(
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof,
+        ) => KvsError::Connection(err.to_string()),
+        _ => err,
+    }
+}
+
+fn is_idempotent(req: &Request) -> bool {
+    matches!(
+        req,
+        Request::Get { .. }
+            | Request::GetMany { .. }
+            | Request::Contains { .. }
+            | Request::Ping
+            | Request::Metrics
+            | Request::ServerInfo
+            | Request::Health
+            | Request::Auth { .. }
+    )
+}
+
+/// A fixed-size pool of `KvsClient` connections that can be shared between threads.
+///
+/// Each operation checks out a connection, waiting if all of them are in use,
+/// and returns it afterwards. A connection that has been idle for longer than
+/// the validation interval is pinged before use and reopened if the ping fails.
+pub struct KvsClientPool {
+    addr: SocketAddr,
+    idle: Mutex<Vec<PooledClient>>,
+    available: Condvar,
+    size: usize,
+    validate_after: Duration,
+    connections_opened: AtomicUsize,
+}
+
+struct PooledClient {
+    client: Option<KvsClient>,
+    last_used: Instant,
+}
+
+impl KvsClientPool {
+    /// Opens `size` connections to the server at `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A, size: usize) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| KvsError::StringError("No address to connect to".to_owned()))?;
+        let pool = KvsClientPool {
+            addr,
+            idle: Mutex::new(Vec::with_capacity(size)),
+            available: Condvar::new(),
+            size,
+            validate_after: Duration::from_secs(30),
+            connections_opened: AtomicUsize::new(0),
+        };
+        for _ in 0..size {
+            let client = pool.open()?;
+            pool.idle.lock().unwrap().push(PooledClient {
+                client: Some(client),
+                last_used: Instant::now(),
+            });
+        }
+        Ok(pool)
+    }
+
+    /// Sets how long a connection may stay idle before it is pinged on checkout.
+    pub fn with_validation_interval(mut self, interval: Duration) -> Self {
+        self.validate_after = interval;
+        self
+    }
+
+    /// Returns the number of connections the pool maintains.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of TCP connections opened over the pool's lifetime.
+    pub fn connections_opened(&self) -> usize {
+        self.connections_opened.load(Ordering::SeqCst)
+    }
+
+    /// Gets the string value of a given string key.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_client(|client| client.get(key))
+    }
+
+    /// Gets the values of several keys in one round trip.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.with_client(|client| client.get_many(keys))
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_client(|client| client.set(key, value))
+    }
+
+    /// Removes a given key.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.with_client(|client| client.remove(key))
+    }
+
+    fn open(&self) -> Result<KvsClient> {
+        let client = KvsClient::connect(self.addr)?;
+        self.connections_opened.fetch_add(1, Ordering::SeqCst);
+        Ok(client)
+    }
+
+    fn with_client<T>(&self, f: impl FnOnce(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let mut pooled = {
+            let mut idle = self.idle.lock().unwrap();
+            loop {
+                match idle.pop() {
+                    Some(pooled) => break pooled,
+                    None => idle = self.available.wait(idle).unwrap(),
+                }
+            }
+        };
+
+        let result = self.checkout(&mut pooled).and_then(f);
+        // Transport errors leave the stream in an unknown state, so reopen it on next use.
+        if let Err(KvsError::Io(_) | KvsError::Serde(_) | KvsError::Connection(_)) = result {
+            pooled.client = None;
+        }
+
+        pooled.last_used = Instant::now();
+        self.idle.lock().unwrap().push(pooled);
+        self.available.notify_one();
+        result
+    }
+
+    fn checkout<'a>(&self, pooled: &'a mut PooledClient) -> Result<&'a mut KvsClient> {
+        let stale = pooled.last_used.elapsed() >= self.validate_after;
+        let healthy = match pooled.client.as_mut() {
+            Some(client) => !stale || client.ping().is_ok(),
+            None => false,
+        };
+        if !healthy {
+            pooled.client = Some(self.open()?);
         }
+        Ok(pooled.client.as_mut().unwrap())
     }
 }
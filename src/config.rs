@@ -0,0 +1,238 @@
+//! The config file of `kvs-server`.
+//!
+//! The file is written in a flat subset of TOML: one `key = value` per line,
+//! with strings, integers, booleans and arrays of them as values and `#`
+//! comments. Tables are not supported. Keys are the names of the command-line
+//! options:
+//!
+//! ```toml
+//! addr = ["127.0.0.1:4000", "[::1]:4000"]
+//! engine = "kvs"
+//! threads = 8
+//! pool = "shared-queue"
+//! access-log-file = "/var/log/kvs/access.log"
+//! ```
+
+use crate::engine::Engine;
+use crate::server::WireProtocol;
+use crate::thread_pool::ThreadPoolKind;
+use crate::{KvsError, Result};
+use clap::ValueEnum;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Settings of `kvs-server`, each of which corresponds to one of its
+/// command-line options. `None` means the option wasn't given.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    /// The addresses to listen on. The file may give a single address or an
+    /// array of them.
+    #[serde(deserialize_with = "one_or_more")]
+    pub addr: Vec<SocketAddr>,
+    /// The storage engine.
+    #[serde(deserialize_with = "value_enum")]
+    pub engine: Option<Engine>,
+    /// The number of threads serving connections.
+    pub threads: Option<u32>,
+    /// The thread pool implementation.
+    #[serde(deserialize_with = "value_enum")]
+    pub pool: Option<ThreadPoolKind>,
+    /// The number of connections the shared-queue pool queues while all its
+    /// threads are busy.
+    pub queue_capacity: Option<usize>,
+    /// Whether to log every request at info level.
+    pub access_log: Option<bool>,
+    /// A file every request is appended to.
+    pub access_log_file: Option<PathBuf>,
+    /// The largest request a client may send, in bytes.
+    pub max_request_size: Option<u64>,
+    /// The longest key a client may write, in bytes.
+    pub max_key_len: Option<usize>,
+    /// The longest value a client may write, in bytes.
+    pub max_value_len: Option<usize>,
+    /// The number of connections the server keeps open at once.
+    pub max_connections: Option<usize>,
+    /// The number of connections the system queues before the server accepts
+    /// them.
+    pub backlog: Option<u32>,
+    /// The number of seconds after which idle connections are closed.
+    pub idle_timeout: Option<u64>,
+    /// The number of seconds between lines of server stats in the log.
+    pub stats_interval: Option<u64>,
+    /// The token clients must authenticate with.
+    pub auth_token: Option<String>,
+    /// The protocol spoken with clients.
+    #[serde(deserialize_with = "value_enum")]
+    pub protocol: Option<WireProtocol>,
+    /// A file the server logs to instead of stderr.
+    pub log_file: Option<PathBuf>,
+    /// The size in bytes at which the log file is rotated.
+    pub log_file_max_size: Option<u64>,
+}
+
+impl ServerConfig {
+    /// Reads a config file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path)?;
+        Self::from_toml(&toml)
+            .map_err(|e| KvsError::StringError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses the contents of a config file.
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let table = parse_toml(toml)?;
+        serde_json::from_value(Value::Object(table)).map_err(|e| KvsError::StringError(e.to_string()))
+    }
+
+    /// Returns these settings with the ones given in `overrides` replacing
+    /// them, e.g. the command-line options over the config file.
+    pub fn merge(self, overrides: ServerConfig) -> Self {
+        ServerConfig {
+            addr: if overrides.addr.is_empty() { self.addr } else { overrides.addr },
+            engine: overrides.engine.or(self.engine),
+            threads: overrides.threads.or(self.threads),
+            pool: overrides.pool.or(self.pool),
+            queue_capacity: overrides.queue_capacity.or(self.queue_capacity),
+            access_log: overrides.access_log.or(self.access_log),
+            access_log_file: overrides.access_log_file.or(self.access_log_file),
+            max_request_size: overrides.max_request_size.or(self.max_request_size),
+            max_key_len: overrides.max_key_len.or(self.max_key_len),
+            max_value_len: overrides.max_value_len.or(self.max_value_len),
+            max_connections: overrides.max_connections.or(self.max_connections),
+            backlog: overrides.backlog.or(self.backlog),
+            idle_timeout: overrides.idle_timeout.or(self.idle_timeout),
+            stats_interval: overrides.stats_interval.or(self.stats_interval),
+            auth_token: overrides.auth_token.or(self.auth_token),
+            protocol: overrides.protocol.or(self.protocol),
+            log_file: overrides.log_file.or(self.log_file),
+            log_file_max_size: overrides.log_file_max_size.or(self.log_file_max_size),
+        }
+    }
+}
+
+/// Deserializes an enum from the name it has on the command line.
+fn value_enum<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    let name = String::deserialize(deserializer)?;
+    T::from_str(&name, true).map(Some).map_err(de::Error::custom)
+}
+
+/// Deserializes either a single value or an array of them.
+fn one_or_more<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore<T> {
+        One(T),
+        More(Vec<T>),
+    }
+    match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(value) => Ok(vec![value]),
+        OneOrMore::More(values) => Ok(values),
+    }
+}
+
+/// Parses flat TOML into a JSON object, so that serde can take it from there.
+fn parse_toml(toml: &str) -> Result<Map<String, Value>> {
+    let mut table = Map::new();
+    for (i, line) in toml.lines().enumerate() {
+        let error = |message: &str| KvsError::StringError(format!("line {}: {}", i + 1, message));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(error("tables are not supported"));
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| error("expected `key = value`"))?;
+        let key = key.trim();
+        if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(error(&format!("invalid key {:?}", key)));
+        }
+        let value = parse_value(value.trim()).map_err(|e| error(&e))?;
+        if table.insert(key.to_owned(), value).is_some() {
+            return Err(error(&format!("duplicate key {:?}", key)));
+        }
+    }
+    Ok(table)
+}
+
+/// Parses a value and the comment that may follow it.
+fn parse_value(s: &str) -> std::result::Result<Value, String> {
+    let (value, rest) = parse_item(s)?;
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected {:?} after value", rest));
+    }
+    Ok(value)
+}
+
+/// Parses the value at the start of `s`, returning it and the rest of `s`.
+fn parse_item(s: &str) -> std::result::Result<(Value, &str), String> {
+    match s.chars().next() {
+        Some('"') => {
+            let mut value = String::new();
+            let mut chars = s.char_indices().skip(1);
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 1,
+                    Some((_, '\\')) => value.push(match chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        _ => return Err("invalid escape sequence".to_owned()),
+                    }),
+                    Some((_, c)) => value.push(c),
+                    None => return Err("unterminated string".to_owned()),
+                }
+            };
+            Ok((Value::String(value), &s[end..]))
+        }
+        Some('\'') => {
+            let end = s[1..].find('\'').ok_or("unterminated string")? + 1;
+            Ok((Value::String(s[1..end].to_owned()), &s[end + 1..]))
+        }
+        Some('[') => {
+            let mut items = Vec::new();
+            let mut rest = s[1..].trim_start();
+            while !rest.starts_with(']') {
+                let (item, after) = parse_item(rest)?;
+                items.push(item);
+                rest = after.trim_start();
+                match rest.strip_prefix(',') {
+                    Some(after) => rest = after.trim_start(),
+                    None if rest.starts_with(']') => {}
+                    None => return Err("expected `,` or `]` in array".to_owned()),
+                }
+            }
+            Ok((Value::Array(items), &rest[1..]))
+        }
+        _ => {
+            let end = s.find(['#', ',', ']']).unwrap_or(s.len());
+            let value = match s[..end].trim() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                token => {
+                    let digits = token.replace('_', "");
+                    let n: i64 = digits.parse().map_err(|_| format!("invalid value {:?}", token))?;
+                    Value::from(n)
+                }
+            };
+            Ok((value, &s[end..]))
+        }
+    }
+}
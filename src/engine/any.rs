@@ -0,0 +1,123 @@
+#[cfg(feature = "sled")]
+use super::SledKvsEngine;
+use super::{Engine, KvStore, KvsEngine};
+use crate::Result;
+use std::path::PathBuf;
+
+/// A `KvsEngine` that is either a `KvStore` or a `SledKvsEngine`.
+///
+/// It lets the engine be picked at runtime while the server and other generic
+/// code are only instantiated once.
+#[derive(Clone)]
+pub enum AnyEngine {
+    Kvs(KvStore),
+    #[cfg(feature = "sled")]
+    Sled(SledKvsEngine),
+}
+
+impl AnyEngine {
+    /// Opens the given kind of engine at `path`.
+    pub fn open(engine: Engine, path: impl Into<PathBuf>) -> Result<Self> {
+        match engine {
+            Engine::Kvs => Ok(AnyEngine::Kvs(KvStore::open(path)?)),
+            #[cfg(feature = "sled")]
+            Engine::Sled => Ok(AnyEngine::Sled(SledKvsEngine::open(path)?)),
+        }
+    }
+
+    /// Returns which kind of engine this is.
+    pub fn kind(&self) -> Engine {
+        match self {
+            AnyEngine::Kvs(_) => Engine::Kvs,
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(_) => Engine::Sled,
+        }
+    }
+}
+
+impl From<KvStore> for AnyEngine {
+    fn from(store: KvStore) -> Self {
+        AnyEngine::Kvs(store)
+    }
+}
+
+#[cfg(feature = "sled")]
+impl From<SledKvsEngine> for AnyEngine {
+    fn from(db: SledKvsEngine) -> Self {
+        AnyEngine::Sled(db)
+    }
+}
+
+impl KvsEngine for AnyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(store) => store.set(key, value),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.set(key, value),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self {
+            AnyEngine::Kvs(store) => store.get(key),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.get(key),
+        }
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self {
+            AnyEngine::Kvs(store) => store.get_many(keys),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.get_many(keys),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(store) => store.remove(key),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.remove(key),
+        }
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        match self {
+            AnyEngine::Kvs(store) => store.contains_key(key),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.contains_key(key),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(store) => store.check(),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.check(),
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        match self {
+            AnyEngine::Kvs(store) => store.is_busy(),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.is_busy(),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(store) => store.flush(),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.flush(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AnyEngine::Kvs(store) => store.name(),
+            #[cfg(feature = "sled")]
+            AnyEngine::Sled(db) => db.name(),
+        }
+    }
+}
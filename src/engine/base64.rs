@@ -0,0 +1,61 @@
+//! Serde adapter that stores binary values in dumps as standard, padded
+//! base64 strings, which is far more compact than JSON's array of numbers.
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serializer};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(bytes))
+}
+
+pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    decode(&encoded).map_err(de::Error::custom)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, String> {
+    let input = encoded.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return Err("invalid base64 length".to_owned());
+    }
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    for chunk in input.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return Err("invalid base64 padding".to_owned());
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = match c {
+                b'=' if i >= 4 - padding => 0,
+                _ => ALPHABET
+                    .iter()
+                    .position(|&a| a == c)
+                    .ok_or_else(|| format!("invalid base64 character {:?}", c as char))?
+                    as u32,
+            };
+            n = n << 6 | value;
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
@@ -0,0 +1,63 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A least-recently-used cache of values keyed by their key.
+///
+/// Recency is tracked with a monotonically increasing tick per access; the entry
+/// with the smallest tick is evicted when the cache is full.
+pub(crate) struct LruCache {
+    capacity: usize,
+    entries: HashMap<String, (String, u64)>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl LruCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Returns a copy of the cached value and marks it as most recently used.
+    pub(crate) fn get(&mut self, key: &str) -> Option<String> {
+        self.tick += 1;
+        let (value, tick) = self.entries.get_mut(key)?;
+        self.order.remove(tick);
+        *tick = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+        Some(value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: String, value: String) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.capacity
+            && let Some((_, oldest)) = self.order.pop_first()
+        {
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some((_, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
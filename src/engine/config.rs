@@ -0,0 +1,114 @@
+use crate::{KvsError, Result};
+use std::time::Duration;
+
+/// Options for opening a storage engine.
+///
+/// `StoreConfig::default()` gives the same behavior as the plain `open` constructors.
+#[derive(Debug, Clone, Default)]
+pub struct StoreConfig {
+    /// Name of the store within its directory. `KvStore` keeps its data in
+    /// `<name>.log` and `<name>.hint`, and `SledKvsEngine` in a `<name>`
+    /// subdirectory. `None` gives `wal.log` and `wal.hint` for `KvStore` and
+    /// the directory itself for sled.
+    pub name: Option<String>,
+    /// Number of recently read values `KvStore` keeps in memory so that hot keys
+    /// are served without touching the log. `0` disables the cache.
+    pub value_cache_entries: usize,
+    /// Number of versions of each key `KvStore` keeps, including the current
+    /// one, for `KvStore::get_versions`. Older versions stay in the log until
+    /// they fall out of the history. `0` and `1` keep only the current value.
+    pub history_depth: usize,
+    /// When writes are flushed to disk.
+    pub flush_mode: FlushMode,
+    /// How often writes are flushed in the background with
+    /// `FlushMode::Background`, in milliseconds, which bounds how much is lost
+    /// on a crash. `None` keeps sled's default, and leaves `KvStore` to flush
+    /// only when asked to or when it is dropped.
+    pub flush_every_ms: Option<u64>,
+    /// Size of sled's page cache in bytes. `None` keeps sled's default of 1GB.
+    pub sled_cache_capacity: Option<u64>,
+    /// Opens a `KvStore` for reading only, without the lock that keeps two
+    /// writers from opening the same store, so that it can be read while
+    /// another process writes to it. It sees the data as of when it was
+    /// opened, and writes and compactions fail with `KvsError::ReadOnly`.
+    pub read_only: bool,
+    /// When `KvStore` compacts its log.
+    pub compaction_policy: CompactionPolicy,
+    /// Has `KvStore` write its index to the hint file when it is closed, so
+    /// that the next open loads the index instead of replaying the whole log.
+    /// Without it the hint file is only written by compactions.
+    pub hint_on_close: bool,
+    /// The longest key, in bytes, `KvStore` accepts in a write. Longer ones
+    /// fail with `KvsError::KeyTooLong`. `None` means no limit.
+    pub max_key_len: Option<usize>,
+    /// The longest value, in bytes, `KvStore` accepts. Longer ones fail with
+    /// `KvsError::ValueTooLong`. `None` means no limit.
+    pub max_value_len: Option<usize>,
+    /// Has `KvStore` grow its log file this many bytes ahead of the writes at a
+    /// time, instead of with every write, so that the file is less fragmented
+    /// on disk. The unused part is filled with zeros, which `KvStore` skips
+    /// when it reopens the log. `None` never grows it ahead.
+    pub preallocate_bytes: Option<u64>,
+    /// Has `KvStore` discard all its data when it is opened if its log was
+    /// last written longer ago than this, for stores used as caches that
+    /// shouldn't serve data left over from before a long downtime. `None`
+    /// keeps the data however old it is. Read-only stores are never discarded.
+    pub max_age: Option<Duration>,
+}
+
+/// Limits on the sizes of the keys and values written to an engine.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SizeLimits {
+    pub(crate) max_key_len: Option<usize>,
+    pub(crate) max_value_len: Option<usize>,
+}
+
+impl SizeLimits {
+    /// Fails if `key` or a value of `value_len` bytes is over its limit.
+    pub(crate) fn check(&self, key: &str, value_len: usize) -> Result<()> {
+        if let Some(max) = self.max_key_len
+            && key.len() > max
+        {
+            return Err(KvsError::KeyTooLong { len: key.len(), max });
+        }
+        if let Some(max) = self.max_value_len
+            && value_len > max
+        {
+            return Err(KvsError::ValueTooLong { len: value_len, max });
+        }
+        Ok(())
+    }
+}
+
+/// When `KvStore` compacts its log, judged after every write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// Compact once the log holds more than this many bytes of stale entries.
+    /// The default is 1MB, which keeps small stores compact but makes large
+    /// ones compact very often.
+    AbsoluteBytes(u64),
+    /// Compact once stale entries take more than this fraction of the log,
+    /// e.g. `0.5` for half of it.
+    StaleRatio(f64),
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::AbsoluteBytes(1024 * 1024)
+    }
+}
+
+/// When an engine flushes writes to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Flush after every `set` and `remove`, so that a write is durable as soon
+    /// as it returns. This is the default.
+    #[default]
+    EveryWrite,
+    /// Leave flushing to explicit `flush` calls and to a periodic background
+    /// flush: sled's own, or for `KvStore` one every
+    /// `StoreConfig::flush_every_ms` if set. `KvStore` buffers writes in memory
+    /// and also flushes them when the store is dropped. Much faster, but the
+    /// latest writes may be lost on a crash.
+    Background,
+}
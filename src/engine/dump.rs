@@ -0,0 +1,134 @@
+//! The dump format written by `KvStore::export` and read by
+//! `KvStore::bulk_load`.
+//!
+//! A dump is made of lines of JSON: a header with a magic string, the format
+//! version and the number of records, then one log command per record, then a
+//! trailer with the CRC-32 of the record lines. A dump that was cut short or
+//! altered is detected before anything is loaded from it.
+
+use super::kvs::Command;
+use crate::error::{KvsError, Result};
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+const MAGIC: &str = "kvs-dump";
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: String,
+    version: u32,
+    records: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Trailer {
+    crc32: u32,
+}
+
+/// Writes a dump of a known number of records.
+pub(super) struct DumpWriter<W: Write> {
+    writer: W,
+    hasher: Hasher,
+    line: Vec<u8>,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Writes the header of a dump of `records` records.
+    pub(super) fn new(mut writer: W, records: usize) -> Result<Self> {
+        let header = Header {
+            magic: MAGIC.to_owned(),
+            version: VERSION,
+            records,
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+        Ok(DumpWriter {
+            writer,
+            hasher: Hasher::new(),
+            line: Vec::new(),
+        })
+    }
+
+    pub(super) fn write(&mut self, cmd: &Command) -> Result<()> {
+        self.line.clear();
+        serde_json::to_writer(&mut self.line, cmd)?;
+        self.line.push(b'\n');
+        self.hasher.update(&self.line);
+        self.writer.write_all(&self.line)?;
+        Ok(())
+    }
+
+    /// Writes the trailer and flushes the dump.
+    pub(super) fn finish(mut self) -> Result<()> {
+        let trailer = Trailer {
+            crc32: self.hasher.finalize(),
+        };
+        serde_json::to_writer(&mut self.writer, &trailer)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the records of a dump, checking the trailer after the last one.
+pub(super) struct DumpReader<R: BufRead> {
+    reader: R,
+    remaining: usize,
+    hasher: Hasher,
+    line: Vec<u8>,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    /// Reads the header of a dump.
+    pub(super) fn new(reader: R) -> Result<Self> {
+        let mut dump = DumpReader {
+            reader,
+            remaining: 0,
+            hasher: Hasher::new(),
+            line: Vec::new(),
+        };
+        dump.read_line()?;
+        let header: Header = serde_json::from_slice(&dump.line)
+            .ok()
+            .filter(|header: &Header| header.magic == MAGIC)
+            .ok_or_else(|| KvsError::StringError("Not a kvs dump".to_owned()))?;
+        if header.version != VERSION {
+            return Err(KvsError::UnsupportedFormat {
+                found: header.version,
+                expected: VERSION,
+            });
+        }
+        dump.remaining = header.records;
+        Ok(dump)
+    }
+
+    /// Returns the next record, or `None` once all of them have been read and
+    /// the checksum matched.
+    pub(super) fn next(&mut self) -> Result<Option<Command>> {
+        self.read_line()?;
+        if self.remaining == 0 {
+            let trailer: Trailer = serde_json::from_slice(&self.line)?;
+            let crc32 = self.hasher.clone().finalize();
+            if trailer.crc32 != crc32 {
+                return Err(KvsError::ChecksumMismatch);
+            }
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        self.hasher.update(&self.line);
+        self.hasher.update(b"\n");
+        Ok(Some(serde_json::from_slice(&self.line)?))
+    }
+
+    /// Reads the next line into `self.line`, without its line break.
+    fn read_line(&mut self) -> Result<()> {
+        self.line.clear();
+        self.reader.read_until(b'\n', &mut self.line)?;
+        if self.line.pop() != Some(b'\n') {
+            return Err(KvsError::TruncatedDump);
+        }
+        Ok(())
+    }
+}
@@ -1,29 +1,71 @@
+#[cfg(feature = "mmap")]
+use super::mmap::LogMap;
+use super::cache::LruCache;
+use super::dump::{DumpReader, DumpWriter};
+use super::namespace::NamespaceHandle;
+use super::record::{self, RecordReader};
+use super::snapshot::{KvStoreIter, Snapshot};
+use super::stream;
+use super::txn::Txn;
+use super::watch::{Event, Watchers};
+use super::{CompactionPolicy, FlushMode, SizeLimits, StoreConfig};
 use crate::error::{KvsError, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use fs2::FileExt;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1MB
+/// Base name of the store's files unless `StoreConfig::name` says otherwise.
+pub(super) const DEFAULT_NAME: &str = "wal";
+/// Version of the format of the store's files, recorded in `<name>.version`.
+/// Bump it with every change that older versions can't read, and teach
+/// `upgrade` to convert the previous format.
+const FORMAT_VERSION: u32 = 1;
 
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to a log file on disk.
-/// The log file is named `wal.log`.
+/// The log file is named `wal.log`, or `<name>.log` with `StoreConfig::name`.
 /// An in-memory `HashMap` is used to index the log file.
 ///
+/// # Thread safety
+///
+/// `KvStore` is a cheap handle that can be cloned and sent to other threads;
+/// all clones share the same store. Every operation, reads included, runs under
+/// a single lock, so operations are atomic and take effect in one total order:
+/// a `get` sees every `set` or `remove` that returned before it started, and
+/// reopening the store after any interleaving of writers yields the state left
+/// by the last write to each key. The flip side is that operations never run in
+/// parallel, including reads of different keys. A thread that panics while
+/// holding the lock, e.g. in a `transaction` closure, leaves the store usable
+/// by the others.
+///
+/// Compaction is the exception: it copies the live records without the lock
+/// and only takes it to swap the new log in, so it doesn't stall other
+/// operations. Writes made while it runs are carried over to the new log.
+///
 /// Example:
 ///
 /// ```rust
-/// use std::env::current_dir;
 /// use kvs::{KvStore, Result};
+/// use tempfile::TempDir;
 ///
 /// fn main() -> Result<()> {
-///     let mut store = KvStore::open(current_dir()?)?;
-///     store.set("key".to_owned(), "value".to_owned())?;
-///     let val = store.get("key".to_owned())?;
+///     let temp_dir = TempDir::new()?;
+///     let mut store = KvStore::open(temp_dir.path())?;
+///     store.set("key", "value")?;
+///     let val = store.get("key")?;
 ///     assert_eq!(val, Some("value".to_owned()));
 ///     Ok(())
 /// }
@@ -32,46 +74,171 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1MB
 pub struct KvStore(Arc<Mutex<KvStoreInner>>);
 
 pub struct KvStoreInner {
-    path: PathBuf,
+    files: StoreFiles,
+    /// The lock on the store's files, released when the store is dropped.
+    /// `None` if the store is read-only.
+    lock: Option<File>,
     writer: BufWriter<File>,
+    /// Length of the log including writes still in `writer`'s buffer.
+    ///
+    /// Kept separately because asking the `BufWriter` for its position would
+    /// flush it.
+    write_pos: u64,
+    /// Length of the log file, which is ahead of `write_pos` when the log is
+    /// preallocated.
+    allocated: u64,
+    /// How far ahead of `write_pos` the log file is grown.
+    preallocate: Option<u64>,
+    flush_mode: FlushMode,
+    compaction_policy: CompactionPolicy,
+    size_limits: SizeLimits,
+    /// Whether to write the hint file when the store is closed.
+    hint_on_close: bool,
     reader: BufReader<File>,
     index: HashMap<String, CommandPos>,
+    history: History,
     stale_bytes: u64,
+    /// Memory map of the log used to read values without a seek and copy.
+    #[cfg(feature = "mmap")]
+    map: Option<LogMap>,
+    cache: LruCache,
+    cache_hits: u64,
+    cache_misses: u64,
+    watchers: Watchers,
+    /// Whether a compaction is currently rewriting the log.
+    compacting: bool,
+    /// Stops the background flush thread when dropped.
+    flusher: Option<Sender<()>>,
+    last_compaction: Option<CompactionReport>,
+}
+
+/// What a finished compaction did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// When the compaction started.
+    pub started_at: SystemTime,
+    /// How long the compaction took, including the time spent rewriting the
+    /// log without holding the lock.
+    pub duration: Duration,
+    /// Size of the log in bytes right before the new one replaced it.
+    pub bytes_before: u64,
+    /// Size of the new log in bytes.
+    pub bytes_after: u64,
+    /// Number of keys in the new log.
+    pub keys_retained: usize,
+}
+
+/// What compacting a `KvStore` would do, as returned by
+/// `KvStore::compaction_estimate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Bytes in the log taken by overwritten or removed entries, which
+    /// compaction reclaims.
+    pub stale_bytes: u64,
+    /// Bytes in the log taken by the current value of each key and the
+    /// previous versions kept of it, which compaction copies.
+    pub live_bytes: u64,
+    /// Size of the log now.
+    pub log_bytes: u64,
+    /// Size of the log after compacting, unless it is written to meanwhile.
+    pub projected_bytes: u64,
+}
+
+/// Statistics about the on-disk state of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of keys currently stored.
+    pub live_keys: usize,
+    /// Bytes in the log taken by overwritten or removed entries.
+    pub stale_bytes: u64,
+    /// Total size of the log in bytes.
+    pub total_log_bytes: u64,
+    /// Number of reads served from the value cache.
+    pub cache_hits: u64,
+    /// Number of reads that had to go to the log while the value cache was enabled.
+    pub cache_misses: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
-struct CommandPos {
-    pos: u64,
-    len: u64,
+pub(super) struct CommandPos {
+    pub(super) pos: u64,
+    pub(super) len: u64,
 }
 
 impl KvStoreInner {
-    fn build_index(reader_file: &File) -> Result<(HashMap<String, CommandPos>, u64)> {
+    /// Builds the index for the log, starting from the hint file if there is a
+    /// usable one and replaying only the records appended after it.
+    ///
+    /// `log_len` is the length of the log without its preallocated part.
+    fn load_index(
+        files: &StoreFiles,
+        reader_file: &File,
+        log_len: u64,
+        history_depth: usize,
+    ) -> Result<(HashMap<String, CommandPos>, History, u64)> {
+        if let Some(hint) = Hint::load(files)?.filter(|hint| hint.log_len <= log_len) {
+            let mut index = hint.index();
+            let mut stale_bytes = hint.stale_bytes;
+            let mut history = hint.history(history_depth, &mut stale_bytes);
+            match KvStoreInner::build_index(
+                reader_file,
+                hint.log_len..log_len,
+                &mut index,
+                &mut history,
+                &mut stale_bytes,
+            ) {
+                Ok(()) => return Ok((index, history, stale_bytes)),
+                Err(e) => warn!("Ignoring hint file that does not match the log: {}", e),
+            }
+        }
+
         let mut index = HashMap::new();
+        let mut history = History::new(history_depth);
         let mut stale_bytes = 0;
-        let mut reader = BufReader::new(reader_file);
-        let mut pos = reader.seek(SeekFrom::Start(0))?;
-        let mut stream = serde_json::Deserializer::from_reader(&mut reader).into_iter::<Command>();
+        KvStoreInner::build_index(reader_file, 0..log_len, &mut index, &mut history, &mut stale_bytes)?;
+        Ok((index, history, stale_bytes))
+    }
 
-        while let Some(cmd) = stream.next() {
-            let new_pos = stream.byte_offset() as u64;
+    /// Replays the records in `range` of the log into `index`.
+    ///
+    /// The range ends where the records do rather than at the end of the
+    /// file, which may be preallocated further.
+    fn build_index(
+        mut reader_file: &File,
+        range: Range<u64>,
+        index: &mut HashMap<String, CommandPos>,
+        history: &mut History,
+        stale_bytes: &mut u64,
+    ) -> Result<()> {
+        let start = range.start;
+        let mut pos = reader_file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(reader_file.take(range.end.saturating_sub(start)));
+        let mut records = RecordReader::new(&mut reader);
+
+        while let Some(cmd) = records.next()? {
+            let new_pos = start + records.pos();
             let len = new_pos - pos;
-            match cmd? {
-                Command::Set { key, .. } => {
-                    if let Some(old_cmd) = index.insert(key, CommandPos { pos, len }) {
-                        stale_bytes += old_cmd.len;
+            match cmd {
+                Command::Set { key, .. } | Command::SetBytes { key, .. } => match index.entry(key) {
+                    Entry::Occupied(mut entry) => {
+                        let old_cmd = entry.insert(CommandPos { pos, len });
+                        *stale_bytes += history.push(entry.key(), old_cmd);
                     }
-                }
+                    Entry::Vacant(entry) => {
+                        entry.insert(CommandPos { pos, len });
+                    }
+                },
                 Command::Remove { key } => {
                     if let Some(old_cmd) = index.remove(&key) {
-                        stale_bytes += old_cmd.len;
+                        *stale_bytes += old_cmd.len;
                     }
-                    stale_bytes += len;
+                    *stale_bytes += history.remove(&key);
+                    *stale_bytes += len;
                 }
             }
             pos = new_pos;
         }
-        Ok((index, stale_bytes))
+        Ok(())
     }
 
     /// Sets the value of a string key to a string.
@@ -79,68 +246,208 @@ impl KvStoreInner {
     /// If the key already exists, the previous value will be overwritten.
     /// The command is written to the log file and the index is updated.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set {
+        self.size_limits.check(&key, value.len())?;
+        let cmd = CommandRef::Set {
+            key: &key,
+            value: &value,
+        };
+        let cmd_pos = self.append(&cmd)?;
+        let event = self.watchers.matches(&key).then(|| Event::Set {
             key: key.clone(),
             value,
-        };
+        });
+        self.insert(key, cmd_pos, event);
+        Ok(())
+    }
 
-        let pos = self.writer.stream_position()?;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        let new_pos = self.writer.stream_position()?;
-        let len = new_pos - pos;
+    /// Sets the value of a key to arbitrary bytes.
+    pub fn set_bytes(&mut self, key: String, value: &[u8]) -> Result<()> {
+        self.size_limits.check(&key, value.len())?;
+        let cmd = CommandRef::SetBytes { key: &key, value };
+        let cmd_pos = self.append(&cmd)?;
+        let event = self.watchers.matches(&key).then(|| Event::Set {
+            key: key.clone(),
+            value: String::from_utf8_lossy(value).into_owned(),
+        });
+        self.insert(key, cmd_pos, event);
+        Ok(())
+    }
 
-        if let Some(old_cmd) = self.index.insert(key, CommandPos { pos, len }) {
-            self.stale_bytes += old_cmd.len;
+    /// Appends a command to the log and returns where it was written.
+    ///
+    /// The command is only flushed to the file right away in
+    /// `FlushMode::EveryWrite`; otherwise it may sit in the write buffer until
+    /// the next read from the log, `flush`, or the store is dropped.
+    fn append(&mut self, cmd: &CommandRef) -> Result<CommandPos> {
+        if self.lock.is_none() {
+            return Err(KvsError::ReadOnly);
         }
-
-        if self.stale_bytes > COMPACTION_THRESHOLD {
-            self.compact()?;
+        let mut buf = Vec::new();
+        record::encode(cmd, &mut buf)?;
+        let pos = self.write_pos;
+        self.preallocate(pos + buf.len() as u64)?;
+        self.writer.write_all(&buf)?;
+        if self.flush_mode == FlushMode::EveryWrite {
+            self.writer.flush()?;
         }
+        self.write_pos += buf.len() as u64;
+        Ok(CommandPos { pos, len: buf.len() as u64 })
+    }
 
+    /// Makes sure the log file is at least `end` bytes long, growing it
+    /// `StoreConfig::preallocate_bytes` past `end` when it isn't.
+    ///
+    /// Without preallocation the file just grows with the writes.
+    fn preallocate(&mut self, end: u64) -> Result<()> {
+        if let Some(chunk) = self.preallocate
+            && end > self.allocated
+        {
+            let len = end + chunk;
+            self.writer.get_ref().set_len(len)?;
+            self.allocated = len;
+        }
         Ok(())
     }
 
+    /// Points the index at a newly written value of `key`.
+    fn insert(&mut self, key: String, cmd_pos: CommandPos, event: Option<Event>) {
+        self.cache.remove(&key);
+        match self.index.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let old_cmd = entry.insert(cmd_pos);
+                self.stale_bytes += self.history.push(entry.key(), old_cmd);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(cmd_pos);
+            }
+        }
+        if let Some(event) = event {
+            self.watchers.notify(event);
+        }
+    }
+
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    /// The value is read from the log file.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            self.reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = self.reader.get_mut().take(cmd_pos.len);
-            let cmd = serde_json::from_reader(cmd_reader)?;
-
-            if let Command::Set { value, .. } = cmd {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
+    /// The value is read from the value cache if present, or else from the log file.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        if let Some(&cmd_pos) = self.index.get(key) {
+            if !self.cache.is_enabled() {
+                return Ok(Some(self.read_string(cmd_pos)?));
+            }
+            if let Some(value) = self.cache.get(key) {
+                self.cache_hits += 1;
+                return Ok(Some(value));
             }
+            self.cache_misses += 1;
+            let value = self.read_string(cmd_pos)?;
+            self.cache.insert(key.to_owned(), value.clone());
+            Ok(Some(value))
         } else {
             Ok(None)
         }
     }
 
+    /// Returns the current value of `key` followed by the previous values kept
+    /// in its history, newest first.
+    pub fn get_versions(&mut self, key: &str) -> Result<Vec<String>> {
+        let Some(&cmd_pos) = self.index.get(key) else {
+            return Ok(Vec::new());
+        };
+        let old_cmds = self.history.get(key);
+        let mut values = Vec::with_capacity(old_cmds.len() + 1);
+        values.push(self.read_string(cmd_pos)?);
+        for old_cmd in old_cmds {
+            values.push(self.read_string(old_cmd)?);
+        }
+        Ok(values)
+    }
+
+    /// Reads the value stored at `cmd_pos` as a string.
+    fn read_string(&mut self, cmd_pos: CommandPos) -> Result<String> {
+        match self.read_command(cmd_pos)? {
+            Command::Set { value, .. } => Ok(value),
+            Command::SetBytes { value, .. } => Ok(String::from_utf8(value)?),
+            Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Gets the value of a given key as raw bytes.
+    pub fn get_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            Some(&cmd_pos) => match self.read_command(cmd_pos)? {
+                Command::Set { value, .. } => Ok(Some(value.into_bytes())),
+                Command::SetBytes { value, .. } => Ok(Some(value)),
+                Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Copies the value of a key to `writer` straight from the log, returning
+    /// whether the key exists.
+    pub fn get_to_writer<W: Write>(&mut self, key: &str, writer: &mut W) -> Result<bool> {
+        let Some(&cmd_pos) = self.index.get(key) else {
+            return Ok(false);
+        };
+        // The command may still be in the write buffer.
+        self.writer.flush()?;
+        #[cfg(feature = "mmap")]
+        {
+            let start = cmd_pos.pos as usize;
+            let end = start + cmd_pos.len as usize;
+            if self.map.as_ref().is_none_or(|map| map.len() < end) {
+                self.map = LogMap::map(self.reader.get_ref())?;
+            }
+            if let Some(map) = self.map.as_ref().filter(|map| map.len() >= end) {
+                stream::copy_value(&map.as_slice()[start..end], writer)?;
+                return Ok(true);
+            }
+        }
+        self.reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        stream::copy_value((&mut self.reader).take(cmd_pos.len), writer)?;
+        Ok(true)
+    }
+
+    /// Reads the command stored at `cmd_pos` from the log.
+    ///
+    /// With the `mmap` feature the command is parsed straight out of a memory map
+    /// of the log, which is remapped whenever the log has grown past the mapped range.
+    fn read_command(&mut self, cmd_pos: CommandPos) -> Result<Command> {
+        // The command may still be in the write buffer.
+        self.writer.flush()?;
+        #[cfg(feature = "mmap")]
+        {
+            let start = cmd_pos.pos as usize;
+            let end = start + cmd_pos.len as usize;
+            if self.map.as_ref().is_none_or(|map| map.len() < end) {
+                self.map = LogMap::map(self.reader.get_ref())?;
+            }
+            if let Some(map) = self.map.as_ref().filter(|map| map.len() >= end) {
+                return record::decode(&map.as_slice()[start..end]);
+            }
+        }
+        self.reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut buf = vec![0; cmd_pos.len as usize];
+        self.reader.read_exact(&mut buf)?;
+        record::decode(&buf)
+    }
+
     /// Remove a given key.
     ///
     /// A `Remove` command is written to the log file and the key is removed from the index.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::Remove { key: key.clone() };
-            let pos = self.writer.stream_position()?;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-
-            let new_pos = self.writer.stream_position()?;
-            let len = new_pos - pos;
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        if self.index.contains_key(key) {
+            let cmd_pos = self.append(&CommandRef::Remove { key })?;
 
-            if let Some(old_cmd) = self.index.remove(&key) {
+            self.cache.remove(key);
+            if let Some(old_cmd) = self.index.remove(key) {
                 self.stale_bytes += old_cmd.len;
-                self.stale_bytes += len;
+                self.stale_bytes += cmd_pos.len;
             }
-
-            if self.stale_bytes > COMPACTION_THRESHOLD {
-                self.compact()?;
+            self.stale_bytes += self.history.remove(key);
+            if self.watchers.matches(key) {
+                self.watchers.notify(Event::Remove { key: key.to_owned() });
             }
 
             Ok(())
@@ -149,48 +456,492 @@ impl KvStoreInner {
         }
     }
 
-    fn compact(&mut self) -> Result<()> {
-        // 1. Create new log file and a new index
-        let compaction_path = self.path.join("wal.log.compact");
-        let mut compaction_writer = BufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&compaction_path)?,
-        );
-        let mut new_index = HashMap::new();
+    /// Removes a key and returns the value it had, or `None` if it doesn't exist.
+    pub fn take(&mut self, key: &str) -> Result<Option<String>> {
+        let Some(&cmd_pos) = self.index.get(key) else {
+            return Ok(None);
+        };
+        let value = self.read_string(cmd_pos)?;
+        self.remove(key)?;
+        Ok(Some(value))
+    }
+
+    /// Moves the value of `from` to `to`, overwriting `to` if it exists.
+    ///
+    /// Returns `KvsError::KeyNotFound` if `from` doesn't exist.
+    pub fn rename(&mut self, from: &str, to: String) -> Result<()> {
+        let Some(&cmd_pos) = self.index.get(from) else {
+            return Err(KvsError::KeyNotFound);
+        };
+        if from == to {
+            return Ok(());
+        }
+        match self.read_command(cmd_pos)? {
+            Command::Set { value, .. } => self.set(to, value)?,
+            Command::SetBytes { value, .. } => self.set_bytes(to, &value)?,
+            Command::Remove { .. } => return Err(KvsError::UnexpectedCommandType),
+        }
+        self.remove(from)
+    }
+
+    /// Returns every key starting with `prefix` with its value, sorted by key.
+    pub fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<String> = self
+            .index
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Removes every key starting with `prefix` and returns how many were removed.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize> {
+        let keys: Vec<String> = self
+            .index
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in &keys {
+            self.remove(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Writes a dump of the current value of every key to `writer`, sorted by
+    /// key, and returns how many there were.
+    pub fn export<W: Write>(&mut self, writer: W) -> Result<usize> {
+        let mut records: Vec<(String, CommandPos)> =
+            self.index.iter().map(|(key, &cmd_pos)| (key.clone(), cmd_pos)).collect();
+        records.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut dump = DumpWriter::new(writer, records.len())?;
+        for &(_, cmd_pos) in &records {
+            dump.write(&self.read_command(cmd_pos)?)?;
+        }
+        dump.finish()?;
+        Ok(records.len())
+    }
+
+    /// Appends the records of the dump read from `reader` to the log with a
+    /// single flush, then indexes them in one pass, and returns how many there
+    /// were.
+    ///
+    /// If the dump turns out to be broken, the log is cut back to where it was
+    /// and nothing is loaded.
+    pub fn bulk_load<R: Read>(&mut self, reader: R) -> Result<usize> {
+        if self.lock.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut dump = DumpReader::new(BufReader::new(reader))?;
+        self.writer.flush()?;
+        let start = self.write_pos;
+        let mut count = 0;
+        let mut buf = Vec::new();
+        let mut load = || -> Result<()> {
+            while let Some(cmd) = dump.next()? {
+                buf.clear();
+                record::encode(&cmd.as_command_ref(), &mut buf)?;
+                self.preallocate(self.write_pos + buf.len() as u64)?;
+                self.writer.write_all(&buf)?;
+                self.write_pos += buf.len() as u64;
+                count += 1;
+            }
+            Ok(())
+        };
+        if let Err(e) = load() {
+            self.writer.flush()?;
+            self.writer.get_ref().set_len(start)?;
+            // A map of the cut records would fault when read.
+            #[cfg(feature = "mmap")]
+            {
+                self.map = None;
+            }
+            self.writer.seek(SeekFrom::Start(start))?;
+            self.write_pos = start;
+            self.allocated = start;
+            return Err(e);
+        }
+
+        self.writer.flush()?;
+        KvStoreInner::build_index(
+            self.reader.get_ref(),
+            start..self.write_pos,
+            &mut self.index,
+            &mut self.history,
+            &mut self.stale_bytes,
+        )?;
+        self.cache.clear();
+        Ok(count)
+    }
+
+    fn stats(&mut self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            live_keys: self.index.len(),
+            stale_bytes: self.stale_bytes,
+            total_log_bytes: self.write_pos,
+            cache_hits: self.cache_hits,
+            cache_misses: self.cache_misses,
+        })
+    }
+
+    fn compaction_estimate(&self) -> CompactionEstimate {
+        let live_bytes = self.index.values().map(|cmd_pos| cmd_pos.len).sum::<u64>() + self.history.bytes();
+        CompactionEstimate {
+            stale_bytes: self.stale_bytes,
+            live_bytes,
+            log_bytes: self.write_pos,
+            // Compaction copies the live records as they are.
+            projected_bytes: live_bytes,
+        }
+    }
 
-        // 2. Write current values to new log and build new index
-        for key in self.index.keys() {
-            let cmd_pos = self.index.get(key).unwrap();
-            self.reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let mut cmd_reader = self.reader.get_mut().take(cmd_pos.len);
+    /// Returns whether enough stale data has built up to compact, and no
+    /// compaction is running yet.
+    fn needs_compaction(&self) -> bool {
+        let due = match self.compaction_policy {
+            CompactionPolicy::AbsoluteBytes(bytes) => self.stale_bytes > bytes,
+            CompactionPolicy::StaleRatio(ratio) => {
+                self.write_pos > 0 && self.stale_bytes as f64 / self.write_pos as f64 > ratio
+            }
+        };
+        due && !self.compacting
+    }
 
-            let pos = compaction_writer.stream_position()?;
-            std::io::copy(&mut cmd_reader, &mut compaction_writer)?;
-            let new_pos = compaction_writer.stream_position()?;
-            new_index.insert(key.clone(), CommandPos { pos, len: new_pos - pos });
+    /// Starts a compaction by capturing the live records and pinning the
+    /// current log, so that they can be rewritten without holding the lock.
+    ///
+    /// Returns `None` if a compaction is already running.
+    fn begin_compaction(&mut self) -> Result<Option<CompactionBase>> {
+        if self.lock.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.compacting {
+            return Ok(None);
         }
+        self.writer.flush()?;
+        let base = CompactionBase {
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            files: self.files.clone(),
+            log: File::open(self.files.log())?,
+            log_end: self.write_pos,
+            history_depth: self.history.depth,
+            records: self
+                .index
+                .iter()
+                .map(|(key, &cmd_pos)| {
+                    let mut versions = self.history.get(key);
+                    versions.reverse();
+                    versions.push(cmd_pos);
+                    (key.clone(), versions)
+                })
+                .collect(),
+        };
+        self.compacting = true;
+        Ok(Some(base))
+    }
+
+    /// Finishes a compaction whose live records have been written to
+    /// `compaction_writer`: appends everything written to the log since
+    /// `log_end`, then swaps the new log and index in.
+    fn finish_compaction(
+        &mut self,
+        mut compaction_writer: BufWriter<File>,
+        tail_start: u64,
+        log_end: u64,
+        mut new_index: HashMap<String, CommandPos>,
+        mut new_history: History,
+        started: (SystemTime, Instant),
+    ) -> Result<()> {
+        // 1. Copy the writes that happened during the rewrite and replay them
+        // on top of the new index.
+        self.writer.flush()?;
+        let end = self.write_pos;
+        self.reader.seek(SeekFrom::Start(log_end))?;
+        let mut tail_reader = self.reader.get_mut().take(end - log_end);
+        std::io::copy(&mut tail_reader, &mut compaction_writer)?;
         compaction_writer.flush()?;
+        let compaction_path = self.files.compact_log();
+        let mut stale_bytes = 0;
+        let log_len = tail_start + (end - log_end);
+        KvStoreInner::build_index(
+            &File::open(&compaction_path)?,
+            tail_start..log_len,
+            &mut new_index,
+            &mut new_history,
+            &mut stale_bytes,
+        )?;
+        let hint_path = Hint::new(log_len, stale_bytes, &new_index, &new_history).write(&self.files)?;
 
-        // 3. Atomically replace old log with new
-        std::fs::rename(&compaction_path, self.path.join("wal.log"))?;
+        // 2. Atomically replace old log with new. The old hint is removed first so
+        // that a crash in between never leaves a hint next to a log it doesn't describe.
+        Hint::remove(&self.files)?;
+        std::fs::rename(&compaction_path, self.files.log())?;
+        std::fs::rename(hint_path, self.files.hint())?;
 
-        // 4. Re-open writer and reader, update index and stale_bytes
+        // 3. Re-open writer and reader, update index and stale_bytes
         self.writer = BufWriter::new(
             OpenOptions::new()
                 .write(true)
-                .open(self.path.join("wal.log"))?,
+                .open(self.files.log())?,
         );
-        self.writer.seek(SeekFrom::End(0))?;
-        self.reader = BufReader::new(File::open(self.path.join("wal.log"))?);
+        self.write_pos = self.writer.seek(SeekFrom::End(0))?;
+        self.allocated = self.write_pos;
+        self.reader = BufReader::new(File::open(self.files.log())?);
         self.index = new_index;
-        self.stale_bytes = 0;
+        self.history = new_history;
+        self.stale_bytes = stale_bytes;
+        // The old map still points at the replaced log.
+        #[cfg(feature = "mmap")]
+        {
+            self.map = None;
+        }
+
+        let report = CompactionReport {
+            started_at: started.0,
+            duration: started.1.elapsed(),
+            bytes_before: end,
+            bytes_after: self.write_pos,
+            keys_retained: self.index.len(),
+        };
+        info!(
+            "Compacted {} from {} to {} bytes in {:?}",
+            self.files.log().display(),
+            report.bytes_before,
+            report.bytes_after,
+            report.duration
+        );
+        self.last_compaction = Some(report);
+        Ok(())
+    }
+
+    /// Writes a hint file describing the whole log, replacing the current one.
+    fn write_hint(&self) -> Result<()> {
+        let hint = Hint::new(self.write_pos, self.stale_bytes, &self.index, &self.history);
+        std::fs::rename(hint.write(&self.files)?, self.files.hint())?;
+        Ok(())
+    }
+}
+
+/// Returns the length of the log without the zeros preallocated past its
+/// records.
+///
+/// Every record ends in a non-zero byte, the closing brace of its JSON or the
+/// newline after a binary value, so the records end at the last non-zero byte
+/// of the file.
+fn log_end(mut file: &File) -> Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut buf = vec![0; 64 * 1024];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let block = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+        if let Some(i) = block.iter().rposition(|&b| b != 0) {
+            return Ok(start + i as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Takes the store's lock, recovering it if a thread panicked while holding it
+/// rather than failing every operation from then on.
+///
+/// The store is still usable after such a panic: the log is only appended to,
+/// and the index only ever points at records whose append succeeded.
+fn lock_store(store: &Mutex<KvStoreInner>) -> MutexGuard<'_, KvStoreInner> {
+    store.lock().unwrap_or_else(|e| {
+        warn!("Recovering the lock of a store a thread panicked while holding it");
+        store.clear_poison();
+        e.into_inner()
+    })
+}
+
+/// Flushes the store's buffered writes every `interval` until the store is
+/// dropped, which disconnects `stopped`.
+fn flush_periodically(store: Weak<Mutex<KvStoreInner>>, stopped: Receiver<()>, interval: Duration) {
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+        let Some(store) = store.upgrade() else {
+            break;
+        };
+        if let Err(e) = lock_store(&store).writer.flush() {
+            error!("Failed to flush buffered writes: {}", e);
+        }
+    }
+}
+
+impl Drop for KvStoreInner {
+    // Runs once the last `KvStore` handle is gone, as the handles share the
+    // inner state through an `Arc`.
+    fn drop(&mut self) {
+        drop(self.flusher.take());
+        if let Err(e) = self.writer.flush() {
+            error!("Failed to flush buffered writes on close: {}", e);
+        } else if self.hint_on_close
+            && self.lock.is_some()
+            && let Err(e) = self.write_hint()
+        {
+            error!("Failed to write hint file on close: {}", e);
+        }
+    }
+}
+
+/// The paths of a store's files: `<name>.log` and `<name>.hint` in the store's
+/// directory, and the `.compact` files a compaction writes before renaming them
+/// into place.
+#[derive(Clone)]
+struct StoreFiles {
+    dir: PathBuf,
+    name: String,
+}
+
+impl StoreFiles {
+    fn log(&self) -> PathBuf {
+        self.dir.join(format!("{}.log", self.name))
+    }
+
+    fn hint(&self) -> PathBuf {
+        self.dir.join(format!("{}.hint", self.name))
+    }
+
+    fn compact_log(&self) -> PathBuf {
+        self.dir.join(format!("{}.log.compact", self.name))
+    }
+
+    fn compact_hint(&self) -> PathBuf {
+        self.dir.join(format!("{}.hint.compact", self.name))
+    }
+
+    fn version(&self) -> PathBuf {
+        self.dir.join(format!("{}.version", self.name))
+    }
+
+    fn lock_file(&self) -> PathBuf {
+        self.dir.join(format!("{}.lock", self.name))
+    }
+
+    /// Takes the lock that only one writable store holds at a time.
+    fn lock(&self) -> Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.lock_file())?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(file),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(KvsError::AlreadyLocked)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks that the files are in the current format, upgrading them if they
+    /// were written by an older version, and records the version of a new store
+    /// unless it is opened `read_only`.
+    fn check_format(&self, read_only: bool) -> Result<()> {
+        let path = self.version();
+        let found = match std::fs::read_to_string(&path) {
+            Ok(contents) => Some(contents.trim().parse().map_err(|_| {
+                KvsError::StringError(format!("Invalid format version in {}", path.display()))
+            })?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        match found {
+            Some(FORMAT_VERSION) => return Ok(()),
+            Some(found) if found > FORMAT_VERSION => {
+                return Err(KvsError::UnsupportedFormat {
+                    found,
+                    expected: FORMAT_VERSION,
+                });
+            }
+            Some(found) => self.upgrade(found)?,
+            // Stores written before the version was recorded have a log but
+            // no version file.
+            None if self.log().exists() => self.upgrade(0)?,
+            None => {}
+        }
+        if !read_only {
+            std::fs::write(path, format!("{}\n", FORMAT_VERSION))?;
+        }
+        Ok(())
+    }
+
+    /// Empties the log if it was last written more than `max_age` ago.
+    fn expire(&self, max_age: Duration) -> Result<()> {
+        let modified = match std::fs::metadata(self.log()) {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        // A modification time in the future counts as new.
+        let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+        if age > max_age {
+            info!(
+                "Discarding {}, last written {:?} ago, which is more than the maximum age of {:?}",
+                self.log().display(),
+                age,
+                max_age
+            );
+            Hint::remove(self)?;
+            File::create(self.log())?;
+        }
+        Ok(())
+    }
 
+    /// Converts the files from format `from` to the current one.
+    fn upgrade(&self, from: u32) -> Result<()> {
+        match from {
+            // The format before versions were recorded, which is version 1
+            // without the version file.
+            0 => {}
+            found => {
+                return Err(KvsError::UnsupportedFormat {
+                    found,
+                    expected: FORMAT_VERSION,
+                });
+            }
+        }
+        info!("Upgraded {} from format version {} to {}", self.log().display(), from, FORMAT_VERSION);
         Ok(())
     }
 }
 
+/// The state a compaction starts from: the live records at the time and a
+/// handle to the log they are in.
+struct CompactionBase {
+    files: StoreFiles,
+    log: File,
+    /// Length of the log when the compaction started.
+    log_end: u64,
+    /// The versions kept of each live key, oldest first.
+    records: Vec<(String, Vec<CommandPos>)>,
+    history_depth: usize,
+    started_at: SystemTime,
+    started: Instant,
+}
+
+/// Marks the compaction of a store as over when dropped, even if the
+/// compaction panicked, so that the store can be compacted again.
+struct Compacting<'a>(&'a Mutex<KvStoreInner>);
+
+impl Drop for Compacting<'_> {
+    fn drop(&mut self) {
+        lock_store(self.0).compacting = false;
+    }
+}
+
 impl KvStore {
     /// Opens a `KvStore` with the given path.
     ///
@@ -198,48 +949,526 @@ impl KvStore {
     /// It will also create a `wal.log` file if it does not exist.
     /// The index will be built from the log file.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        std::fs::create_dir_all(&path)?;
-        let log_path = path.join("wal.log");
+        KvStore::open_with_config(path, StoreConfig::default())
+    }
 
-        let writer_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&log_path)?;
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// Stores with different `StoreConfig::name`s can share a directory.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnsupportedFormat` if the store was written in a
+    /// format this version doesn't know, such as by a newer version. Stores
+    /// written by older versions are upgraded.
+    pub fn open_with_config(path: impl Into<PathBuf>, config: StoreConfig) -> Result<KvStore> {
+        let files = StoreFiles {
+            dir: path.into(),
+            name: config.name.unwrap_or_else(|| DEFAULT_NAME.to_owned()),
+        };
+        let log_path = files.log();
+        let lock = if config.read_only {
+            None
+        } else {
+            std::fs::create_dir_all(&files.dir)?;
+            Some(files.lock()?)
+        };
+        files.check_format(config.read_only)?;
+        if let (Some(max_age), false) = (config.max_age, config.read_only) {
+            files.expire(max_age)?;
+        }
+        // Files left behind by a compaction that crashed before they were renamed
+        // into place; the log and hint they were meant to replace are still intact.
+        if !config.read_only {
+            for orphan in [files.compact_log(), files.compact_hint()] {
+                match std::fs::remove_file(orphan) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+
+        // A read-only store never writes, but still needs a writer.
+        let writer_file = if config.read_only {
+            File::open(&log_path)?
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&log_path)?
+        };
         let reader_file = File::open(&log_path)?;
+        let allocated = reader_file.metadata()?.len();
+        let write_pos = log_end(&reader_file)?;
 
-        let (index, stale_bytes) = KvStoreInner::build_index(&reader_file)?;
+        let (index, history, stale_bytes) =
+            KvStoreInner::load_index(&files, &reader_file, write_pos, config.history_depth)?;
 
         let mut writer = BufWriter::new(writer_file);
-        writer.seek(SeekFrom::End(0))?;
+        writer.seek(SeekFrom::Start(write_pos))?;
 
         let inner = KvStoreInner {
-            path,
+            files,
+            lock,
             writer,
+            write_pos,
+            allocated,
+            preallocate: config.preallocate_bytes,
+            flush_mode: config.flush_mode,
+            compaction_policy: config.compaction_policy,
+            size_limits: SizeLimits {
+                max_key_len: config.max_key_len,
+                max_value_len: config.max_value_len,
+            },
+            hint_on_close: config.hint_on_close,
             reader: BufReader::new(reader_file),
             index,
+            history,
             stale_bytes,
+            #[cfg(feature = "mmap")]
+            map: None,
+            cache: LruCache::new(config.value_cache_entries),
+            cache_hits: 0,
+            cache_misses: 0,
+            watchers: Watchers::default(),
+            compacting: false,
+            flusher: None,
+            last_compaction: None,
         };
+        let store = KvStore(Arc::new(Mutex::new(inner)));
 
-        Ok(KvStore(Arc::new(Mutex::new(inner))))
+        if let (FlushMode::Background, Some(ms), false) =
+            (config.flush_mode, config.flush_every_ms, config.read_only)
+        {
+            let (stop, stopped) = crossbeam_channel::bounded(0);
+            lock_store(&store.0).flusher = Some(stop);
+            let weak = Arc::downgrade(&store.0);
+            thread::Builder::new()
+                .name("kvs-flush".to_owned())
+                .spawn(move || flush_periodically(weak, stopped, Duration::from_millis(ms)))?;
+        }
+        Ok(store)
     }
 
     /// Sets the value of a string key to a string.
-    pub fn set(&self, key: String, value: String) -> Result<()> {
-        let mut inner = self.0.lock().unwrap();
-        inner.set(key, value)
+    ///
+    /// Takes anything that converts into a `String`, such as a `&str`, so that
+    /// callers don't have to convert it themselves.
+    pub fn set(&self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.write(|inner| inner.set(key, value))
     }
 
     /// Gets the string value of a given string key.
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        let mut inner = self.0.lock().unwrap();
-        inner.get(key)
+    ///
+    /// The key is only borrowed, so looking up a `&str` doesn't allocate.
+    pub fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let mut inner = lock_store(&self.0);
+        inner.get(key.as_ref())
+    }
+
+    /// Gets the value of a key, or `default` if it doesn't exist.
+    pub fn get_or(&self, key: impl AsRef<str>, default: impl Into<String>) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or_else(|| default.into()))
+    }
+
+    /// Gets the value of a key parsed into a `T`, such as a number or a `bool`.
+    ///
+    /// Returns `None` if the key doesn't exist, and `KvsError::Parse` if its
+    /// value doesn't parse.
+    pub fn get_parsed<T>(&self, key: impl AsRef<str>) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let key = key.as_ref();
+        let Some(value) = self.get(key)? else {
+            return Ok(None);
+        };
+        match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(e) => Err(KvsError::Parse(format!("value of {:?}: {}", key, e))),
+        }
+    }
+
+    /// Returns whether a key exists, looking it up in the index without reading
+    /// its value from disk.
+    pub fn contains_key(&self, key: impl AsRef<str>) -> Result<bool> {
+        let inner = lock_store(&self.0);
+        Ok(inner.index.contains_key(key.as_ref()))
+    }
+
+    /// Gets the values of several keys, in the order of `keys`, holding the lock
+    /// only once for all of them.
+    pub fn get_many<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> Result<Vec<Option<String>>> {
+        let mut inner = lock_store(&self.0);
+        keys.into_iter().map(|key| inner.get(key.as_ref())).collect()
     }
 
     /// Remove a given key.
-    pub fn remove(&self, key: String) -> Result<()> {
-        let mut inner = self.0.lock().unwrap();
-        inner.remove(key)
+    pub fn remove(&self, key: impl AsRef<str>) -> Result<()> {
+        self.write(|inner| inner.remove(key.as_ref()))
+    }
+
+    /// Removes a key and returns the value it had.
+    ///
+    /// Unlike `remove`, a key that doesn't exist is not an error: `None` is
+    /// returned and nothing is written.
+    pub fn take(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        self.write(|inner| inner.take(key.as_ref()))
+    }
+
+    /// Moves the value of `from` to `to` under a single lock, so no other
+    /// operation sees both keys or neither. An existing `to` is overwritten, and
+    /// a value stored with `set_bytes` stays raw bytes.
+    ///
+    /// Returns `KvsError::KeyNotFound` if `from` doesn't exist.
+    pub fn rename(&self, from: impl AsRef<str>, to: impl Into<String>) -> Result<()> {
+        let to = to.into();
+        self.write(|inner| inner.rename(from.as_ref(), to))
+    }
+
+    /// Sets the value of a key to arbitrary bytes, such as an encoded protobuf
+    /// message.
+    ///
+    /// The bytes are stored in the log as they are, after a short header.
+    /// Watchers see the value converted to UTF-8 lossily.
+    pub fn set_bytes(&self, key: impl Into<String>, value: &[u8]) -> Result<()> {
+        let key = key.into();
+        self.write(|inner| inner.set_bytes(key, value))
+    }
+
+    /// Gets the value of a key as raw bytes, whether it was stored with `set` or
+    /// `set_bytes`.
+    ///
+    /// Reads of raw bytes bypass the value cache.
+    pub fn get_bytes(&self, key: impl AsRef<str>) -> Result<Option<Vec<u8>>> {
+        let mut inner = lock_store(&self.0);
+        inner.get_bytes(key.as_ref())
+    }
+
+    /// Writes the value of a key to `writer` as it is read from the log, without
+    /// holding the whole value in memory, and returns whether the key exists.
+    ///
+    /// The value is written as stored: UTF-8 for values stored with `set`, raw
+    /// bytes for ones stored with `set_bytes`. Like `get_bytes` this bypasses the
+    /// value cache. The store stays locked until the whole value is written, so
+    /// `writer` should not block for long.
+    pub fn get_to_writer<W: Write>(&self, key: impl AsRef<str>, writer: &mut W) -> Result<bool> {
+        let mut inner = lock_store(&self.0);
+        inner.get_to_writer(key.as_ref(), writer)
+    }
+
+    /// Returns the value of `key`, or stores and returns the value computed by `f`
+    /// if the key doesn't exist.
+    ///
+    /// The lookup and the insert happen under the lock, so concurrent callers for
+    /// the same key agree on a single value and `f` runs at most once. `f` should
+    /// be quick, as it blocks every other operation on the store while it runs.
+    pub fn get_or_insert_with(&self, key: impl Into<String>, f: impl FnOnce() -> String) -> Result<String> {
+        let key = key.into();
+        self.write(|inner| {
+            if let Some(value) = inner.get(&key)? {
+                return Ok(value);
+            }
+            let value = f();
+            inner.set(key, value.clone())?;
+            Ok(value)
+        })
+    }
+
+    /// Runs `f` with the store locked, so that everything it reads and writes
+    /// through the `Txn` happens atomically, e.g. reading a counter and
+    /// writing it back incremented.
+    ///
+    /// Every other operation on the store waits while `f` runs, so `f` must
+    /// not block for long, and it must not use the store other than through
+    /// the `Txn`, which would deadlock.
+    pub fn transaction<R>(&self, f: impl FnOnce(&mut Txn) -> R) -> Result<R> {
+        self.write(|inner| Ok(f(&mut Txn::new(inner))))
+    }
+
+    /// Returns the values of `key` newest first: the current value followed by
+    /// up to `StoreConfig::history_depth - 1` values it was overwritten with.
+    ///
+    /// Returns an empty list if the key doesn't exist. Removing a key discards
+    /// its history.
+    pub fn get_versions(&self, key: impl AsRef<str>) -> Result<Vec<String>> {
+        let mut inner = lock_store(&self.0);
+        inner.get_versions(key.as_ref())
+    }
+
+    /// Returns the value `key` had at log position `before_pos`: the value of
+    /// the last write to it that ends at or before that position, or `None` if
+    /// there is none or it was a removal.
+    ///
+    /// This is meant for debugging. It replays the log from the start rather
+    /// than using the index, so it is slow for large logs, but the store is
+    /// only locked while the log is opened. Positions are offsets into the log
+    /// as it is now, such as `StoreStats::total_log_bytes` at some earlier
+    /// point; a compaction since then rewrites the log and moves them.
+    pub fn get_at(&self, key: impl AsRef<str>, before_pos: u64) -> Result<Option<String>> {
+        let (log, end) = {
+            let mut inner = lock_store(&self.0);
+            inner.writer.flush()?;
+            (File::open(inner.files.log())?, before_pos.min(inner.write_pos))
+        };
+        let key = key.as_ref();
+        let mut records = RecordReader::new(BufReader::new(log.take(end)));
+        let mut value = None;
+        loop {
+            let cmd = match records.next() {
+                Ok(Some(cmd)) => cmd,
+                Ok(None) => break,
+                // A record that straddles `before_pos`.
+                Err(e) if record::is_truncated(&e) => break,
+                Err(e) => return Err(e),
+            };
+            match cmd {
+                Command::Set { key: k, value: v } if k == key => value = Some(v),
+                Command::SetBytes { key: k, value: v } if k == key => value = Some(String::from_utf8(v)?),
+                Command::Remove { key: k } if k == key => value = None,
+                _ => {}
+            }
+        }
+        Ok(value)
+    }
+
+    /// Returns every key starting with `prefix` with its value, sorted by key.
+    ///
+    /// An empty prefix returns the whole store.
+    pub fn scan_prefix(&self, prefix: impl AsRef<str>) -> Result<Vec<(String, String)>> {
+        let mut inner = lock_store(&self.0);
+        inner.scan_prefix(prefix.as_ref())
+    }
+
+    /// Removes every key starting with `prefix` and returns how many were removed.
+    ///
+    /// The keys are removed under a single lock, so no other operation sees the
+    /// store with only some of them gone.
+    pub fn remove_prefix(&self, prefix: impl AsRef<str>) -> Result<usize> {
+        self.write(|inner| inner.remove_prefix(prefix.as_ref()))
+    }
+
+    /// Writes a dump of the current value of every key to `writer`, sorted by
+    /// key, in the format `bulk_load` reads, and returns the number of keys
+    /// written.
+    ///
+    /// The dump is JSON lines: a header with the number of records, a log
+    /// command per key, such as `{"Set":{"key":"key1","value":"value1"}}`, and
+    /// a trailer with a CRC-32 of the records. Other operations on the store
+    /// wait until the export is done.
+    pub fn export<W: Write>(&self, writer: W) -> Result<usize> {
+        let mut inner = lock_store(&self.0);
+        inner.export(writer)
+    }
+
+    /// Loads a dump written by `export` and returns the number of records
+    /// loaded.
+    ///
+    /// Much faster than a `set` per record: the records are appended to the
+    /// log with a single flush and indexed once at the end, and compaction
+    /// waits until the load is done. Watchers are not notified of the loaded
+    /// keys.
+    ///
+    /// Nothing is loaded from a dump that is cut short, which fails with
+    /// `KvsError::TruncatedDump`, or whose records don't match its checksum,
+    /// which fails with `KvsError::ChecksumMismatch`.
+    pub fn bulk_load<R: Read>(&self, reader: R) -> Result<usize> {
+        self.write(|inner| inner.bulk_load(reader))
+    }
+
+    /// Returns a handle to the namespace called `name`, a separate key space
+    /// within this store.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` contains a NUL character.
+    pub fn namespace(&self, name: &str) -> NamespaceHandle {
+        NamespaceHandle::new(self.clone(), name)
+    }
+
+    /// Takes a consistent point-in-time view of the store.
+    ///
+    /// Reads through the snapshot are unaffected by later writes and by
+    /// compaction.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let mut inner = lock_store(&self.0);
+        inner.writer.flush()?;
+        let log = File::open(inner.files.log())?;
+        let index = inner
+            .index
+            .iter()
+            .map(|(key, &cmd_pos)| (key.clone(), cmd_pos))
+            .collect();
+        Ok(Snapshot::new(index, log))
+    }
+
+    /// Returns an iterator over every key and its value, sorted by key.
+    ///
+    /// It iterates over a snapshot taken now, reading each value from the log
+    /// only as the iterator gets to it, so that the values are never all in
+    /// memory at once.
+    pub fn iter(&self) -> Result<KvStoreIter> {
+        Ok(self.snapshot()?.into_iter())
+    }
+
+    /// Subscribes to changes of keys starting with `prefix`.
+    ///
+    /// An event is sent on the returned channel after every successful `set` or
+    /// `remove` of a matching key, in the order the changes were applied. Drop
+    /// the receiver to unsubscribe.
+    pub fn watch_prefix(&self, prefix: impl Into<String>) -> Receiver<Event> {
+        let mut inner = lock_store(&self.0);
+        inner.watchers.add(prefix.into())
+    }
+
+    /// Rewrites the log so that it only contains the current value of each key.
+    ///
+    /// Compaction normally runs automatically once enough stale data has built up.
+    /// The live records are copied without holding the store's lock, so other
+    /// operations only wait for the short final step that swaps the new log in.
+    /// Until then reads are served from the old log, and the new log and index
+    /// replace the old ones together, so a read never sees a key missing or
+    /// pointing into the wrong log. If a compaction is already running, this
+    /// returns immediately.
+    pub fn compact(&self) -> Result<()> {
+        let Some(base) = lock_store(&self.0).begin_compaction()? else {
+            return Ok(());
+        };
+        let _compacting = Compacting(&self.0);
+        self.rewrite(base)
+    }
+
+    /// Copies the live records of `base` into a new log, then has the store
+    /// swap it in.
+    fn rewrite(&self, base: CompactionBase) -> Result<()> {
+        let mut compaction_writer = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(base.files.compact_log())?,
+        );
+        let mut new_index = HashMap::with_capacity(base.records.len());
+        let mut new_history = History::new(base.history_depth);
+        let started = (base.started_at, base.started);
+        let mut log = base.log;
+        let mut buf = Vec::new();
+        let mut pos = 0;
+        for (key, versions) in base.records {
+            // Older versions are written first, so that replaying the new log
+            // rebuilds the same history.
+            let mut current = None;
+            for cmd_pos in versions {
+                buf.resize(cmd_pos.len as usize, 0);
+                log.seek(SeekFrom::Start(cmd_pos.pos))?;
+                log.read_exact(&mut buf)?;
+                compaction_writer.write_all(&buf)?;
+                if let Some(old_cmd) = current.replace(CommandPos { pos, len: cmd_pos.len }) {
+                    new_history.push(&key, old_cmd);
+                }
+                pos += cmd_pos.len;
+            }
+            if let Some(cmd_pos) = current {
+                new_index.insert(key, cmd_pos);
+            }
+        }
+
+        let mut inner = lock_store(&self.0);
+        inner.finish_compaction(compaction_writer, pos, base.log_end, new_index, new_history, started)
+    }
+
+    /// Estimates how much compacting the store now would reclaim, from the
+    /// index alone, without reading or writing the log.
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate> {
+        Ok(lock_store(&self.0).compaction_estimate())
+    }
+
+    /// Returns what the last compaction since the store was opened did, or
+    /// `None` if there hasn't been one.
+    pub fn last_compaction(&self) -> Option<CompactionReport> {
+        lock_store(&self.0).last_compaction
+    }
+
+    /// Runs a write under the lock, then compacts outside of it if the write
+    /// left enough stale data behind.
+    fn write<T>(&self, f: impl FnOnce(&mut KvStoreInner) -> Result<T>) -> Result<T> {
+        let (result, compact) = {
+            let mut inner = lock_store(&self.0);
+            let result = f(&mut inner)?;
+            (result, inner.needs_compaction())
+        };
+        if compact {
+            self.compact()?;
+        }
+        Ok(result)
+    }
+
+    /// Writes any buffered writes to the log file.
+    ///
+    /// Only needed with `FlushMode::Background`; otherwise every write is
+    /// flushed before it returns. Buffered writes are also flushed when the
+    /// last handle to the store is dropped.
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = lock_store(&self.0);
+        Ok(inner.writer.flush()?)
+    }
+
+    /// Returns statistics about the store's log.
+    pub fn stats(&self) -> Result<StoreStats> {
+        let mut inner = lock_store(&self.0);
+        inner.stats()
+    }
+}
+
+impl KvStore {
+    /// Returns the log path, live key count and stale bytes for `Debug` and
+    /// `Display`, or `None` if the store is locked.
+    ///
+    /// Formatting must not wait for the lock, which would deadlock when a store
+    /// is formatted while its lock is held, e.g. inside a transaction.
+    fn summary(&self) -> Option<(PathBuf, usize, u64)> {
+        let inner = match self.0.try_lock() {
+            Ok(inner) => inner,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        Some((inner.files.log(), inner.index.len(), inner.stale_bytes))
+    }
+}
+
+/// Shows the log path, the number of live keys and the stale bytes, or only
+/// `..` if the store is locked at the moment.
+impl fmt::Debug for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("KvStore");
+        match self.summary() {
+            Some((path, live_keys, stale_bytes)) => s
+                .field("path", &path)
+                .field("live_keys", &live_keys)
+                .field("stale_bytes", &stale_bytes)
+                .finish(),
+            None => s.finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Summarizes the store like `Debug`, e.g. `KvStore at /data/wal.log (3 keys,
+/// 120 stale bytes)`.
+impl fmt::Display for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.summary() {
+            Some((path, live_keys, stale_bytes)) => write!(
+                f,
+                "KvStore at {} ({} keys, {} stale bytes)",
+                path.display(),
+                live_keys,
+                stale_bytes
+            ),
+            None => write!(f, "KvStore (locked)"),
+        }
     }
 }
 
@@ -252,13 +1481,206 @@ impl super::KvsEngine for KvStore {
         KvStore::get(self, key)
     }
 
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        KvStore::get_many(self, keys)
+    }
+
     fn remove(&self, key: String) -> Result<()> {
         KvStore::remove(self, key)
     }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        KvStore::contains_key(self, key)
+    }
+
+    /// Returns whether a compaction is running.
+    fn is_busy(&self) -> bool {
+        lock_store(&self.0).compacting
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvStore::flush(self)
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
 }
 
+/// A record of the log, which `record` encodes, and of a dump, which is the
+/// record's serialized form.
 #[derive(Debug, Serialize, Deserialize)]
-enum Command {
+pub(super) enum Command {
     Set { key: String, value: String },
+    /// A binary value, stored raw in the log but as base64 in a dump.
+    SetBytes {
+        key: String,
+        #[serde(with = "super::base64")]
+        value: Vec<u8>,
+    },
     Remove { key: String },
 }
+
+impl Command {
+    fn as_command_ref(&self) -> CommandRef<'_> {
+        match self {
+            Command::Set { key, value } => CommandRef::Set { key, value },
+            Command::SetBytes { key, value } => CommandRef::SetBytes { key, value },
+            Command::Remove { key } => CommandRef::Remove { key },
+        }
+    }
+}
+
+/// A borrowed `Command`, so that commands can be written without cloning the
+/// key that goes into the index.
+pub(super) enum CommandRef<'a> {
+    Set { key: &'a str, value: &'a str },
+    SetBytes { key: &'a str, value: &'a [u8] },
+    Remove { key: &'a str },
+}
+
+/// A snapshot of the index written next to the log after each compaction,
+/// and when the store is closed with `StoreConfig::hint_on_close`.
+///
+/// On open the index is loaded from the hint and only the part of the log
+/// after `log_len` is replayed, instead of the whole log.
+#[derive(Debug, Serialize, Deserialize)]
+struct Hint {
+    /// Length of the log prefix described by this hint.
+    log_len: u64,
+    stale_bytes: u64,
+    /// `(key, pos, len)` of every live key.
+    entries: Vec<(String, u64, u64)>,
+    /// `(key, [(pos, len)])` of the previous versions of every key with a
+    /// history, newest first.
+    #[serde(default)]
+    history: Vec<(String, Vec<(u64, u64)>)>,
+}
+
+impl Hint {
+    fn new(log_len: u64, stale_bytes: u64, index: &HashMap<String, CommandPos>, history: &History) -> Self {
+        let entries = index
+            .iter()
+            .map(|(key, cmd_pos)| (key.clone(), cmd_pos.pos, cmd_pos.len))
+            .collect();
+        let history = history
+            .versions
+            .iter()
+            .map(|(key, old_cmds)| {
+                let old_cmds = old_cmds.iter().map(|cmd_pos| (cmd_pos.pos, cmd_pos.len)).collect();
+                (key.clone(), old_cmds)
+            })
+            .collect();
+        Hint {
+            log_len,
+            stale_bytes,
+            entries,
+            history,
+        }
+    }
+
+    fn index(&self) -> HashMap<String, CommandPos> {
+        self.entries
+            .iter()
+            .map(|(key, pos, len)| (key.clone(), CommandPos { pos: *pos, len: *len }))
+            .collect()
+    }
+
+    /// Returns the history recorded in the hint, trimmed to `depth`. The bytes
+    /// of versions trimmed away are added to `stale_bytes`.
+    fn history(&self, depth: usize, stale_bytes: &mut u64) -> History {
+        let mut history = History::new(depth);
+        for (key, old_cmds) in &self.history {
+            for &(pos, len) in old_cmds.iter().rev() {
+                *stale_bytes += history.push(key, CommandPos { pos, len });
+            }
+        }
+        history
+    }
+
+    /// Loads the store's hint file, returning `None` if it is missing or unreadable.
+    fn load(files: &StoreFiles) -> Result<Option<Hint>> {
+        let file = match File::open(files.hint()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(hint) => Ok(Some(hint)),
+            Err(e) => {
+                warn!("Ignoring unreadable hint file: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Writes the hint to a temporary file next to the store's hint file and
+    /// returns its path.
+    fn write(&self, files: &StoreFiles) -> Result<PathBuf> {
+        let path = files.compact_hint();
+        let mut writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(path)
+    }
+
+    fn remove(files: &StoreFiles) -> Result<()> {
+        match std::fs::remove_file(files.hint()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The previous versions kept of each key, newest first.
+struct History {
+    /// Number of versions kept per key, including the current one.
+    depth: usize,
+    versions: HashMap<String, VecDeque<CommandPos>>,
+}
+
+impl History {
+    fn new(depth: usize) -> Self {
+        History {
+            depth,
+            versions: HashMap::new(),
+        }
+    }
+
+    /// Records `old_cmd` as the latest previous version of `key`, and returns
+    /// the length of the version that no longer fits, which is now stale.
+    fn push(&mut self, key: &str, old_cmd: CommandPos) -> u64 {
+        if self.depth <= 1 {
+            return old_cmd.len;
+        }
+        let old_cmds = match self.versions.get_mut(key) {
+            Some(old_cmds) => old_cmds,
+            None => self.versions.entry(key.to_owned()).or_default(),
+        };
+        old_cmds.push_front(old_cmd);
+        if old_cmds.len() < self.depth {
+            return 0;
+        }
+        old_cmds.pop_back().map_or(0, |cmd_pos| cmd_pos.len)
+    }
+
+    /// Forgets the previous versions of `key`, and returns their total
+    /// length, which is now stale.
+    fn remove(&mut self, key: &str) -> u64 {
+        self.versions
+            .remove(key)
+            .map_or(0, |old_cmds| old_cmds.iter().map(|cmd_pos| cmd_pos.len).sum())
+    }
+
+    /// Returns the total length of the previous versions kept of all keys.
+    fn bytes(&self) -> u64 {
+        self.versions.values().flatten().map(|cmd_pos| cmd_pos.len).sum()
+    }
+
+    /// Returns the previous versions of `key`, newest first.
+    fn get(&self, key: &str) -> Vec<CommandPos> {
+        self.versions
+            .get(key)
+            .map_or_else(Vec::new, |old_cmds| old_cmds.iter().copied().collect())
+    }
+}
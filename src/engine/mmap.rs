@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+/// A read-only memory map of a whole log file.
+///
+/// The map covers the file as it was when mapped; bytes appended later are not
+/// visible until the file is mapped again.
+pub(crate) struct LogMap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and never aliased mutably, so it can be shared freely.
+unsafe impl Send for LogMap {}
+unsafe impl Sync for LogMap {}
+
+impl LogMap {
+    /// Maps the current contents of `file`. Returns `None` for an empty file,
+    /// which cannot be mapped.
+    pub(crate) fn map(file: &File) -> io::Result<Option<LogMap>> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        // SAFETY: we request a fresh shared read-only mapping of `len` bytes of an
+        // open file descriptor and check for failure before using the pointer.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(LogMap { ptr, len }))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` points to a live mapping of exactly `len` readable bytes.
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for LogMap {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe a mapping created by `map` that is
+        // unmapped exactly once.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
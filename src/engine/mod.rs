@@ -1,12 +1,42 @@
-use crate::Result;
+use crate::{KvsError, Result};
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
 
+mod any;
+pub use any::AnyEngine;
+mod base64;
+mod cache;
+mod config;
+mod dump;
+pub(crate) use config::SizeLimits;
+pub use config::{CompactionPolicy, FlushMode, StoreConfig};
 mod kvs;
-pub use kvs::KvStore;
+pub use kvs::{CompactionEstimate, CompactionReport, KvStore, StoreStats};
+#[cfg(feature = "mmap")]
+mod mmap;
+mod namespace;
+pub use namespace::NamespaceHandle;
+mod record;
+#[cfg(feature = "sled")]
 mod sled;
+#[cfg(feature = "sled")]
 pub use sled::SledKvsEngine;
+mod sharded;
+pub use sharded::ShardedKvStore;
+mod snapshot;
+pub use snapshot::{KvStoreIter, Snapshot};
+mod stream;
+mod txn;
+pub use txn::Txn;
+mod watch;
+pub use watch::Event;
+
+/// The key `KvsEngine::check` reads by default.
+const HEALTH_CHECK_KEY: &str = "__kvs_health_check";
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine: Clone + Send + 'static {
@@ -20,17 +50,64 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Returns `None` if the given key does not exist.
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Gets the values of several keys, in the order of `keys`.
+    ///
+    /// The default implementation calls `get` for each key; engines that lock
+    /// on every read override it to take the lock once.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns whether the given key exists.
+    ///
+    /// The default implementation reads the value with `get`; engines that can
+    /// tell without reading it override it.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Makes sure that every write so far has reached the disk.
+    ///
+    /// Engines that write through on every operation have nothing to do, which
+    /// is the default.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Checks that the engine can serve requests, for `Request::Health`.
+    ///
+    /// The default implementation reads a key that is never written.
+    fn check(&self) -> Result<()> {
+        self.get(HEALTH_CHECK_KEY.to_owned()).map(drop)
+    }
+
+    /// Returns whether the engine is busy with maintenance, such as compacting
+    /// its log, and would rather not be sent traffic until it is done.
+    ///
+    /// The default is never.
+    fn is_busy(&self) -> bool {
+        false
+    }
+
+    /// Returns the name the server reports for the engine, e.g. `kvs`.
+    ///
+    /// The default is the name of the implementing type.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 #[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Engine {
     Kvs,
+    #[cfg(feature = "sled")]
     Sled,
 }
 
@@ -38,7 +115,51 @@ impl fmt::Display for Engine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Engine::Kvs => write!(f, "kvs"),
+            #[cfg(feature = "sled")]
             Engine::Sled => write!(f, "sled"),
         }
     }
 }
+
+/// The file in a data directory that records the engine its data belongs to.
+const ENGINE_FILE: &str = ".engine";
+
+/// Returns the engine recorded in the data directory `dir` by
+/// `persist_engine`, or `None` if there is none.
+///
+/// The engine is recorded by name, e.g. `kvs`. The JSON form written by older
+/// versions, e.g. `"Kvs"`, is read too.
+pub fn detect_engine(dir: impl AsRef<Path>) -> Result<Option<Engine>> {
+    let path = dir.as_ref().join(ENGINE_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let contents = contents.trim();
+    let engine = if contents.starts_with('"') {
+        serde_json::from_str(contents).ok()
+    } else {
+        Engine::from_str(contents, true).ok()
+    };
+    match engine {
+        Some(engine) => Ok(Some(engine)),
+        None => Err(KvsError::StringError(format!(
+            "{}: unknown engine {}",
+            path.display(),
+            contents
+        ))),
+    }
+}
+
+/// Records in the data directory `dir` that its data belongs to `engine`.
+///
+/// Fails with `KvsError::EngineMismatch` if `dir` already holds data of
+/// another engine.
+pub fn persist_engine(dir: impl AsRef<Path>, engine: Engine) -> Result<()> {
+    let dir = dir.as_ref();
+    match detect_engine(dir)? {
+        Some(recorded) if recorded != engine => Err(KvsError::EngineMismatch),
+        _ => Ok(fs::write(dir.join(ENGINE_FILE), engine.to_string())?),
+    }
+}
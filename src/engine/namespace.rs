@@ -0,0 +1,59 @@
+use super::KvStore;
+use crate::Result;
+
+/// A separate key space inside a `KvStore`.
+///
+/// Keys are stored as `<name>\0<key>`, so the same key can hold different values
+/// in different namespaces, and keys of the unnamespaced store never collide with
+/// namespaced ones unless they contain a NUL byte themselves.
+#[derive(Clone)]
+pub struct NamespaceHandle {
+    store: KvStore,
+    prefix: String,
+}
+
+impl NamespaceHandle {
+    pub(super) fn new(store: KvStore, name: &str) -> Self {
+        assert!(!name.contains('\0'), "namespace names must not contain NUL");
+        NamespaceHandle {
+            store,
+            prefix: format!("{}\0", name),
+        }
+    }
+
+    /// Returns the name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.prefix[..self.prefix.len() - 1]
+    }
+
+    /// Sets the value of a key in this namespace.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(self.key(&key), value)
+    }
+
+    /// Gets the value of a key in this namespace.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(self.key(&key))
+    }
+
+    /// Removes a key from this namespace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key is not in this namespace.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(self.key(&key))
+    }
+
+    /// Removes every key in this namespace and returns how many there were.
+    pub fn clear(&self) -> Result<usize> {
+        self.store.remove_prefix(&self.prefix)
+    }
+
+    fn key(&self, key: &str) -> String {
+        let mut full_key = String::with_capacity(self.prefix.len() + key.len());
+        full_key.push_str(&self.prefix);
+        full_key.push_str(key);
+        full_key
+    }
+}
@@ -0,0 +1,150 @@
+//! The encoding of the records of a `KvStore` log.
+//!
+//! `Set` and `Remove` records are JSON objects. A `SetBytes` record is a JSON
+//! header with the key and the length of the value, followed by the raw bytes
+//! of the value and a newline, so that a binary value takes no more space in
+//! the log than it is long. The newline keeps every record ending in a
+//! non-zero byte, which `log_end` relies on.
+
+use super::kvs::{Command, CommandRef};
+use crate::error::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Read, Write};
+
+/// The JSON part of a record.
+#[derive(Serialize)]
+enum HeaderRef<'a> {
+    Set { key: &'a str, value: &'a str },
+    SetBytes { key: &'a str, len: u64 },
+    Remove { key: &'a str },
+}
+
+#[derive(Deserialize)]
+enum Header {
+    Set { key: String, value: String },
+    /// `len` is missing from a binary value stored as base64, which no
+    /// released version wrote.
+    SetBytes { key: String, len: Option<u64> },
+    Remove { key: String },
+}
+
+/// Writes the record of `cmd` to `writer`.
+pub(super) fn encode<W: Write>(cmd: &CommandRef, mut writer: W) -> Result<()> {
+    match *cmd {
+        CommandRef::Set { key, value } => serde_json::to_writer(&mut writer, &HeaderRef::Set { key, value })?,
+        CommandRef::SetBytes { key, value } => {
+            let len = value.len() as u64;
+            serde_json::to_writer(&mut writer, &HeaderRef::SetBytes { key, len })?;
+            writer.write_all(value)?;
+            writer.write_all(b"\n")?;
+        }
+        CommandRef::Remove { key } => serde_json::to_writer(&mut writer, &HeaderRef::Remove { key })?,
+    }
+    Ok(())
+}
+
+/// Decodes the record that `buf` holds.
+pub(super) fn decode(buf: &[u8]) -> Result<Command> {
+    RecordReader::new(buf)
+        .next()?
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+/// Returns whether `err` means that the log ended in the middle of a record.
+pub(super) fn is_truncated(err: &KvsError) -> bool {
+    match err {
+        KvsError::Serde(e) => e.is_eof(),
+        KvsError::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+/// Reads the records of a log one after another.
+pub(super) struct RecordReader<R> {
+    reader: R,
+    /// Number of bytes read, which is the end of the last record returned.
+    pos: u64,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    pub(super) fn new(reader: R) -> Self {
+        RecordReader { reader, pos: 0 }
+    }
+
+    /// Returns the number of bytes read so far.
+    pub(super) fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Returns the next record, or `None` at the end of the log.
+    pub(super) fn next(&mut self) -> Result<Option<Command>> {
+        if !self.skip_whitespace()? {
+            return Ok(None);
+        }
+        let header = {
+            let mut counted = Counted {
+                reader: &mut self.reader,
+                count: &mut self.pos,
+            };
+            Header::deserialize(&mut serde_json::Deserializer::from_reader(&mut counted))?
+        };
+        let cmd = match header {
+            Header::Set { key, value } => Command::Set { key, value },
+            Header::SetBytes { len: None, .. } => {
+                let message = "binary value in an unsupported encoding";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+            }
+            Header::SetBytes { key, len: Some(len) } => {
+                // A corrupt length must not make us allocate it up front.
+                let mut value = Vec::new();
+                (&mut self.reader).take(len).read_to_end(&mut value)?;
+                if value.len() as u64 != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                let mut newline = [0];
+                self.reader.read_exact(&mut newline)?;
+                if newline != *b"\n" {
+                    let message = "binary value not followed by a newline";
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+                }
+                self.pos += len + 1;
+                Command::SetBytes { key, value }
+            }
+            Header::Remove { key } => Command::Remove { key },
+        };
+        Ok(Some(cmd))
+    }
+
+    /// Skips the whitespace before the next record, returning whether there is
+    /// one.
+    fn skip_whitespace(&mut self) -> Result<bool> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            let skipped = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            let found = skipped < buf.len();
+            self.reader.consume(skipped);
+            self.pos += skipped as u64;
+            if found {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Counts the bytes read through it, since the JSON deserializer doesn't tell
+/// where a value ended.
+struct Counted<'a, R> {
+    reader: &'a mut R,
+    count: &'a mut u64,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        *self.count += n as u64;
+        Ok(n)
+    }
+}
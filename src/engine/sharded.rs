@@ -0,0 +1,161 @@
+use super::kvs::DEFAULT_NAME;
+use super::{KvStore, KvsEngine, StoreConfig};
+use crate::{KvsError, Result};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A store split into several `KvStore`s by the hash of the key, so that writes
+/// to keys in different shards don't wait for each other.
+///
+/// Shard `i` of a store named `<name>` is a `KvStore` named `<name>-<i>` in the
+/// same directory, and the number of shards is recorded in `<name>.shards`.
+/// Keys are routed with CRC32, so a key lands in the same shard on every open
+/// as long as the number of shards doesn't change; opening a store with a
+/// different number of shards than it was created with is an error.
+///
+/// Operations on a single key behave like those of `KvStore`. There is no
+/// ordering between writes to different shards.
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Arc<[KvStore]>,
+}
+
+impl ShardedKvStore {
+    /// Opens a `ShardedKvStore` with the given path and number of shards.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        ShardedKvStore::open_with_config(path, shards, StoreConfig::default())
+    }
+
+    /// Opens a `ShardedKvStore` with the given path, number of shards and
+    /// options, which apply to every shard.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if `shards` is zero, or if the store
+    /// was created with a different number of shards.
+    pub fn open_with_config(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        config: StoreConfig,
+    ) -> Result<ShardedKvStore> {
+        if shards == 0 {
+            return Err(KvsError::StringError("a store needs at least one shard".to_owned()));
+        }
+        let dir = path.into();
+        let name = config.name.clone().unwrap_or_else(|| DEFAULT_NAME.to_owned());
+        let shards_path = dir.join(format!("{}.shards", name));
+        match fs::read_to_string(&shards_path) {
+            Ok(recorded) => {
+                if recorded.trim().parse::<usize>().ok() != Some(shards) {
+                    return Err(KvsError::StringError(format!(
+                        "{}: the store has {} shards, not {}",
+                        shards_path.display(),
+                        recorded.trim(),
+                        shards
+                    )));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !config.read_only => {
+                fs::create_dir_all(&dir)?;
+                fs::write(&shards_path, shards.to_string())?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let shards = (0..shards)
+            .map(|i| {
+                let config = StoreConfig {
+                    name: Some(format!("{}-{}", name, i)),
+                    ..config.clone()
+                };
+                KvStore::open_with_config(&dir, config)
+            })
+            .collect::<Result<_>>()?;
+        Ok(ShardedKvStore { shards })
+    }
+
+    /// Returns the number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the index of the shard that holds `key`.
+    pub fn shard_for(&self, key: &str) -> usize {
+        crc32fast::hash(key.as_bytes()) as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[self.shard_for(key)]
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        let key = key.into();
+        self.shard(&key).set(key, value)
+    }
+
+    /// Gets the string value of a given string key.
+    pub fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let key = key.as_ref();
+        self.shard(key).get(key)
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&self, key: impl AsRef<str>) -> Result<()> {
+        let key = key.as_ref();
+        self.shard(key).remove(key)
+    }
+
+    /// Returns whether the given key exists.
+    pub fn contains_key(&self, key: impl AsRef<str>) -> Result<bool> {
+        let key = key.as_ref();
+        self.shard(key).contains_key(key)
+    }
+
+    /// Compacts the log of every shard, one after another.
+    pub fn compact(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::compact)
+    }
+
+    /// Flushes the writes of every shard to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::flush)
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        ShardedKvStore::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        ShardedKvStore::get(self, key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        ShardedKvStore::remove(self, key)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        ShardedKvStore::contains_key(self, key)
+    }
+
+    /// Returns whether any shard is compacting.
+    fn is_busy(&self) -> bool {
+        self.shards.iter().any(KvsEngine::is_busy)
+    }
+
+    fn flush(&self) -> Result<()> {
+        ShardedKvStore::flush(self)
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs-sharded"
+    }
+}
@@ -1,30 +1,174 @@
+use super::{Event, FlushMode, StoreConfig};
 use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
+use crossbeam_channel::{Receiver, unbounded};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Batch, Db};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 /// A key-value store using the `sled` storage engine.
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    flush_mode: FlushMode,
+    /// Serializes `get_or_insert_with` so that its closure runs at most once per key.
+    insert_lock: Arc<Mutex<()>>,
+}
 
 impl SledKvsEngine {
     /// Opens a `SledKvsEngine` with the given path.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let db = sled::open(path.into())?;
-        Ok(SledKvsEngine(db))
+        SledKvsEngine::open_with_config(path, StoreConfig::default())
+    }
+
+    /// Opens a `SledKvsEngine` with the given path and configuration.
+    pub fn open_with_config(path: impl Into<PathBuf>, config: StoreConfig) -> Result<Self> {
+        let mut path = path.into();
+        if let Some(name) = &config.name {
+            path.push(name);
+        }
+        let mut sled_config = sled::Config::new().path(path);
+        if let Some(ms) = config.flush_every_ms {
+            sled_config = sled_config.flush_every_ms(Some(ms));
+        }
+        if let Some(bytes) = config.sled_cache_capacity {
+            sled_config = sled_config.cache_capacity(bytes);
+        }
+        Ok(SledKvsEngine {
+            db: sled_config.open()?,
+            flush_mode: config.flush_mode,
+            insert_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Flushes all pending writes to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Sets the value of a key to arbitrary bytes, like `KvStore::set_bytes`.
+    /// sled stores them as they are.
+    pub fn set_bytes(&self, key: String, value: &[u8]) -> Result<()> {
+        self.db.insert(key, value)?;
+        self.flush_write()
+    }
+
+    /// Gets the value of a key as raw bytes.
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    /// Removes a key and returns the value it had, or `None` if it doesn't
+    /// exist, like `KvStore::take`.
+    pub fn take(&self, key: String) -> Result<Option<String>> {
+        let Some(ivec) = self.db.remove(key)? else {
+            return Ok(None);
+        };
+        self.flush_write()?;
+        Ok(Some(String::from_utf8(ivec.to_vec())?))
+    }
+
+    /// Moves the value of `from` to `to`, like `KvStore::rename`.
+    ///
+    /// The read and the batch that inserts `to` and removes `from` run in one
+    /// sled transaction, so a concurrent write to `from` can't be lost.
+    pub fn rename(&self, from: String, to: String) -> Result<()> {
+        if from == to {
+            return match self.db.contains_key(&from)? {
+                true => Ok(()),
+                false => Err(KvsError::KeyNotFound),
+            };
+        }
+        self.db
+            .transaction(|tx| {
+                let value = tx
+                    .get(&from)?
+                    .ok_or(ConflictableTransactionError::Abort(KvsError::KeyNotFound))?;
+                let mut batch = Batch::default();
+                batch.insert(to.as_bytes(), value);
+                batch.remove(from.as_bytes());
+                tx.apply_batch(&batch)?;
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })?;
+        self.flush_write()
+    }
+
+    /// Returns the value of `key`, or stores and returns the value computed by `f`
+    /// if the key doesn't exist, like `KvStore::get_or_insert_with`.
+    ///
+    /// Concurrent callers are serialized, and the value is inserted with a
+    /// compare-and-swap so that a concurrent `set` is never overwritten.
+    pub fn get_or_insert_with(&self, key: String, f: impl FnOnce() -> String) -> Result<String> {
+        let _guard = self.insert_lock.lock().unwrap();
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = f();
+        match self.db.compare_and_swap(&key, None as Option<&[u8]>, Some(value.as_bytes()))? {
+            Ok(()) => {
+                self.flush_write()?;
+                Ok(value)
+            }
+            Err(e) => {
+                let current = e.current.map(|ivec| String::from_utf8(ivec.to_vec())).transpose()?;
+                Ok(current.unwrap_or(value))
+            }
+        }
+    }
+
+    /// Subscribes to changes of keys starting with `prefix`, like
+    /// `KvStore::watch_prefix`.
+    ///
+    /// Events from sled's own subscriber are forwarded by a background thread,
+    /// which exits on the first event after the receiver is dropped.
+    pub fn watch_prefix(&self, prefix: String) -> Result<Receiver<Event>> {
+        let subscriber = self.db.watch_prefix(prefix);
+        let (sender, receiver) = unbounded();
+        thread::Builder::new()
+            .name("kvs-sled-watch".to_owned())
+            .spawn(move || {
+                for event in subscriber {
+                    let event = match event {
+                        sled::Event::Insert { key, value } => Event::Set {
+                            key: String::from_utf8_lossy(&key).into_owned(),
+                            value: String::from_utf8_lossy(&value).into_owned(),
+                        },
+                        sled::Event::Remove { key } => Event::Remove {
+                            key: String::from_utf8_lossy(&key).into_owned(),
+                        },
+                    };
+                    if sender.send(event).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(receiver)
+    }
+
+    fn flush_write(&self) -> Result<()> {
+        match self.flush_mode {
+            FlushMode::EveryWrite => self.flush(),
+            FlushMode::Background => Ok(()),
+        }
     }
 }
 
 impl KvsEngine for SledKvsEngine {
     /// Sets the value of a string key to a string.
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.0.insert(key, value.as_bytes())?;
-        self.0.flush()?;
-        Ok(())
+        self.db.insert(key, value.as_bytes())?;
+        self.flush_write()
     }
 
     /// Gets the string value of a given string key.
     fn get(&self, key: String) -> Result<Option<String>> {
-        let value = self.0
+        let value = self.db
             .get(key)?
             .map(|ivec| String::from_utf8(ivec.to_vec())).transpose()?;
         Ok(value)
@@ -32,8 +176,21 @@ impl KvsEngine for SledKvsEngine {
 
     /// Removes a given key.
     fn remove(&self, key: String) -> Result<()> {
-        self.0.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        self.0.flush()?;
-        Ok(())
+        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.flush_write()
+    }
+
+    /// Returns whether a given key exists.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    /// Flushes all pending writes to disk.
+    fn flush(&self) -> Result<()> {
+        SledKvsEngine::flush(self)
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
     }
 }
@@ -0,0 +1,105 @@
+use super::kvs::{Command, CommandPos};
+use super::record;
+use crate::{KvsError, Result};
+use std::collections::{BTreeMap, btree_map};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+/// A point-in-time, read-only view of a `KvStore`.
+///
+/// The snapshot keeps its own handle to the log it was taken from. Compaction
+/// replaces the log by renaming a new file over it, so the records the snapshot
+/// refers to stay readable through that handle for as long as the snapshot lives,
+/// and later writes to the store are never visible through it.
+pub struct Snapshot {
+    index: BTreeMap<String, CommandPos>,
+    log: Mutex<File>,
+}
+
+impl Snapshot {
+    pub(super) fn new(index: BTreeMap<String, CommandPos>, log: File) -> Self {
+        Snapshot {
+            index,
+            log: Mutex::new(log),
+        }
+    }
+
+    /// Gets the value the key had when the snapshot was taken.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.index.get(key) {
+            Some(&cmd_pos) => self.read_value(cmd_pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns whether the snapshot holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Iterates over every key and its value, sorted by key.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.index
+            .iter()
+            .map(|(key, &cmd_pos)| Ok((key.clone(), self.read_value(cmd_pos)?)))
+    }
+
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<String> {
+        read_value(&mut self.log.lock().unwrap(), cmd_pos)
+    }
+}
+
+impl IntoIterator for Snapshot {
+    type Item = Result<(String, String)>;
+    type IntoIter = KvStoreIter;
+
+    /// Iterates over every key and its value, sorted by key, without keeping
+    /// more than one value in memory at a time.
+    fn into_iter(self) -> KvStoreIter {
+        KvStoreIter {
+            index: self.index.into_iter(),
+            log: self.log.into_inner().unwrap(),
+        }
+    }
+}
+
+/// An iterator over the keys and values of a `KvStore` as of when it was
+/// created, returned by `KvStore::iter`.
+///
+/// It owns a copy of the index and its own handle to the log, and reads each
+/// value as it gets to it. Like a `Snapshot`, it doesn't see writes made after
+/// it was created.
+pub struct KvStoreIter {
+    index: btree_map::IntoIter<String, CommandPos>,
+    log: File,
+}
+
+impl Iterator for KvStoreIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, cmd_pos) = self.index.next()?;
+        Some(read_value(&mut self.log, cmd_pos).map(|value| (key, value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.index.size_hint()
+    }
+}
+
+fn read_value(log: &mut File, cmd_pos: CommandPos) -> Result<String> {
+    let mut buf = vec![0; cmd_pos.len as usize];
+    log.seek(SeekFrom::Start(cmd_pos.pos))?;
+    log.read_exact(&mut buf)?;
+    match record::decode(&buf)? {
+        Command::Set { value, .. } => Ok(value),
+        Command::SetBytes { value, .. } => Ok(String::from_utf8(value)?),
+        Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+    }
+}
@@ -0,0 +1,162 @@
+//! Copies a value out of a record in the log without deserializing the
+//! record, so that a large value never has to be held in memory.
+
+use crate::error::{KvsError, Result};
+use std::io::{self, BufRead, Write};
+
+/// Copies the value of the `Set` or `SetBytes` record read from `reader` to
+/// `writer`, unescaping the string of a `Set` on the way.
+///
+/// Returns `KvsError::UnexpectedCommandType` for a `Remove` record.
+pub(super) fn copy_value<R: BufRead, W: Write>(mut reader: R, writer: &mut W) -> Result<()> {
+    expect(&mut reader, b'{')?;
+    let variant = read_string(&mut reader)?;
+    expect(&mut reader, b':')?;
+    expect(&mut reader, b'{')?;
+    let mut len = None;
+    loop {
+        let field = read_string(&mut reader)?;
+        expect(&mut reader, b':')?;
+        match (variant.as_str(), field.as_str()) {
+            ("Set", "value") => return copy_string(&mut reader, writer),
+            ("SetBytes", "len") => len = Some(read_u64(&mut reader)?),
+            ("SetBytes", "value") => return Err(malformed("binary value in an unsupported encoding")),
+            _ => copy_string(&mut reader, &mut io::sink())?,
+        }
+        match next_token(&mut reader)? {
+            b',' => {}
+            b'}' => break,
+            b => return Err(malformed(format!("unexpected {:?}", b as char))),
+        }
+    }
+    let Some(len) = len else {
+        return Err(KvsError::UnexpectedCommandType);
+    };
+    // The raw bytes of a binary value follow the header.
+    expect(&mut reader, b'}')?;
+    if io::copy(&mut reader.take(len), writer)? != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Reads a short string, such as a variant or field name.
+fn read_string<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut buf = Vec::new();
+    copy_string(reader, &mut buf)?;
+    String::from_utf8(buf).map_err(|_| malformed("string is not valid UTF-8"))
+}
+
+/// Copies the JSON string at the start of `reader` to `writer` unescaped.
+fn copy_string<R: BufRead, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> Result<()> {
+    expect(reader, b'"')?;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let Some(i) = buf.iter().position(|&b| b == b'"' || b == b'\\') else {
+            let len = buf.len();
+            writer.write_all(buf)?;
+            reader.consume(len);
+            continue;
+        };
+        let end = buf[i] == b'"';
+        writer.write_all(&buf[..i])?;
+        reader.consume(i + 1);
+        if end {
+            return Ok(());
+        }
+        let c = unescape(reader)?;
+        writer.write_all(c.encode_utf8(&mut [0; 4]).as_bytes())?;
+    }
+}
+
+/// Reads the rest of an escape sequence after its backslash.
+fn unescape<R: BufRead>(reader: &mut R) -> Result<char> {
+    let c = match next_byte(reader)? {
+        b'"' => '"',
+        b'\\' => '\\',
+        b'/' => '/',
+        b'b' => '\u{8}',
+        b'f' => '\u{c}',
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'u' => {
+            let unit = read_hex(reader)?;
+            let units = if (0xd800..0xdc00).contains(&unit) {
+                // A surrogate pair.
+                let mut prefix = [0; 2];
+                reader.read_exact(&mut prefix)?;
+                if &prefix != b"\\u" {
+                    return Err(malformed("unpaired surrogate in \\u escape"));
+                }
+                vec![unit, read_hex(reader)?]
+            } else {
+                vec![unit]
+            };
+            char::decode_utf16(units)
+                .next()
+                .and_then(|c| c.ok())
+                .ok_or_else(|| malformed("invalid \\u escape"))?
+        }
+        b => return Err(malformed(format!("invalid escape '\\{}'", b as char))),
+    };
+    Ok(c)
+}
+
+/// Reads the four hex digits of a `\u` escape.
+fn read_hex<R: BufRead>(reader: &mut R) -> Result<u16> {
+    let mut digits = [0; 4];
+    reader.read_exact(&mut digits)?;
+    std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u16::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| malformed("invalid \\u escape"))
+}
+
+/// Reads an unsigned integer, such as the length of a binary value.
+fn read_u64<R: BufRead>(reader: &mut R) -> Result<u64> {
+    let mut n: Option<u64> = None;
+    loop {
+        let digit = match reader.fill_buf()?.first() {
+            Some(&b) if b.is_ascii_digit() => u64::from(b - b'0'),
+            _ => return n.ok_or_else(|| malformed("expected a number")),
+        };
+        n = Some(
+            n.unwrap_or(0)
+                .checked_mul(10)
+                .and_then(|n| n.checked_add(digit))
+                .ok_or_else(|| malformed("number out of range"))?,
+        );
+        reader.consume(1);
+    }
+}
+
+fn expect<R: BufRead>(reader: &mut R, expected: u8) -> Result<()> {
+    match next_token(reader)? {
+        b if b == expected => Ok(()),
+        b => Err(malformed(format!("expected {:?}, got {:?}", expected as char, b as char))),
+    }
+}
+
+/// Reads the next byte that isn't whitespace.
+fn next_token<R: BufRead>(reader: &mut R) -> Result<u8> {
+    loop {
+        match next_byte(reader)? {
+            b' ' | b'\n' | b'\r' | b'\t' => {}
+            b => return Ok(b),
+        }
+    }
+}
+
+fn next_byte<R: BufRead>(reader: &mut R) -> Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn malformed(message: impl Into<String>) -> KvsError {
+    io::Error::new(io::ErrorKind::InvalidData, message.into()).into()
+}
@@ -0,0 +1,38 @@
+use super::kvs::KvStoreInner;
+use crate::Result;
+
+/// Access to a `KvStore` inside `KvStore::transaction`, with the store's lock
+/// held for the whole transaction.
+///
+/// Every operation is written to the log as soon as it is made. The
+/// transaction is atomic with respect to other users of the store, which never
+/// see it half done, but not with respect to crashes.
+pub struct Txn<'a> {
+    inner: &'a mut KvStoreInner,
+}
+
+impl<'a> Txn<'a> {
+    pub(super) fn new(inner: &'a mut KvStoreInner) -> Self {
+        Txn { inner }
+    }
+
+    /// Gets the value of a key, including changes made earlier in the
+    /// transaction.
+    pub fn get(&mut self, key: impl AsRef<str>) -> Result<Option<String>> {
+        self.inner.get(key.as_ref())
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        self.inner.set(key.into(), value.into())
+    }
+
+    /// Removes a key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key does not exist.
+    pub fn remove(&mut self, key: impl AsRef<str>) -> Result<()> {
+        self.inner.remove(key.as_ref())
+    }
+}
@@ -0,0 +1,46 @@
+use crossbeam_channel::{Receiver, Sender, unbounded};
+
+/// A change to a watched key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The key was set to `value`.
+    Set { key: String, value: String },
+    /// The key was removed.
+    Remove { key: String },
+}
+
+impl Event {
+    /// Returns the key the event is about.
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key } => key,
+        }
+    }
+}
+
+/// The subscribers of a store, each interested in keys with a given prefix.
+#[derive(Default)]
+pub(crate) struct Watchers(Vec<(String, Sender<Event>)>);
+
+impl Watchers {
+    /// Registers a subscriber for keys starting with `prefix`.
+    pub(crate) fn add(&mut self, prefix: String) -> Receiver<Event> {
+        let (sender, receiver) = unbounded();
+        self.0.push((prefix, sender));
+        receiver
+    }
+
+    /// Returns whether any subscriber is interested in `key`, so that callers
+    /// only build events that will be sent.
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        self.0.iter().any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+
+    /// Sends the event to every subscriber whose prefix matches, dropping the
+    /// subscribers whose receiver has gone away.
+    pub(crate) fn notify(&mut self, event: Event) {
+        self.0.retain(|(prefix, sender)| {
+            !event.key().starts_with(prefix.as_str()) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io;
 use std::string::FromUtf8Error;
 use thiserror::Error;
@@ -8,6 +10,7 @@ pub enum KvsError {
     Io(#[from] io::Error),
     #[error("Serialization error: {0}")]
     Serde(#[from] serde_json::Error),
+    #[cfg(feature = "sled")]
     #[error("Sled error: {0}")]
     Sled(#[from] sled::Error),
     #[error("UTF-8 conversion error: {0}")]
@@ -18,8 +21,130 @@ pub enum KvsError {
     UnexpectedCommandType,
     #[error("Engine mismatch")]
     EngineMismatch,
+    #[error("Operation timed out")]
+    Timeout,
+    /// The connection to the server could not be established or broke.
+    #[error("Connection error: {0}")]
+    Connection(String),
+    /// The data directory was written in a format this version can't read.
+    #[error("Unsupported store format version {found}, expected {expected}")]
+    UnsupportedFormat { found: u32, expected: u32 },
+    /// Another open store, in this process or another one, is writing to the
+    /// same files.
+    #[error("Store is locked by another writer")]
+    AlreadyLocked,
+    /// The store was opened read-only.
+    #[error("Store is read-only")]
+    ReadOnly,
+    /// The key of a write is longer than the configured maximum.
+    #[error("Key is {len} bytes long, the maximum is {max}")]
+    KeyTooLong { len: usize, max: usize },
+    /// The value of a write is longer than the configured maximum.
+    #[error("Value is {len} bytes long, the maximum is {max}")]
+    ValueTooLong { len: usize, max: usize },
+    /// A dump ends before its last record or its checksum.
+    #[error("Dump is truncated")]
+    TruncatedDump,
+    /// The records of a dump don't match its checksum.
+    #[error("Dump checksum mismatch")]
+    ChecksumMismatch,
+    /// A stored value could not be parsed into the requested type.
+    #[error("Parse error: {0}")]
+    Parse(String),
     #[error("{0}")]
     StringError(String),
 }
 
+impl KvsError {
+    /// Returns the stable code of this kind of error.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::from(self)
+    }
+}
+
+/// The kind of a `KvsError`, without its details.
+///
+/// Unlike the error itself it can be copied, compared and serialized, which
+/// makes it suitable for sending over the network and for counting errors.
+/// Codes serialize as `snake_case` strings, e.g. `"key_not_found"`, and never
+/// change once added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Io,
+    Serialization,
+    /// An error from sled. Exists even without the `sled` feature so that the
+    /// set of codes doesn't depend on how the crate was built.
+    Sled,
+    Utf8,
+    KeyNotFound,
+    UnexpectedCommandType,
+    EngineMismatch,
+    Timeout,
+    Connection,
+    UnsupportedFormat,
+    AlreadyLocked,
+    ReadOnly,
+    KeyTooLong,
+    ValueTooLong,
+    TruncatedDump,
+    ChecksumMismatch,
+    Parse,
+    /// Any other error, such as one reported by a server as a message.
+    Other,
+}
+
+impl From<&KvsError> for ErrorCode {
+    fn from(err: &KvsError) -> Self {
+        match err {
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Serde(_) => ErrorCode::Serialization,
+            #[cfg(feature = "sled")]
+            KvsError::Sled(_) => ErrorCode::Sled,
+            KvsError::Utf8(_) => ErrorCode::Utf8,
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::UnexpectedCommandType => ErrorCode::UnexpectedCommandType,
+            KvsError::EngineMismatch => ErrorCode::EngineMismatch,
+            KvsError::Timeout => ErrorCode::Timeout,
+            KvsError::Connection(_) => ErrorCode::Connection,
+            KvsError::UnsupportedFormat { .. } => ErrorCode::UnsupportedFormat,
+            KvsError::AlreadyLocked => ErrorCode::AlreadyLocked,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::KeyTooLong { .. } => ErrorCode::KeyTooLong,
+            KvsError::ValueTooLong { .. } => ErrorCode::ValueTooLong,
+            KvsError::TruncatedDump => ErrorCode::TruncatedDump,
+            KvsError::ChecksumMismatch => ErrorCode::ChecksumMismatch,
+            KvsError::Parse(_) => ErrorCode::Parse,
+            KvsError::StringError(_) => ErrorCode::Other,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    /// Writes the code as it is serialized, e.g. `key_not_found`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::Io => "io",
+            ErrorCode::Serialization => "serialization",
+            ErrorCode::Sled => "sled",
+            ErrorCode::Utf8 => "utf8",
+            ErrorCode::KeyNotFound => "key_not_found",
+            ErrorCode::UnexpectedCommandType => "unexpected_command_type",
+            ErrorCode::EngineMismatch => "engine_mismatch",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Connection => "connection",
+            ErrorCode::UnsupportedFormat => "unsupported_format",
+            ErrorCode::AlreadyLocked => "already_locked",
+            ErrorCode::ReadOnly => "read_only",
+            ErrorCode::KeyTooLong => "key_too_long",
+            ErrorCode::ValueTooLong => "value_too_long",
+            ErrorCode::TruncatedDump => "truncated_dump",
+            ErrorCode::ChecksumMismatch => "checksum_mismatch",
+            ErrorCode::Parse => "parse",
+            ErrorCode::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,189 @@
+//! The minimal HTTP/1.1 interface spoken by a server started with
+//! `WireProtocol::Http`.
+//!
+//! Routes:
+//!
+//! - `GET /kv/{key}` returns the value as the body, or 404 if the key doesn't exist.
+//! - `PUT /kv/{key}` sets the key to the body.
+//! - `DELETE /kv/{key}` removes the key, or returns 404 if it doesn't exist.
+//! - `GET /metrics` returns the server's metrics in the Prometheus text format.
+//!
+//! Keys are percent-decoded. On a server that requires a token, it is sent as
+//! `Authorization: Bearer <token>` with every request. Connections are kept
+//! alive unless the client asks otherwise, and chunked bodies are not supported.
+
+use crate::protocol::{Request, Response};
+use crate::resp::read_line;
+use crate::server::{AUTH_REQUIRED, INVALID_TOKEN};
+use crate::{KvsError, Result};
+use std::io::{self, BufRead, Read, Write};
+
+/// An HTTP request as far as the server cares about it.
+pub(crate) struct HttpRequest {
+    method: String,
+    path: String,
+    token: Option<String>,
+    body: Vec<u8>,
+    /// Whether the client wants the connection closed after the response.
+    pub(crate) close: bool,
+}
+
+/// Reads the next request.
+///
+/// Returns `None` once the client has closed the connection, and
+/// `KvsError::StringError` if the client sent something that isn't a request
+/// this server understands.
+pub(crate) fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request(format!("malformed request line {:?}", line)));
+    };
+    let mut close = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => return Err(bad_request(format!("unsupported version {:?}", version))),
+    };
+    let mut request = HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        token: None,
+        body: Vec::new(),
+        close,
+    };
+
+    let mut content_length = 0;
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request(format!("malformed header {:?}", line)))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| bad_request(format!("invalid Content-Length {:?}", value)))?;
+            }
+            "transfer-encoding" => return Err(bad_request("chunked bodies are not supported")),
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            "authorization" => request.token = value.strip_prefix("Bearer ").map(str::to_owned),
+            _ => {}
+        }
+    }
+    request.close = close;
+
+    // Read through `take` rather than into a buffer of `content_length` bytes,
+    // so that a bogus length can't make the server allocate it up front.
+    (&mut *reader).take(content_length).read_to_end(&mut request.body)?;
+    if request.body.len() as u64 != content_length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(request))
+}
+
+/// Runs a request through `execute` and writes the response.
+pub(crate) fn handle_request<W: Write>(
+    request: HttpRequest,
+    writer: &mut W,
+    mut execute: impl FnMut(Request) -> Response,
+) -> io::Result<()> {
+    let close = request.close;
+    if let Some(token) = request.token
+        && let Err(e) = execute(Request::Auth { token }).into_result()
+    {
+        return write_response(writer, 401, &e.to_string(), close);
+    }
+
+    let key = match request.path.strip_prefix("/kv/") {
+        Some(key) => match percent_decode(key) {
+            Some(key) if !key.is_empty() => key,
+            _ => return write_response(writer, 400, "Invalid key", close),
+        },
+        None if request.path == "/metrics" && request.method == "GET" => {
+            return match execute(Request::Metrics).into_result() {
+                Ok(metrics) => write_response(writer, 200, &metrics.unwrap_or_default(), close),
+                Err(e) => write_error(writer, &e, close),
+            };
+        }
+        None => return write_response(writer, 404, "Not found", close),
+    };
+    let req = match request.method.as_str() {
+        "GET" => Request::Get { key },
+        "PUT" => match String::from_utf8(request.body) {
+            Ok(value) => Request::Set { key, value },
+            Err(_) => return write_response(writer, 400, "Value is not valid UTF-8", close),
+        },
+        "DELETE" => Request::Remove { key },
+        _ => return write_response(writer, 405, "Method not allowed", close),
+    };
+    match execute(req).into_result() {
+        Ok(Some(value)) => write_response(writer, 200, &value, close),
+        Ok(None) if request.method == "GET" => write_response(writer, 404, "Key not found", close),
+        Ok(None) => write_response(writer, 204, "", close),
+        Err(e) => write_error(writer, &e, close),
+    }
+}
+
+/// Writes the response for an error returned by the server.
+fn write_error<W: Write>(writer: &mut W, err: &KvsError, close: bool) -> io::Result<()> {
+    let status = match err {
+        KvsError::KeyNotFound => 404,
+        KvsError::StringError(msg) if msg == AUTH_REQUIRED || msg == INVALID_TOKEN => 401,
+        _ => 500,
+    };
+    write_response(writer, status, &err.to_string(), close)
+}
+
+/// Writes a response with a plain text body.
+pub(crate) fn write_response<W: Write>(writer: &mut W, status: u16, body: &str, close: bool) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    write!(writer, "HTTP/1.1 {} {}\r\n", status, reason)?;
+    if status != 204 {
+        write!(writer, "Content-Type: text/plain; charset=utf-8\r\n")?;
+        write!(writer, "Content-Length: {}\r\n", body.len())?;
+    }
+    if close {
+        write!(writer, "Connection: close\r\n")?;
+    }
+    write!(writer, "\r\n{}", body)
+}
+
+/// Decodes `%XX` escapes, returning `None` if the result isn't valid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn bad_request(message: impl Into<String>) -> KvsError {
+    KvsError::StringError(message.into())
+}
@@ -1,12 +1,36 @@
-pub use client::KvsClient;
-pub use engine::{Engine, KvStore, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
-pub use protocol::{Request, Response};
-pub use server::KvsServer;
+//! A simple on-disk key/value store with pluggable storage engines, and a
+//! client and server to access it over the network.
+//!
+//! # Features
+//!
+//! - `sled` (default): the `SledKvsEngine` engine and `Engine::Sled`. Build
+//!   with `default-features = false` to only get `KvStore` and drop the sled
+//!   dependency.
+//! - `http`: `WireProtocol::Http`, a small REST interface to the server.
+//! - `mmap`: reads `KvStore` values through a memory map of the log.
+
+pub use config::ServerConfig;
+pub use client::{KvsClient, KvsClientPool, ReconnectPolicy};
+pub use engine::{
+    AnyEngine, CompactionEstimate, CompactionPolicy, CompactionReport, Engine, Event, FlushMode, KvStore,
+    KvStoreIter, KvsEngine, NamespaceHandle, ShardedKvStore, Snapshot, StoreConfig, StoreStats, Txn,
+    detect_engine, persist_engine,
+};
+#[cfg(feature = "sled")]
+pub use engine::SledKvsEngine;
+pub use error::{ErrorCode, KvsError, Result};
+pub use protocol::{Health, Request, Response, ServerInfo};
+pub use server::{AccessLog, KvsServer, KvsServerBuilder, ReloadHandle, ShutdownHandle, WireProtocol};
 
 mod error;
 mod engine;
 pub mod protocol;
 mod client;
+mod config;
+#[cfg(feature = "http")]
+mod http;
+mod metrics;
+mod replies;
+mod resp;
 mod server;
 pub mod thread_pool;
\ No newline at end of file
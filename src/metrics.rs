@@ -0,0 +1,101 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in microseconds.
+const BUCKETS: [u64; 10] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000];
+
+/// The operations the server keeps metrics for.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    Get,
+    GetMany,
+    Set,
+    Remove,
+    Contains,
+}
+
+impl Op {
+    const ALL: [Op; 5] = [Op::Get, Op::GetMany, Op::Set, Op::Remove, Op::Contains];
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::GetMany => "get_many",
+            Op::Set => "set",
+            Op::Remove => "remove",
+            Op::Contains => "contains",
+        }
+    }
+}
+
+/// Request counters and latency histograms, updated by every connection.
+///
+/// Everything is a relaxed atomic so that recording a request never blocks.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    ops: [OpMetrics; 5],
+}
+
+#[derive(Default)]
+struct OpMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    /// Per-bucket counts; the last slot counts requests slower than every bound.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    latency_micros: AtomicU64,
+}
+
+impl Metrics {
+    /// Records one handled request.
+    pub(crate) fn record(&self, op: Op, latency: Duration, failed: bool) {
+        let metrics = &self.ops[op as usize];
+        let micros = latency.as_micros() as u64;
+        let bucket = BUCKETS.iter().position(|&bound| micros <= bound).unwrap_or(BUCKETS.len());
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        metrics.latency_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE kvs_requests_total counter\n");
+        for op in Op::ALL {
+            let requests = self.ops[op as usize].requests.load(Ordering::Relaxed);
+            writeln!(out, "kvs_requests_total{{op=\"{}\"}} {}", op.name(), requests).unwrap();
+        }
+        out.push_str("# TYPE kvs_errors_total counter\n");
+        for op in Op::ALL {
+            let errors = self.ops[op as usize].errors.load(Ordering::Relaxed);
+            writeln!(out, "kvs_errors_total{{op=\"{}\"}} {}", op.name(), errors).unwrap();
+        }
+        out.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        for op in Op::ALL {
+            let metrics = &self.ops[op as usize];
+            let mut count = 0;
+            for (i, bucket) in metrics.buckets.iter().enumerate() {
+                count += bucket.load(Ordering::Relaxed);
+                let le = match BUCKETS.get(i) {
+                    Some(&bound) => (bound as f64 / 1e6).to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    op.name(),
+                    le,
+                    count
+                )
+                .unwrap();
+            }
+            let sum = metrics.latency_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            writeln!(out, "kvs_request_duration_seconds_sum{{op=\"{}\"}} {}", op.name(), sum).unwrap();
+            writeln!(out, "kvs_request_duration_seconds_count{{op=\"{}\"}} {}", op.name(), count).unwrap();
+        }
+        out
+    }
+}
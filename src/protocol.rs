@@ -1,14 +1,133 @@
+use crate::{KvsError, Result};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Request {
     Set { key: String, value: String },
     Get { key: String },
+    /// Gets several keys at once. Answered with `Response::Values`.
+    GetMany { keys: Vec<String> },
     Remove { key: String },
+    /// Asks whether a key exists, without its value. Answered with
+    /// `Response::Bool`.
+    Contains { key: String },
+    Ping,
+    /// Asks for the server's metrics in the Prometheus text format.
+    Metrics,
+    /// Asks what the server runs. Answered with `Response::ServerInfo`.
+    ServerInfo,
+    /// Asks whether the server is up and should be sent traffic, for
+    /// orchestrators' liveness and readiness probes. Answered with
+    /// `Response::Health`, even on a server that requires authentication.
+    Health,
+    /// Authenticates the connection on a server that requires a token.
+    Auth { token: String },
+    /// Runs `request` at most once: if the same client sent the same request
+    /// with the same `id` recently, its response is returned again instead.
+    /// This makes requests that change data safe to retry. `client` is a
+    /// random number a client picks once and sends with all its ids, so that
+    /// clients don't have to keep their ids apart. Reusing an id for a
+    /// different request is answered with an error.
+    WithId { client: u64, id: u64, request: Box<Request> },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
     Ok(Option<String>),
     Err(String),
+    /// The key of a `Remove` request doesn't exist.
+    KeyNotFound,
+    /// The values of the keys of a `GetMany` request, in the same order.
+    Values(Vec<Option<String>>),
+    ServerInfo(ServerInfo),
+    /// Whether the key of a `Contains` request exists.
+    Bool(bool),
+    Health(Health),
+}
+
+/// What a server runs, as returned by `KvsClient::server_info`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The version of the server.
+    pub version: String,
+    /// The name of the engine, e.g. `kvs` or `sled`.
+    pub engine: String,
+    /// Seconds since the server started.
+    pub uptime_secs: u64,
+    /// Number of requests the server has handled.
+    pub total_requests: u64,
+}
+
+/// Whether a server can serve requests, as returned by `KvsClient::health`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    /// Whether the server should be sent traffic: false while the engine is
+    /// busy, e.g. compacting its log. The server only starts listening once
+    /// the engine has been opened, so it is never asked before then.
+    pub ready: bool,
+    /// Whether the engine passed its self-check, see `KvsEngine::check`.
+    pub engine_ok: bool,
+}
+
+impl Response {
+    /// Turns the response into the result of the request, with the error the
+    /// engine would have returned locally where it is known.
+    pub(crate) fn into_result(self) -> Result<Option<String>> {
+        match self {
+            Response::Ok(value) => Ok(value),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            Response::Values(_) | Response::ServerInfo(_) | Response::Bool(_) | Response::Health(_) => {
+                Err(unexpected_response())
+            }
+        }
+    }
+
+    /// Like `into_result`, for the response to a `GetMany` request.
+    pub(crate) fn into_values(self) -> Result<Vec<Option<String>>> {
+        match self {
+            Response::Values(values) => Ok(values),
+            Response::Ok(_) | Response::ServerInfo(_) | Response::Bool(_) | Response::Health(_) => {
+                Err(unexpected_response())
+            }
+            resp => resp.into_result().map(|_| Vec::new()),
+        }
+    }
+
+    /// Like `into_result`, for the response to a `ServerInfo` request.
+    pub(crate) fn into_server_info(self) -> Result<ServerInfo> {
+        match self {
+            Response::ServerInfo(info) => Ok(info),
+            Response::Ok(_) | Response::Values(_) | Response::Bool(_) | Response::Health(_) => {
+                Err(unexpected_response())
+            }
+            resp => resp.into_result().and_then(|_| Err(unexpected_response())),
+        }
+    }
+
+    /// Like `into_result`, for the response to a `Contains` request.
+    pub(crate) fn into_bool(self) -> Result<bool> {
+        match self {
+            Response::Bool(value) => Ok(value),
+            Response::Ok(_) | Response::Values(_) | Response::ServerInfo(_) | Response::Health(_) => {
+                Err(unexpected_response())
+            }
+            resp => resp.into_result().and_then(|_| Err(unexpected_response())),
+        }
+    }
+
+    /// Like `into_result`, for the response to a `Health` request.
+    pub(crate) fn into_health(self) -> Result<Health> {
+        match self {
+            Response::Health(health) => Ok(health),
+            Response::Ok(_) | Response::Values(_) | Response::ServerInfo(_) | Response::Bool(_) => {
+                Err(unexpected_response())
+            }
+            resp => resp.into_result().and_then(|_| Err(unexpected_response())),
+        }
+    }
+}
+
+fn unexpected_response() -> KvsError {
+    KvsError::StringError("Unexpected response from the server".to_owned())
 }
@@ -0,0 +1,108 @@
+use crate::protocol::{Request, Response};
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// The answer to a `Request::WithId` whose id was sent before with a different
+/// request.
+const ID_REUSED: &str = "Request id reused for a different request";
+
+/// The responses to the latest requests sent with `Request::WithId`, so that a
+/// retried request is answered without running it a second time.
+///
+/// Requests are keyed by the client's random number and the id, and the
+/// request is kept next to its response, so that one client's response is
+/// never replayed to another request. Only the `capacity` most recent ids are
+/// remembered; a retry of an older request runs again.
+pub(crate) struct ReplyCache {
+    capacity: usize,
+    replies: Mutex<Replies>,
+    /// Notified whenever a request finishes.
+    finished: Condvar,
+}
+
+/// A client's random number and a request id.
+type ReplyKey = (u64, u64);
+
+#[derive(Default)]
+struct Replies {
+    entries: HashMap<ReplyKey, Reply>,
+    /// Keys in the order they were first seen.
+    order: VecDeque<ReplyKey>,
+}
+
+struct Reply {
+    request: Request,
+    /// `None` while the request is still running.
+    response: Option<Response>,
+}
+
+impl ReplyCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ReplyCache {
+            capacity,
+            replies: Mutex::new(Replies::default()),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Returns the response to `request`, sent by `client` with `id`, running
+    /// it with `run` if the id hasn't been seen. A retry that arrives while the
+    /// first attempt is still running waits for its response.
+    pub(crate) fn get_or_run(
+        &self,
+        client: u64,
+        id: u64,
+        request: Request,
+        run: impl FnOnce(Request) -> Response,
+    ) -> Response {
+        let key = (client, id);
+        let mut replies = self.replies.lock().unwrap();
+        loop {
+            match replies.entries.get(&key) {
+                Some(reply) if reply.request != request => return Response::Err(ID_REUSED.to_owned()),
+                Some(Reply { response: Some(resp), .. }) => return resp.clone(),
+                Some(Reply { response: None, .. }) => replies = self.finished.wait(replies).unwrap(),
+                None => break,
+            }
+        }
+        let reply = Reply {
+            request: request.clone(),
+            response: None,
+        };
+        replies.entries.insert(key, reply);
+        replies.order.push_back(key);
+        while replies.order.len() > self.capacity {
+            if let Some(oldest) = replies.order.pop_front() {
+                replies.entries.remove(&oldest);
+            }
+        }
+        drop(replies);
+
+        let guard = Running { cache: self, key };
+        let resp = run(request);
+        mem::forget(guard);
+        let mut replies = self.replies.lock().unwrap();
+        if let Some(reply) = replies.entries.get_mut(&key) {
+            reply.response = Some(resp.clone());
+        }
+        self.finished.notify_all();
+        resp
+    }
+}
+
+/// Forgets a request whose `run` panicked, so that the retries waiting for it
+/// wake up and run it themselves instead of waiting forever.
+struct Running<'a> {
+    cache: &'a ReplyCache,
+    key: ReplyKey,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut replies = self.cache.replies.lock().unwrap_or_else(PoisonError::into_inner);
+        replies.entries.remove(&self.key);
+        replies.order.retain(|&key| key != self.key);
+        self.cache.finished.notify_all();
+    }
+}
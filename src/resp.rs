@@ -0,0 +1,157 @@
+//! The subset of the Redis serialization protocol (RESP) spoken by a server
+//! started with `WireProtocol::Resp`.
+//!
+//! The commands are `GET`, `MGET`, `SET`, `DEL`, `PING` and `AUTH`. They are
+//! read either as arrays of bulk strings, which is what Redis clients send, or
+//! as inline commands separated by spaces, which is handy with `telnet` or `nc`. Each command is translated into a `Request`, and the
+//! `Response` back into the reply a Redis client expects.
+
+use crate::protocol::{Request, Response};
+use crate::{KvsError, Result};
+use std::io::{self, BufRead, Read, Write};
+use std::mem;
+
+/// Reads the next command and its arguments.
+///
+/// Returns `None` once the client has closed the connection, and
+/// `KvsError::StringError` if the client sent something that isn't RESP.
+pub(crate) fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix('*') else {
+        return Ok(Some(line.split_whitespace().map(str::to_owned).collect()));
+    };
+
+    let count = parse_len(count)?;
+    let mut args = Vec::with_capacity(count.min(16));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let len = line
+            .strip_prefix('$')
+            .ok_or_else(|| protocol_error(format!("expected '$', got {:?}", line)))?;
+        let len = parse_len(len)?;
+        // Read through `take` rather than into a buffer of `len` bytes, so that
+        // a bogus length can't make the server allocate it up front.
+        let mut arg = Vec::new();
+        (&mut *reader).take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is longer than its length"));
+        }
+        arg.truncate(len);
+        let arg = String::from_utf8(arg).map_err(|_| protocol_error("argument is not valid UTF-8"))?;
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Runs a command through `execute` and writes the reply.
+pub(crate) fn handle_command<W: Write>(
+    args: Vec<String>,
+    writer: &mut W,
+    mut execute: impl FnMut(Request) -> Response,
+) -> io::Result<()> {
+    let mut args = args;
+    if args.is_empty() {
+        // Redis ignores empty inline commands.
+        return Ok(());
+    }
+    let name = args.remove(0);
+    match (name.to_ascii_uppercase().as_str(), args.as_mut_slice()) {
+        ("GET", [key]) => match execute(Request::Get { key: mem::take(key) }).into_result() {
+            Ok(value) => write_bulk(writer, value.as_deref()),
+            Err(e) => write_error(writer, &e.to_string()),
+        },
+        ("MGET", keys) if !keys.is_empty() => {
+            let keys = keys.iter_mut().map(mem::take).collect();
+            match execute(Request::GetMany { keys }).into_values() {
+                Ok(values) => {
+                    write!(writer, "*{}\r\n", values.len())?;
+                    values.iter().try_for_each(|value| write_bulk(writer, value.as_deref()))
+                }
+                Err(e) => write_error(writer, &e.to_string()),
+            }
+        }
+        ("SET", [key, value]) => {
+            let req = Request::Set {
+                key: mem::take(key),
+                value: mem::take(value),
+            };
+            match execute(req).into_result() {
+                Ok(_) => write!(writer, "+OK\r\n"),
+                Err(e) => write_error(writer, &e.to_string()),
+            }
+        }
+        ("DEL", keys) if !keys.is_empty() => {
+            // Like Redis, reply with the number of keys that existed.
+            let mut removed = 0;
+            for key in keys {
+                match execute(Request::Remove { key: mem::take(key) }).into_result() {
+                    Ok(_) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return write_error(writer, &e.to_string()),
+                }
+            }
+            write!(writer, ":{}\r\n", removed)
+        }
+        ("PING", []) => {
+            execute(Request::Ping);
+            write!(writer, "+PONG\r\n")
+        }
+        ("PING", [message]) => {
+            execute(Request::Ping);
+            write_bulk(writer, Some(message))
+        }
+        ("AUTH", [token]) => match execute(Request::Auth { token: mem::take(token) }).into_result() {
+            Ok(_) => write!(writer, "+OK\r\n"),
+            Err(e) => write_error(writer, &e.to_string()),
+        },
+        (command @ ("GET" | "MGET" | "SET" | "DEL" | "PING" | "AUTH"), _) => write_error(
+            writer,
+            &format!("wrong number of arguments for '{}' command", command.to_ascii_lowercase()),
+        ),
+        _ => write_error(writer, &format!("unknown command '{}'", name)),
+    }
+}
+
+/// Writes an error reply.
+pub(crate) fn write_error<W: Write>(writer: &mut W, message: &str) -> io::Result<()> {
+    // The message ends at the first line break.
+    write!(writer, "-ERR {}\r\n", message.replace(['\r', '\n'], " "))
+}
+
+/// Writes a bulk string, or the null bulk string for `None`.
+fn write_bulk<W: Write>(writer: &mut W, value: Option<&str>) -> io::Result<()> {
+    match value {
+        Some(value) => write!(writer, "${}\r\n{}\r\n", value.len(), value),
+        None => write!(writer, "$-1\r\n"),
+    }
+}
+
+/// Reads a line without its line break, or `None` at the end of the stream.
+pub(crate) fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    let line = String::from_utf8(line).map_err(|_| protocol_error("line is not valid UTF-8"))?;
+    Ok(Some(line))
+}
+
+fn parse_len(len: &str) -> Result<usize> {
+    len.parse()
+        .map_err(|_| protocol_error(format!("invalid length {:?}", len)))
+}
+
+fn protocol_error(message: impl Into<String>) -> KvsError {
+    KvsError::StringError(message.into())
+}
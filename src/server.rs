@@ -1,65 +1,1187 @@
-use crate::engine::KvsEngine;
-use crate::protocol::{Request, Response};
-use crate::Result;
-use log::{debug, error};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use crate::engine::{KvsEngine, SizeLimits};
+use crate::metrics::{Metrics, Op};
+use crate::protocol::{Health, Request, Response, ServerInfo};
+use crate::replies::ReplyCache;
+#[cfg(feature = "http")]
+use crate::http;
+use crate::resp;
+use crate::{KvsError, Result};
+use clap::ValueEnum;
+use crossbeam_channel::Sender;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use crate::thread_pool::ThreadPool;
 
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+/// The largest request a client may send unless `with_max_request_size` says
+/// otherwise, so that a single request can't exhaust the server's memory.
+const DEFAULT_MAX_REQUEST_SIZE: u64 = 64 * 1024 * 1024;
+pub(crate) const AUTH_REQUIRED: &str = "Authentication required";
+pub(crate) const INVALID_TOKEN: &str = "Invalid authentication token";
+const TOO_MANY_CONNECTIONS: &str = "Too many connections";
+const SERVER_BUSY: &str = "Server busy";
+/// Number of responses to `Request::WithId` a server remembers.
+const REPLY_CACHE_ENTRIES: usize = 10_000;
+/// How long an accept loop first waits after `accept` fails for lack of
+/// resources, such as file descriptors. The wait doubles with every failure in
+/// a row, up to `MAX_ACCEPT_BACKOFF`.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
-    engine: E,
+    engine: Arc<Mutex<E>>,
     pool: P,
+    context: Context,
+    state: Arc<ServerState>,
+}
+
+/// Stops a running `KvsServer` from another thread, e.g. a signal handler.
+///
+/// Get one with `KvsServer::shutdown_handle` before calling `run`. Clones
+/// stop the same server.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<ServerState>);
+
+/// Replaces the engine of a running `KvsServer` from another thread.
+///
+/// Get one with `KvsServer::reload_handle` before calling `run`. Clones
+/// replace the engine of the same server.
+#[derive(Clone)]
+pub struct ReloadHandle<E>(Arc<Mutex<E>>);
+
+/// The state of a server that outlives single connections.
+struct ServerState {
+    /// Whether a shutdown has been requested.
+    requested: AtomicBool,
+    /// The addresses the server listens on, to wake up blocked `accept`s.
+    local_addrs: Mutex<Vec<SocketAddr>>,
+    /// The open connections, so that they can be told to finish.
+    connections: Mutex<HashMap<u64, OpenConnection>>,
+    next_id: AtomicU64,
+    /// Notified whenever a connection closes.
+    closed: Condvar,
+    /// The time activity is measured from.
+    started: Instant,
+}
+
+struct OpenConnection {
+    stream: TcpStream,
+    activity: Activity,
+}
+
+/// When a connection last received data.
+#[derive(Clone)]
+struct Activity {
+    since: Instant,
+    /// Milliseconds from `since` to the last time data was received.
+    last_millis: Arc<AtomicU64>,
+}
+
+/// Settings and state shared by every connection of a server.
+#[derive(Clone)]
+struct Context {
+    metrics: Arc<Metrics>,
+    replies: Arc<ReplyCache>,
+    /// Number of requests handled, for `Request::ServerInfo`.
+    requests: Arc<AtomicU64>,
+    /// When the server was started, for `Request::ServerInfo`.
+    started: Instant,
+    access_log: Option<Arc<AccessLogger>>,
+    max_request_size: u64,
+    size_limits: SizeLimits,
+    max_connections: Option<usize>,
+    backlog: Option<u32>,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    stats_interval: Option<Duration>,
+    auth_token: Option<String>,
+    read_buffer_size: usize,
+    write_buffer_size: usize,
+    nodelay: bool,
+    protocol: WireProtocol,
+}
+
+/// Where the server writes its access log.
+///
+/// Each handled request produces one line with the peer address, the operation,
+/// the key, whether it succeeded and how long the engine took, e.g.
+/// `127.0.0.1:53012 get key1 ok 42us`.
+#[derive(Debug, Clone)]
+pub enum AccessLog {
+    /// Log through the `log` facility at info level.
+    Log,
+    /// Append to the given file.
+    File(PathBuf),
+}
+
+/// The protocol a server speaks with its clients.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireProtocol {
+    /// The native protocol: `Request`s and `Response`s as JSON. This is what
+    /// `KvsClient` speaks.
+    #[default]
+    Json,
+    /// A subset of the Redis serialization protocol, so that Redis clients can
+    /// talk to the server: `GET`, `MGET`, `SET`, `DEL`, `PING` and `AUTH`.
+    Resp,
+    /// A small REST interface over HTTP/1.1: `GET`, `PUT` and `DELETE` on
+    /// `/kv/{key}`. Needs the `http` feature.
+    #[cfg(feature = "http")]
+    Http,
+}
+
+enum AccessLogger {
+    Log,
+    File(Mutex<File>),
+}
+
+impl AccessLogger {
+    fn write(&self, entry: &str) {
+        match self {
+            AccessLogger::Log => info!(target: "kvs::access", "{}", entry),
+            AccessLogger::File(file) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", entry) {
+                    error!("Error writing access log: {}", e);
+                }
+            }
+        }
+    }
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            engine: Arc::new(Mutex::new(engine)),
+            pool,
+            context: Context {
+                metrics: Arc::new(Metrics::default()),
+                replies: Arc::new(ReplyCache::new(REPLY_CACHE_ENTRIES)),
+                requests: Arc::new(AtomicU64::new(0)),
+                started: Instant::now(),
+                access_log: None,
+                max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+                size_limits: SizeLimits::default(),
+                max_connections: None,
+                backlog: None,
+                idle_timeout: None,
+                read_timeout: None,
+                stats_interval: None,
+                auth_token: None,
+                read_buffer_size: DEFAULT_BUFFER_SIZE,
+                write_buffer_size: DEFAULT_BUFFER_SIZE,
+                nodelay: true,
+                protocol: WireProtocol::Json,
+            },
+            state: Arc::new(ServerState::new()),
+        }
+    }
+
+    /// Returns a builder for a server with `engine` and `pool` and the options
+    /// set on the builder, for when there are many of them.
+    pub fn builder(engine: E, pool: P) -> KvsServerBuilder<E, P> {
+        KvsServerBuilder {
+            server: KvsServer::new(engine, pool),
+            access_log: None,
+        }
+    }
+
+    /// Returns a handle that makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.state.clone())
+    }
+
+    /// Returns a handle that replaces the engine while `run` serves clients.
+    pub fn reload_handle(&self) -> ReloadHandle<E> {
+        ReloadHandle(self.engine.clone())
+    }
+
+    /// Replaces the engine, like `ReloadHandle::reload`.
+    pub fn reload_engine(&self, new_engine: E) -> Result<()> {
+        self.reload_handle().reload(new_engine)
+    }
+
+    /// Requires clients to send `Request::Auth` with `token` before any other
+    /// request. Until then every request except `Ping` is rejected.
+    pub fn with_auth(mut self, token: impl Into<String>) -> Self {
+        self.context.auth_token = Some(token.into());
+        self
+    }
+
+    /// Sets the protocol spoken on every connection. The default is
+    /// `WireProtocol::Json`.
+    pub fn with_protocol(mut self, protocol: WireProtocol) -> Self {
+        self.context.protocol = protocol;
+        self
+    }
+
+    /// Sets whether `TCP_NODELAY` is set on accepted connections.
+    ///
+    /// It is on by default, so that small responses are sent immediately instead
+    /// of being delayed by Nagle's algorithm.
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.context.nodelay = nodelay;
+        self
+    }
+
+    /// Sets the sizes of the per-connection read and write buffers, which are
+    /// 8KB by default.
+    ///
+    /// Larger buffers mean fewer system calls for large values, at the cost of
+    /// more memory per connection. Every response is still flushed as soon as
+    /// it has been written.
+    pub fn with_buffer_sizes(mut self, read: usize, write: usize) -> Self {
+        self.context.read_buffer_size = read;
+        self.context.write_buffer_size = write;
+        self
+    }
+
+    /// Limits the size of a single request to `bytes`.
+    ///
+    /// A client that sends a larger request is sent an error and disconnected
+    /// as soon as it goes over the limit, instead of having the request
+    /// buffered. The limit applies to the bytes read, whether or not the
+    /// request is ever complete. It is 64MB by default.
+    pub fn with_max_request_size(mut self, bytes: u64) -> Self {
+        self.context.max_request_size = bytes;
+        self
+    }
+
+    /// Rejects writes of keys longer than `bytes` with `KvsError::KeyTooLong`,
+    /// whatever the engine allows. Keys are unlimited by default.
+    pub fn with_max_key_len(mut self, bytes: usize) -> Self {
+        self.context.size_limits.max_key_len = Some(bytes);
+        self
+    }
+
+    /// Rejects writes of values longer than `bytes` with
+    /// `KvsError::ValueTooLong`, whatever the engine allows. Values are
+    /// unlimited by default.
+    pub fn with_max_value_len(mut self, bytes: usize) -> Self {
+        self.context.size_limits.max_value_len = Some(bytes);
+        self
+    }
+
+    /// Limits the number of connections open at once to `max`.
+    ///
+    /// Connections beyond the limit are sent an error in the server's protocol
+    /// and closed right away, so that a flood of connections can't exhaust the
+    /// server's memory and file descriptors. Connections are unlimited by
+    /// default.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.context.max_connections = Some(max);
+        self
+    }
+
+    /// Sets how many connections the operating system queues for the server
+    /// before it accepts them. Connections beyond that are refused or retried
+    /// by the client, depending on the system.
+    ///
+    /// Only has an effect on Unix. The default is the standard library's, 128
+    /// or the system's maximum.
+    pub fn with_backlog(mut self, backlog: u32) -> Self {
+        self.context.backlog = Some(backlog);
+        self
+    }
+
+    /// Closes connections that haven't sent anything for `timeout`.
+    ///
+    /// This frees the workers of clients that went away without closing their
+    /// connection. A request in progress when the timeout hits is still
+    /// answered. Connections are never closed for being idle by default.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.context.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections on which a single read waits longer than `timeout`,
+    /// including in the middle of a request.
+    ///
+    /// Unlike `with_idle_timeout`, which waits for the request being handled,
+    /// this also frees the workers of clients that stall while sending a
+    /// request. There is no read timeout by default.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.context.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Logs the number of open connections and of requests handled, in total
+    /// and since the previous line, at info level every `interval`. Nothing is
+    /// logged by default.
+    pub fn with_stats_interval(mut self, interval: Duration) -> Self {
+        self.context.stats_interval = Some(interval);
+        self
+    }
+
+    /// Enables the access log.
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Result<Self> {
+        let logger = match access_log {
+            AccessLog::Log => AccessLogger::Log,
+            AccessLog::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                AccessLogger::File(Mutex::new(file))
+            }
+        };
+        self.context.access_log = Some(Arc::new(logger));
+        Ok(self)
+    }
+
+    /// Serves a single connection that isn't a TCP stream, such as in-memory
+    /// buffers or a pipe, reading requests from `reader` until it ends and
+    /// writing the responses to `writer`.
+    ///
+    /// The connection is served on the calling thread, in the server's protocol
+    /// and with its settings, except for those specific to TCP.
+    pub fn handle<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<()> {
+        serve(self.engine(), &self.context, "-", reader, writer, None)
+    }
+
+    /// Serves a single TCP connection accepted elsewhere, such as on a listener
+    /// the caller set up itself, on the calling thread.
+    ///
+    /// Unlike `handle`, this applies the TCP settings, such as `with_nodelay`
+    /// and `with_read_timeout`. The connection is not counted by
+    /// `with_max_connections` and not closed by `with_idle_timeout`.
+    pub fn handle_stream(&self, stream: TcpStream) -> Result<()> {
+        handle_client(self.engine(), &self.context, stream, None)
+    }
+
+    /// Accepts and serves connections on every address `addrs` resolves to,
+    /// such as `"127.0.0.1:4000"`, or `&[addr1, addr2][..]` to listen on
+    /// several interfaces or on both IPv4 and IPv6.
+    ///
+    /// Each address gets its own accept loop, and the connections of all of
+    /// them share the thread pool and the engine. Each open connection takes
+    /// up a worker of the pool; while the pool's queue is full (see
+    /// `ThreadPool::is_full`), new connections are sent a "Server busy" error
+    /// and closed rather than left waiting.
+    ///
+    /// Runs until `ShutdownHandle::shutdown` is called. The server then stops
+    /// accepting connections, lets every open connection finish the request it
+    /// is handling, and flushes the engine before returning.
+    pub fn run<A: ToSocketAddrs>(&mut self, addrs: A) -> Result<()> {
+        let listeners = addrs
+            .to_socket_addrs()?
+            .map(TcpListener::bind)
+            .collect::<io::Result<Vec<_>>>()?;
+        if listeners.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on").into());
+        }
+        if let Some(backlog) = self.context.backlog {
+            for listener in &listeners {
+                set_backlog(listener, backlog)?;
+            }
+        }
+        let local_addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+        *self.state.local_addrs.lock().unwrap() = local_addrs;
+        let context = Arc::new(Context {
+            started: Instant::now(),
+            ..self.context.clone()
+        });
+        if let Some(timeout) = context.idle_timeout {
+            let state = self.state.clone();
+            thread::spawn(move || state.close_idle_connections(timeout));
+        }
+        if let Some(interval) = context.stats_interval {
+            let state = self.state.clone();
+            let requests = context.requests.clone();
+            thread::spawn(move || state.log_stats(interval, &requests));
+        }
+
+        let (sender, receiver) = crossbeam_channel::bounded(0);
+        // A shutdown requested before the addresses were known can't wake up
+        // the accept loops, so don't start them.
+        if !self.state.requested.load(Ordering::SeqCst) {
+            for listener in listeners {
+                let state = self.state.clone();
+                let sender = sender.clone();
+                thread::spawn(move || state.accept(listener, sender));
+            }
+        }
+        drop(sender);
+        for stream in receiver {
+            if self.state.requested.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Some(max) = context.max_connections
+                && self.state.connections.lock().unwrap().len() >= max
+            {
+                debug!("Refusing connection: {} connections are open", max);
+                reject(&context, stream, TOO_MANY_CONNECTIONS);
+                continue;
+            }
+            // Spawning onto a full pool would block, and with it the accept
+            // loops, so turn the client away instead.
+            if self.pool.is_full() {
+                debug!("Refusing connection: the thread pool is full");
+                reject(&context, stream, SERVER_BUSY);
+                continue;
+            }
+            let connection = match Connection::open(&self.state, &stream) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let engine = self.engine();
+            let context = context.clone();
+            self.pool.spawn(move || {
+                let activity = connection.activity.clone();
+                match handle_client(engine, &context, stream, Some(activity)) {
+                    Ok(()) => {}
+                    Err(e) if is_disconnect(&e) => debug!("Client disconnected: {}", e),
+                    Err(e) if is_timeout(&e) => debug!("Closing connection after read timeout: {}", e),
+                    Err(e) => error!("Error handling client: {}", e),
+                }
+                drop(connection);
+            });
+        }
+
+        info!("Shutting down");
+        let mut connections = self.state.connections.lock().unwrap();
+        if !connections.is_empty() {
+            info!("Waiting for {} connections to close", connections.len());
+        }
+        while !connections.is_empty() {
+            connections = self.state.closed.wait(connections).unwrap();
+        }
+        drop(connections);
+        self.engine().flush()
+    }
+
+    /// Returns the engine new connections are served with.
+    fn engine(&self) -> E {
+        self.engine.lock().unwrap().clone()
+    }
+}
+
+/// Collects the options of a `KvsServer`, as returned by `KvsServer::builder`.
+///
+/// Each option is the same as the `with_` method of `KvsServer` of the same
+/// name, and options that aren't set keep their defaults:
+///
+/// ```no_run
+/// # use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+/// # use kvs::{KvStore, KvsServer, Result};
+/// # use std::time::Duration;
+/// # fn main() -> Result<()> {
+/// let mut server = KvsServer::builder(KvStore::open("data")?, SharedQueueThreadPool::new(4)?)
+///     .auth("secret")
+///     .max_connections(100)
+///     .read_timeout(Duration::from_secs(30))
+///     .build()?;
+/// server.run("127.0.0.1:4000")
+/// # }
+/// ```
+pub struct KvsServerBuilder<E: KvsEngine, P: ThreadPool> {
+    server: KvsServer<E, P>,
+    access_log: Option<AccessLog>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServerBuilder<E, P> {
+    /// See `KvsServer::with_auth`.
+    pub fn auth(mut self, token: impl Into<String>) -> Self {
+        self.server = self.server.with_auth(token);
+        self
+    }
+
+    /// See `KvsServer::with_protocol`.
+    pub fn protocol(mut self, protocol: WireProtocol) -> Self {
+        self.server = self.server.with_protocol(protocol);
+        self
+    }
+
+    /// See `KvsServer::with_nodelay`.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.server = self.server.with_nodelay(nodelay);
+        self
+    }
+
+    /// See `KvsServer::with_buffer_sizes`.
+    pub fn buffer_sizes(mut self, read: usize, write: usize) -> Self {
+        self.server = self.server.with_buffer_sizes(read, write);
+        self
+    }
+
+    /// See `KvsServer::with_max_request_size`.
+    pub fn max_request_size(mut self, bytes: u64) -> Self {
+        self.server = self.server.with_max_request_size(bytes);
+        self
+    }
+
+    /// See `KvsServer::with_max_key_len`.
+    pub fn max_key_len(mut self, bytes: usize) -> Self {
+        self.server = self.server.with_max_key_len(bytes);
+        self
+    }
+
+    /// See `KvsServer::with_max_value_len`.
+    pub fn max_value_len(mut self, bytes: usize) -> Self {
+        self.server = self.server.with_max_value_len(bytes);
+        self
+    }
+
+    /// See `KvsServer::with_max_connections`.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.server = self.server.with_max_connections(max);
+        self
+    }
+
+    /// See `KvsServer::with_backlog`.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.server = self.server.with_backlog(backlog);
+        self
+    }
+
+    /// See `KvsServer::with_idle_timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.server = self.server.with_idle_timeout(timeout);
+        self
+    }
+
+    /// See `KvsServer::with_read_timeout`.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.server = self.server.with_read_timeout(timeout);
+        self
+    }
+
+    /// See `KvsServer::with_stats_interval`.
+    pub fn stats_interval(mut self, interval: Duration) -> Self {
+        self.server = self.server.with_stats_interval(interval);
+        self
+    }
+
+    /// See `KvsServer::with_access_log`. The log file is opened by `build`.
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Creates the server.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if the access log file can't be opened.
+    pub fn build(self) -> Result<KvsServer<E, P>> {
+        match self.access_log {
+            Some(access_log) => self.server.with_access_log(access_log),
+            None => Ok(self.server),
+        }
+    }
+}
+
+impl<E: KvsEngine> ReloadHandle<E> {
+    /// Serves connections opened from now on with `new_engine`, and flushes the
+    /// engine it replaces.
+    ///
+    /// Connections that are already open keep using the old engine until they
+    /// close, so for a while both engines are in use. A `KvStore` can only be
+    /// opened once at a time, so the new engine must use another directory.
+    pub fn reload(&self, new_engine: E) -> Result<()> {
+        let old_engine = std::mem::replace(&mut *self.0.lock().unwrap(), new_engine);
+        info!("Engine reloaded");
+        old_engine.flush()
+    }
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down, without waiting for it to do so.
+    ///
+    /// Open connections are closed for reading, so that each of them ends once
+    /// its current request has been answered.
+    pub fn shutdown(&self) {
+        let state = &self.0;
+        if state.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for connection in state.connections.lock().unwrap().values() {
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+        // `accept` only notices the request once another connection comes in.
+        for mut addr in state.local_addrs.lock().unwrap().iter().copied() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+    }
+}
+
+impl ServerState {
+    fn new() -> Self {
+        ServerState {
+            requested: AtomicBool::new(false),
+            local_addrs: Mutex::new(Vec::new()),
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            closed: Condvar::new(),
+            started: Instant::now(),
+        }
     }
 
-    pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+    /// Accepts connections on `listener` and passes them to `run`, until the
+    /// server shuts down.
+    ///
+    /// Errors that won't go away by accepting again right away, such as running
+    /// out of file descriptors, are retried after a growing wait rather than in
+    /// a busy loop.
+    fn accept(&self, listener: TcpListener, streams: Sender<TcpStream>) {
+        let mut backoff = MIN_ACCEPT_BACKOFF;
         for stream in listener.incoming() {
+            if self.requested.load(Ordering::SeqCst) {
+                break;
+            }
             match stream {
                 Ok(stream) => {
-                    let engine = self.engine.clone();
-                    self.pool.spawn(move || {
-                        if let Err(e) = handle_client(engine, stream) {
-                            error!("Error handling client: {}", e);
-                        }
-                    })
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    if streams.send(stream).is_err() {
+                        break;
+                    }
+                }
+                // Problems with that one connection, which the next one
+                // doesn't have.
+                Err(e) if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted
+                ) => debug!("Connection failed: {}", e),
+                Err(e) => {
+                    error!("Connection failed: {}, retrying in {:?}", e, backoff);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
                 }
-                Err(e) => error!("Connection failed: {}", e),
             }
         }
-        Ok(())
+    }
+
+    /// Closes the connections that have been idle for `timeout`, until the
+    /// server shuts down.
+    fn close_idle_connections(&self, timeout: Duration) {
+        let interval = (timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        while !self.requested.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+            for connection in self.connections.lock().unwrap().values() {
+                if connection.activity.idle() >= timeout {
+                    // Closing the read half ends the connection once the
+                    // request being handled, if any, has been answered.
+                    if let Ok(peer_addr) = connection.stream.peer_addr() {
+                        debug!("Closing idle connection from {}", peer_addr);
+                    }
+                    let _ = connection.stream.shutdown(Shutdown::Read);
+                }
+            }
+        }
+    }
+
+    /// Logs the server's stats every `interval`, until the server shuts down.
+    fn log_stats(&self, interval: Duration, requests: &AtomicU64) {
+        let mut last = requests.load(Ordering::Relaxed);
+        loop {
+            thread::sleep(interval);
+            if self.requested.load(Ordering::SeqCst) {
+                break;
+            }
+            let total = requests.load(Ordering::Relaxed);
+            let open = self.connections.lock().unwrap().len();
+            info!(
+                "{} connections open, {} requests handled, {} in the last {:?}",
+                open,
+                total,
+                total - last,
+                interval
+            );
+            last = total;
+        }
     }
 }
 
-fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
-    let req_stream = serde_json::Deserializer::from_reader(reader).into_iter::<Request>();
+impl Activity {
+    fn touch(&self) {
+        let millis = self.since.elapsed().as_millis() as u64;
+        self.last_millis.store(millis, Ordering::Relaxed);
+    }
 
-    for req in req_stream {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", stream.peer_addr()?, req);
-        let resp = match req {
-            Request::Get { key } => match engine.get(key) {
-                Ok(value) => Response::Ok(value),
-                Err(e) => Response::Err(e.to_string()),
-            },
-            Request::Set { key, value } => match engine.set(key, value) {
-                Ok(_) => Response::Ok(None),
-                Err(e) => Response::Err(e.to_string()),
-            },
-            Request::Remove { key } => match engine.remove(key) {
-                Ok(_) => Response::Ok(None),
-                Err(e) => Response::Err(e.to_string()),
-            },
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_millis.load(Ordering::Relaxed));
+        self.since.elapsed().saturating_sub(last)
+    }
+}
+
+/// An open connection, which is forgotten when dropped.
+struct Connection {
+    state: Arc<ServerState>,
+    id: u64,
+    activity: Activity,
+}
+
+impl Connection {
+    fn open(state: &Arc<ServerState>, stream: &TcpStream) -> io::Result<Self> {
+        let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = stream.try_clone()?;
+        let activity = Activity {
+            since: state.started,
+            last_millis: Arc::default(),
         };
-        serde_json::to_writer(&mut writer, &resp)?;
-        writer.flush()?;
-        debug!("Response sent to {}: {:?}", stream.peer_addr()?, resp);
+        activity.touch();
+        let mut connections = state.connections.lock().unwrap();
+        // A shutdown may have started since the connection was accepted.
+        if state.requested.load(Ordering::SeqCst) {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let open = OpenConnection {
+            stream,
+            activity: activity.clone(),
+        };
+        connections.insert(id, open);
+        Ok(Connection {
+            state: state.clone(),
+            id,
+            activity,
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.state.connections.lock().unwrap().remove(&self.id);
+        self.state.closed.notify_all();
+    }
+}
+
+/// Tells a client that the server can't take its connection, and hangs up.
+fn reject(context: &Context, mut stream: TcpStream, message: &str) {
+    let result = match context.protocol {
+        WireProtocol::Json => {
+            let resp = Response::Err(message.to_owned());
+            serde_json::to_writer(&mut stream, &resp).map_err(io::Error::from)
+        }
+        WireProtocol::Resp => resp::write_error(&mut stream, message),
+        #[cfg(feature = "http")]
+        WireProtocol::Http => http::write_response(&mut stream, 503, message, true),
+    };
+    if let Err(e) = result {
+        debug!("Error refusing connection: {}", e);
+    }
+}
+
+fn handle_client<E: KvsEngine>(
+    engine: E,
+    context: &Context,
+    stream: TcpStream,
+    activity: Option<Activity>,
+) -> Result<()> {
+    let peer = stream.peer_addr()?.to_string();
+    stream.set_nodelay(context.nodelay)?;
+    stream.set_read_timeout(context.read_timeout)?;
+    serve(engine, context, &peer, &stream, &stream, activity)
+}
+
+/// Serves the requests read from `reader` until it ends, writing the responses
+/// to `writer`. `peer` identifies the client in the logs, and `activity` is
+/// touched whenever data is received.
+fn serve<E: KvsEngine, R: Read, W: Write>(
+    engine: E,
+    context: &Context,
+    peer: &str,
+    reader: R,
+    writer: W,
+    activity: Option<Activity>,
+) -> Result<()> {
+    let request_bytes = Rc::new(Cell::new(0));
+    let reader = LimitedReader {
+        inner: BufReader::with_capacity(context.read_buffer_size, reader),
+        read: request_bytes.clone(),
+        limit: context.max_request_size,
+        activity,
+    };
+    let mut authenticated = context.auth_token.is_none();
+    let mut writer = BufWriter::with_capacity(context.write_buffer_size, writer);
+
+    match context.protocol {
+        WireProtocol::Json => {
+            // Requests are read as JSON values first, so that a value that
+            // isn't a request is answered with an error without losing track
+            // of where the next one starts.
+            let mut reader = reader;
+            loop {
+                let values = serde_json::Deserializer::from_reader(&mut reader).into_iter::<Value>();
+                let mut error = None;
+                for value in values {
+                    let value = match value {
+                        Ok(value) => value,
+                        Err(e) => {
+                            error = Some(e);
+                            break;
+                        }
+                    };
+                    request_bytes.set(0);
+                    let resp = match serde_json::from_value(value) {
+                        Ok(req) => handle_request(&engine, context, peer, &mut authenticated, req),
+                        Err(e) => malformed_request(peer, &e),
+                    };
+                    serde_json::to_writer(&mut writer, &resp)?;
+                    writer.flush()?;
+                    debug!("Response sent to {}: {:?}", peer, resp);
+                }
+                let Some(e) = error else {
+                    break;
+                };
+                // Say why before hanging up; the rest of the request is
+                // never read.
+                if request_bytes.get() > context.max_request_size {
+                    let resp = Response::Err(too_large(context.max_request_size).to_string());
+                    serde_json::to_writer(&mut writer, &resp)?;
+                    writer.flush()?;
+                    return Err(e.into());
+                }
+                if !e.is_syntax() {
+                    return Err(e.into());
+                }
+                // Not JSON at all, so there is no telling where it ends, and
+                // a brace inside it may start a request the client never
+                // meant to send. Skip the rest of the line instead.
+                serde_json::to_writer(&mut writer, &malformed_request(peer, &e))?;
+                writer.flush()?;
+                if !skip_to_next_request(&mut reader)? {
+                    break;
+                }
+                request_bytes.set(0);
+            }
+        }
+        WireProtocol::Resp => {
+            let mut reader = reader;
+            loop {
+                let command = match resp::read_command(&mut reader) {
+                    Ok(Some(command)) => command,
+                    Ok(None) => break,
+                    // Like Redis, report a malformed command and hang up, as
+                    // there is no telling where the next one starts.
+                    Err(KvsError::StringError(e)) => {
+                        resp::write_error(&mut writer, &format!("Protocol error: {}", e))?;
+                        writer.flush()?;
+                        return Err(KvsError::StringError(e));
+                    }
+                    Err(e) => return Err(e),
+                };
+                request_bytes.set(0);
+                resp::handle_command(command, &mut writer, |req| {
+                    handle_request(&engine, context, peer, &mut authenticated, req)
+                })?;
+                writer.flush()?;
+            }
+        }
+        #[cfg(feature = "http")]
+        WireProtocol::Http => {
+            let mut reader = reader;
+            loop {
+                let request = match http::read_request(&mut reader) {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(KvsError::StringError(e)) => {
+                        http::write_response(&mut writer, 400, &e, true)?;
+                        writer.flush()?;
+                        return Err(KvsError::StringError(e));
+                    }
+                    Err(e) => return Err(e),
+                };
+                request_bytes.set(0);
+                let close = request.close;
+                http::handle_request(request, &mut writer, |req| {
+                    handle_request(&engine, context, peer, &mut authenticated, req)
+                })?;
+                writer.flush()?;
+                if close {
+                    break;
+                }
+            }
+        }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Returns the response to a JSON message from `peer` that isn't a request.
+fn malformed_request(peer: &str, e: &serde_json::Error) -> Response {
+    debug!("Malformed request from {}: {}", peer, e);
+    Response::Err(format!("Malformed request: {}", e))
+}
+
+/// Discards bytes up to and including the next newline, which can't be part
+/// of a JSON string, returning `false` if the stream ends first.
+fn skip_to_next_request<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(true);
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Handles a single request from `peer`, whichever protocol it came in.
+fn handle_request<E: KvsEngine>(
+    engine: &E,
+    context: &Context,
+    peer: &str,
+    authenticated: &mut bool,
+    req: Request,
+) -> Response {
+    let req = match req {
+        Request::WithId { client, id, request } if *authenticated => {
+            return context.replies.get_or_run(client, id, *request, |request| {
+                handle_request(engine, context, peer, authenticated, request)
+            });
+        }
+        // Not cached, so that a retry after authenticating runs the request.
+        Request::WithId { request, .. } => *request,
+        req => req,
+    };
+    context.requests.fetch_add(1, Ordering::Relaxed);
+    let metrics = &context.metrics;
+    let access_log = context.access_log.as_deref();
+    match &req {
+        // Keep the token out of the logs.
+        Request::Auth { .. } => debug!("Receive auth request from {}", peer),
+        req => debug!("Receive request from {}: {:?}", peer, req),
+    }
+    let access_entry = access_log.map(|_| access_entry(peer, &req));
+    let start = Instant::now();
+    let op = match req {
+        Request::Get { .. } => Some(Op::Get),
+        Request::GetMany { .. } => Some(Op::GetMany),
+        Request::Set { .. } => Some(Op::Set),
+        Request::Remove { .. } => Some(Op::Remove),
+        Request::Contains { .. } => Some(Op::Contains),
+        Request::Ping
+        | Request::Metrics
+        | Request::ServerInfo
+        | Request::Health
+        | Request::Auth { .. }
+        | Request::WithId { .. } => None,
+    };
+    let resp = match req {
+        Request::Auth { token } => {
+            *authenticated = context.auth_token.as_ref().is_none_or(|expected| {
+                constant_time_eq(expected.as_bytes(), token.as_bytes())
+            });
+            if *authenticated {
+                Response::Ok(None)
+            } else {
+                Response::Err(INVALID_TOKEN.to_owned())
+            }
+        }
+        Request::Ping => Response::Ok(None),
+        Request::Health => {
+            let engine_ok = match engine.check() {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Engine check failed: {}", e);
+                    false
+                }
+            };
+            Response::Health(Health {
+                ready: !engine.is_busy(),
+                engine_ok,
+            })
+        }
+        _ if !*authenticated => Response::Err(AUTH_REQUIRED.to_owned()),
+        Request::Get { key } => match engine.get(key) {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::GetMany { keys } => match engine.get_many(keys) {
+            Ok(values) => Response::Values(values),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Set { key, value } => match context
+            .size_limits
+            .check(&key, value.len())
+            .and_then(|()| engine.set(key, value))
+        {
+            Ok(_) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Remove { key } => match engine.remove(key) {
+            Ok(_) => Response::Ok(None),
+            Err(KvsError::KeyNotFound) => Response::KeyNotFound,
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Contains { key } => match engine.contains_key(key) {
+            Ok(exists) => Response::Bool(exists),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Metrics => Response::Ok(Some(metrics.render())),
+        Request::ServerInfo => Response::ServerInfo(ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            engine: engine.name().to_owned(),
+            uptime_secs: context.started.elapsed().as_secs(),
+            total_requests: context.requests.load(Ordering::Relaxed),
+        }),
+        Request::WithId { .. } => unreachable!("unwrapped above"),
+    };
+    let elapsed = start.elapsed();
+    let failed = !matches!(
+        resp,
+        Response::Ok(_)
+            | Response::Values(_)
+            | Response::ServerInfo(_)
+            | Response::Bool(_)
+            | Response::Health(_)
+    );
+    if let Some(op) = op {
+        metrics.record(op, elapsed, failed);
+    }
+    if let (Some(access_log), Some(entry)) = (access_log, access_entry) {
+        let status = if failed { "err" } else { "ok" };
+        access_log.write(&format!("{} {} {}us", entry, status, elapsed.as_micros()));
+    }
+    resp
+}
+
+/// A reader that fails once more than `limit` bytes have been read since the
+/// shared counter was last reset, which the server does after every request.
+/// It also records when data was last received.
+struct LimitedReader<R> {
+    inner: R,
+    read: Rc<Cell<u64>>,
+    limit: u64,
+    activity: Option<Activity>,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+        let read = self.read.get() + n as u64;
+        self.read.set(read);
+        if read > self.limit {
+            return Err(too_large(self.limit));
+        }
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for LimitedReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.read.get() > self.limit {
+            return Err(too_large(self.limit));
+        }
+        let buf = self.inner.fill_buf()?;
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+        Ok(buf)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.read.set(self.read.get() + amt as u64);
+        self.inner.consume(amt);
+    }
+}
+
+/// Sets the backlog of a listening socket by calling `listen` on it again,
+/// which updates the backlog `TcpListener::bind` gave it.
+#[cfg(unix)]
+fn set_backlog(listener: &TcpListener, backlog: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let backlog = backlog.min(libc::c_int::MAX as u32) as libc::c_int;
+    // SAFETY: `listen` only takes the descriptor of a socket we own and an
+    // integer, and reports failure through its return value.
+    if unsafe { libc::listen(listener.as_raw_fd(), backlog) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_backlog(_listener: &TcpListener, _backlog: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn too_large(limit: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Request exceeds the limit of {} bytes", limit),
+    )
+}
+
+/// Returns the part of an access log line that describes the request.
+fn access_entry(peer: &str, req: &Request) -> String {
+    let (op, key) = match req {
+        Request::Get { key } => ("get", key.as_str()),
+        Request::GetMany { keys } => return format!("{} mget {}", peer, keys.join(",")),
+        Request::WithId { request, .. } => return access_entry(peer, request),
+        Request::Set { key, .. } => ("set", key.as_str()),
+        Request::Remove { key } => ("rm", key.as_str()),
+        Request::Contains { key } => ("contains", key.as_str()),
+        Request::Ping => ("ping", "-"),
+        Request::Metrics => ("metrics", "-"),
+        Request::ServerInfo => ("info", "-"),
+        Request::Health => ("health", "-"),
+        Request::Auth { .. } => ("auth", "-"),
+    };
+    format!("{} {} {}", peer, op, key)
+}
+
+/// Returns whether an error just means that the client went away, possibly in
+/// the middle of a request, rather than that something went wrong.
+fn is_disconnect(err: &KvsError) -> bool {
+    let kind = match err {
+        KvsError::Io(e) => Some(e.kind()),
+        KvsError::Serde(e) if e.is_eof() => return true,
+        KvsError::Serde(e) => e.io_error_kind(),
+        _ => None,
+    };
+    matches!(
+        kind,
+        Some(
+            io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+        )
+    )
+}
+
+/// Returns whether `err` is a read on a connection that timed out.
+fn is_timeout(err: &KvsError) -> bool {
+    let kind = match err {
+        KvsError::Io(e) => Some(e.kind()),
+        KvsError::Serde(e) => e.io_error_kind(),
+        _ => None,
+    };
+    matches!(kind, Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so
+/// that a token can't be guessed byte by byte from response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -1,13 +1,26 @@
 use crate::Result;
+use clap::ValueEnum;
 
 mod naive;
 mod shared_queue;
 mod rayon;
 
 pub use naive::NaiveThreadPool;
-pub use shared_queue::SharedQueueThreadPool;
+pub use shared_queue::{PanicHandler, PoolMetrics, SharedQueueThreadPool};
 pub use rayon::RayonThreadPool;
 
+/// The thread pool implementations, for choosing one at runtime.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThreadPoolKind {
+    /// `NaiveThreadPool`
+    Naive,
+    /// `SharedQueueThreadPool`
+    SharedQueue,
+    /// `RayonThreadPool`
+    #[default]
+    Rayon,
+}
+
 /// Trait for a thread pool.
 pub trait ThreadPool {
     /// Create a new thread pool.
@@ -19,4 +32,10 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Returns whether `spawn` would block because the pool's queue of jobs
+    /// is full. Pools with unbounded queues never are, which is the default.
+    fn is_full(&self) -> bool {
+        false
+    }
 }
\ No newline at end of file
@@ -1,22 +1,76 @@
 use super::ThreadPool;
 use crate::Result;
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
 /// It is actually not a thread pool. It spawns a new thread every time.
-pub struct NaiveThreadPool;
+///
+/// At most `threads` jobs run at once: `spawn` blocks until one of the
+/// running jobs has finished when the limit is reached.
+pub struct NaiveThreadPool {
+    permits: Arc<Semaphore>,
+}
+
+/// A minimal counting semaphore limiting the number of running threads.
+struct Semaphore {
+    available: Mutex<u32>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn is_exhausted(&self) -> bool {
+        *self.available.lock().unwrap() == 0
+    }
+
+    fn acquire(&self) {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+    }
+
+    fn release(&self) {
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+/// Returns its permit to the semaphore when dropped, even if the job panicked.
+struct Permit(Arc<Semaphore>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_threads: u32) -> Result<Self>
+    fn new(threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
-        Ok(NaiveThreadPool)
+        let permits = Arc::new(Semaphore {
+            available: Mutex::new(threads.max(1)),
+            released: Condvar::new(),
+        });
+        Ok(NaiveThreadPool { permits })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        self.permits.acquire();
+        let permit = Permit(Arc::clone(&self.permits));
+        thread::spawn(move || {
+            let _permit = permit;
+            job();
+        });
+    }
+
+    /// Returns `true` while `threads` jobs are running, as `spawn` then blocks.
+    fn is_full(&self) -> bool {
+        self.permits.is_exhausted()
     }
-}
\ No newline at end of file
+}
@@ -1,20 +1,54 @@
 use super::ThreadPool;
 use crate::{KvsError, Result};
+use log::error;
+use std::any::Any;
+use std::sync::Arc;
 
+/// A thread pool backed by a local `rayon` thread pool.
+///
+/// Panics in spawned jobs are passed to a panic handler instead of aborting the process.
+///
+/// Clones share the same threads, so that several servers in one process can
+/// run on a single pool. Each open connection occupies a thread, so a shared
+/// pool needs as many threads as the servers have connections together.
+#[derive(Clone)]
 pub struct RayonThreadPool {
-    pool: rayon::ThreadPool,
+    pool: Arc<rayon::ThreadPool>,
 }
 
-impl ThreadPool for RayonThreadPool {
-    fn new(threads: u32) -> Result<Self>
+impl RayonThreadPool {
+    /// Creates a pool with the given number of threads, naming each thread
+    /// `{thread_name_prefix}{index}` and calling `panic_handler` with the payload
+    /// of every job that panics.
+    pub fn with_config<H>(threads: u32, thread_name_prefix: &str, panic_handler: H) -> Result<Self>
     where
-        Self: Sized,
+        H: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
     {
+        let prefix = thread_name_prefix.to_owned();
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
+            .thread_name(move |index| format!("{prefix}{index}"))
+            .panic_handler(panic_handler)
             .build()
             .map_err(|e| KvsError::StringError(e.to_string()))?;
-        Ok(RayonThreadPool { pool })
+        Ok(RayonThreadPool { pool: Arc::new(pool) })
+    }
+
+    /// Returns the number of threads in the pool.
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+}
+
+impl ThreadPool for RayonThreadPool {
+    /// Creates a pool with `kvs-rayon-{index}` thread names that logs panicking jobs.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        RayonThreadPool::with_config(threads, "kvs-rayon-", |e| {
+            error!("Rayon worker panicked: {:?}", e);
+        })
     }
 
     fn spawn<F>(&self, job: F)
@@ -23,4 +57,4 @@ impl ThreadPool for RayonThreadPool {
     {
         self.pool.spawn(job);
     }
-}
\ No newline at end of file
+}
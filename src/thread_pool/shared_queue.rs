@@ -1,19 +1,51 @@
 use super::ThreadPool;
 use crate::Result;
 use crossbeam_channel::{self, Receiver, Sender};
-use log::{debug, error};
+use log::{debug, error, warn};
+use std::any::Any;
 use std::panic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
 /// Type alias for a job that can be sent to the thread pool.
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Callback invoked with the worker id and the panic payload when a job panics.
+pub type PanicHandler = Arc<dyn Fn(usize, &(dyn Any + Send)) + Send + Sync>;
+
 /// Enum representing messages sent to worker threads.
 enum Message {
     NewJob(Job),
     Terminate,
 }
 
+/// State shared between the pool and all of its workers.
+struct Shared {
+    receiver: Receiver<Message>,
+    exited: Sender<usize>,
+    /// Number of jobs that have been spawned but have not finished yet.
+    pending: Mutex<usize>,
+    /// Notified whenever `pending` drops to zero.
+    idle: Condvar,
+    /// Number of workers currently executing a job.
+    busy: AtomicUsize,
+    /// Number of jobs that have finished executing.
+    processed: AtomicUsize,
+    panic_handler: Mutex<Option<PanicHandler>>,
+}
+
+impl Shared {
+    fn job_done(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.idle.notify_all();
+        }
+    }
+}
+
 /// Represents a single worker thread in the thread pool.
 struct Worker {
     id: usize,
@@ -23,51 +55,196 @@ struct Worker {
 impl Worker {
     /// Creates a new worker thread that listens for jobs on the provided receiver.
     ///
-    /// The worker thread will continuously try to receive messages from the `receiver`.
+    /// The worker thread will continuously try to receive messages from the shared receiver.
     /// If it receives a `NewJob`, it executes it.
-    /// If it receives a `Terminate` message, it breaks its loop and exits.
-    fn new(id: usize, receiver: Receiver<Message>) -> Self {
-        let thread = thread::spawn(move || {
+    /// If it receives a `Terminate` message, it breaks its loop, reports its id
+    /// on the shared `exited` channel and exits.
+    ///
+    /// The thread is named `kvs-worker-{id}` so it can be told apart in thread dumps.
+    fn new(id: usize, shared: Arc<Shared>) -> Result<Self> {
+        let thread = thread::Builder::new().name(format!("kvs-worker-{id}")).spawn(move || {
             loop {
-                let message = receiver.recv();
+                let message = shared.receiver.recv();
 
                 match message {
                     Ok(Message::NewJob(job)) => {
                         debug!("Worker {} got a job; executing.", id);
+                        shared.busy.fetch_add(1, Ordering::SeqCst);
                         // Catch panics from the job to prevent the worker thread from crashing.
                         if let Err(e) = panic::catch_unwind(panic::AssertUnwindSafe(job)) {
                             error!("Worker {} panicked: {:?}", id, e);
+                            let handler = shared.panic_handler.lock().unwrap().clone();
+                            // A panicking handler mustn't take the worker down with it
+                            // before the job is accounted for.
+                            if let Some(handler) = handler
+                                && panic::catch_unwind(panic::AssertUnwindSafe(|| handler(id, e.as_ref())))
+                                    .is_err()
+                            {
+                                error!("Panic handler of worker {} panicked", id);
+                            }
                         }
+                        shared.busy.fetch_sub(1, Ordering::SeqCst);
+                        shared.processed.fetch_add(1, Ordering::SeqCst);
+                        shared.job_done();
                     }
                     Ok(Message::Terminate) | Err(_) => {
                         debug!("Worker {} was told to terminate.", id);
+                        shared.exited.send(id).ok();
                         break;
                     }
                 }
             }
-        });
-        Worker { id, thread: Some(thread) }
+        })?;
+        Ok(Worker { id, thread: Some(thread) })
     }
 }
 
+/// A point-in-time view of the load on a `SharedQueueThreadPool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Number of jobs waiting in the queue.
+    pub queued: usize,
+    /// Number of workers currently executing a job.
+    pub busy: usize,
+    /// Total number of jobs that have finished executing.
+    pub processed: usize,
+}
+
 /// A thread pool that uses a shared queue inside.
 pub struct SharedQueueThreadPool {
-    workers: Vec<Worker>,
+    workers: Mutex<Vec<Worker>>,
     sender: Sender<Message>,
+    shared: Arc<Shared>,
+    exited_receiver: Receiver<usize>,
+    next_id: AtomicUsize,
+    /// How long dropping the pool waits for the workers, or `None` to wait
+    /// for as long as it takes.
+    shutdown_timeout: Option<Duration>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    /// Creates a new `SharedQueueThreadPool` with the specified number of threads.
-    fn new(size: u32) -> Result<Self> {
-        let (sender, receiver) = crossbeam_channel::unbounded();
+impl SharedQueueThreadPool {
+    /// Grows or shrinks the pool to the given number of worker threads.
+    ///
+    /// Growing spawns new workers right away. Shrinking sends one `Terminate`
+    /// message per surplus worker and blocks until that many workers have
+    /// exited and been joined, so jobs queued before the call are still run.
+    pub fn resize(&self, threads: u32) -> Result<()> {
+        let mut workers = self.workers.lock().unwrap();
+        let target = threads as usize;
+
+        while workers.len() < target {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            workers.push(Worker::new(id, Arc::clone(&self.shared))?);
+        }
+
+        let surplus = workers.len() - target;
+        for _ in 0..surplus {
+            self.sender.send(Message::Terminate).expect("The thread pool is dead.");
+        }
+        for _ in 0..surplus {
+            let id = self.exited_receiver.recv().expect("The thread pool is dead.");
+            if let Some(index) = workers.iter().position(|worker| worker.id == id) {
+                let mut worker = workers.swap_remove(index);
+                if let Some(thread) = worker.thread.take() {
+                    debug!("Shutting down worker {}", worker.id);
+                    thread.join().unwrap();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets a handler that is called inside the worker whenever a job panics.
+    ///
+    /// The handler receives the id of the worker that ran the job and the panic payload.
+    pub fn with_panic_handler<H>(self, handler: H) -> Self
+    where
+        H: Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static,
+    {
+        *self.shared.panic_handler.lock().unwrap() = Some(Arc::new(handler));
+        self
+    }
+
+    /// Limits how long dropping the pool waits for the workers to finish the
+    /// jobs they are running and the ones still queued.
+    ///
+    /// Workers that haven't finished by then are detached and left running,
+    /// and a warning with the number of abandoned jobs is logged, so that a
+    /// stuck job can't hang the shutdown. By default dropping the pool waits
+    /// for every job.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Blocks until every job spawned so far has finished running.
+    ///
+    /// Unlike dropping the pool, this leaves all workers alive so the pool can
+    /// keep accepting jobs afterwards.
+    pub fn join(&self) {
+        let mut pending = self.shared.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = self.shared.idle.wait(pending).unwrap();
+        }
+    }
+
+    /// Returns the current queue depth and worker activity of the pool.
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            queued: self.sender.len(),
+            busy: self.shared.busy.load(Ordering::SeqCst),
+            processed: self.shared.processed.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Returns the current number of worker threads.
+    pub fn size(&self) -> usize {
+        self.workers.lock().unwrap().len()
+    }
+
+    /// Creates a pool with the specified number of threads whose queue holds
+    /// at most `capacity` jobs waiting for a worker, at least one.
+    ///
+    /// Once the queue is full, `spawn` blocks until a worker takes a job from
+    /// it, and `is_full` returns `true`.
+    pub fn with_queue_capacity(size: u32, capacity: usize) -> Result<Self> {
+        SharedQueueThreadPool::build(size, crossbeam_channel::bounded(capacity.max(1)))
+    }
+
+    fn build(size: u32, (sender, receiver): (Sender<Message>, Receiver<Message>)) -> Result<Self> {
+        let (exited, exited_receiver) = crossbeam_channel::unbounded();
+        let shared = Arc::new(Shared {
+            receiver,
+            exited,
+            pending: Mutex::new(0),
+            idle: Condvar::new(),
+            busy: AtomicUsize::new(0),
+            processed: AtomicUsize::new(0),
+            panic_handler: Mutex::new(None),
+        });
 
         let mut workers = Vec::with_capacity(size as usize);
 
         for id in 0..size {
-            workers.push(Worker::new(id as usize, receiver.clone()));
+            workers.push(Worker::new(id as usize, Arc::clone(&shared))?);
         }
 
-        Ok(SharedQueueThreadPool { workers, sender })
+        Ok(SharedQueueThreadPool {
+            workers: Mutex::new(workers),
+            sender,
+            shared,
+            exited_receiver,
+            next_id: AtomicUsize::new(size as usize),
+            shutdown_timeout: None,
+        })
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    /// Creates a new `SharedQueueThreadPool` with the specified number of
+    /// threads and an unbounded queue.
+    fn new(size: u32) -> Result<Self> {
+        SharedQueueThreadPool::build(size, crossbeam_channel::unbounded())
     }
 
     /// Spawns a new job onto the thread pool.
@@ -76,26 +253,68 @@ impl ThreadPool for SharedQueueThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(job);
+        *self.shared.pending.lock().unwrap() += 1;
         self.sender.send(Message::NewJob(job)).expect("The thread pool is dead.");
     }
+
+    fn is_full(&self) -> bool {
+        self.sender.is_full()
+    }
 }
 
 /// Implements graceful shutdown for the thread pool.
 ///
 /// When the `SharedQueueThreadPool` goes out of scope, its `drop` method is called.
 /// This method sends `Terminate` messages to all workers and then waits for
-/// each worker thread to finish its execution.
+/// each worker thread to finish its execution, or for the shutdown timeout
+/// to pass if one was set with `with_shutdown_timeout`.
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
         debug!("Sending terminate message to all workers.");
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).ok();
+        let deadline = self.shutdown_timeout.map(|timeout| (timeout, Instant::now() + timeout));
+        let workers = self.workers.get_mut().unwrap();
+        for _ in workers.iter() {
+            // A full bounded queue mustn't hold the shutdown past its deadline.
+            // Workers that miss their `Terminate` still exit once the queue is
+            // drained, as the sender is gone by then.
+            let sent = match deadline {
+                Some((_, deadline)) => self.sender.send_deadline(Message::Terminate, deadline).is_ok(),
+                None => self.sender.send(Message::Terminate).is_ok(),
+            };
+            if !sent {
+                break;
+            }
         }
 
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                debug!("Shutting down worker {}", worker.id);
-                thread.join().unwrap();
+        let Some((timeout, deadline)) = deadline else {
+            for worker in workers.iter_mut() {
+                if let Some(thread) = worker.thread.take() {
+                    debug!("Shutting down worker {}", worker.id);
+                    thread.join().unwrap();
+                }
+            }
+            return;
+        };
+        // Join the workers in the order they exit, so that a stuck one
+        // doesn't keep the others from being joined in time.
+        while !workers.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(id) = self.exited_receiver.recv_timeout(remaining) else {
+                let pending = *self.shared.pending.lock().unwrap();
+                warn!(
+                    "Abandoning {} workers and {} unfinished jobs after waiting {:?}",
+                    workers.len(),
+                    pending,
+                    timeout
+                );
+                return;
+            };
+            if let Some(index) = workers.iter().position(|worker| worker.id == id) {
+                let mut worker = workers.swap_remove(index);
+                if let Some(thread) = worker.thread.take() {
+                    debug!("Shutting down worker {}", worker.id);
+                    thread.join().unwrap();
+                }
             }
         }
     }
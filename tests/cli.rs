@@ -2,7 +2,7 @@ use assert_cmd::cargo_bin;
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,25 +20,25 @@ fn client_cli_no_args() {
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -48,31 +48,31 @@ fn client_cli_invalid_get() {
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -82,25 +82,25 @@ fn client_cli_invalid_set() {
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -110,7 +110,7 @@ fn client_cli_invalid_rm() {
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -121,7 +121,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::new(cargo_bin!("kvs-client"));
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -132,7 +132,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::new(cargo_bin!("kvs-server"));
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -144,13 +144,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::new(cargo_bin!("kvs-server"));
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -159,21 +160,23 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::new(cargo_bin!("kvs-server"));
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::new(cargo_bin!("kvs-server"));
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -184,15 +187,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::new(cargo_bin!("kvs-server"));
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::new(cargo_bin!("kvs-server"));
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -204,67 +208,68 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::new(cargo_bin!("kvs-server"));
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout("value1\n");
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout("value2\n");
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("Key not found"));
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
         .stderr(contains("Key not found"));
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -277,24 +282,25 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::new(cargo_bin!("kvs-server"));
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::new(cargo_bin!("kvs-client"))
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -309,6 +315,306 @@ fn cli_access_server_kvs_engine() {
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `cargo test --no-default-features` checks the build without sled.
+#[test]
+#[cfg(not(feature = "sled"))]
+fn cli_sled_engine_unavailable() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4005"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid value 'sled'"));
+}
+
+#[test]
+fn cli_exec_file() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let commands_path = temp_dir.path().join("commands.txt");
+    fs::write(
+        &commands_path,
+        "# load some keys\n\
+         set key1 value1\n\
+         set key2 value with spaces\n\
+         get key1\n\
+         rm key1\n\
+         rm key3\n\
+         bogus\n\
+         get key2\n",
+    )
+    .unwrap();
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["exec", "--file", commands_path.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout("value1\nvalue with spaces\n")
+        .stderr(contains("5 succeeded, 2 failed"));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value with spaces\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_json_output() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr, "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"value\":\"value1\"}\n");
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key2", "--addr", addr, "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"value\":null}\n");
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key2", "--addr", addr, "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr("{\"error\":\"Key not found\"}\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_local_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+        store.set("user:2".to_owned(), "bob".to_owned()).unwrap();
+        store.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+        store.set("group:1".to_owned(), "admins".to_owned()).unwrap();
+    }
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["scan", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\talice\nuser:2\tbob\n");
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("group:1\tadmins\nuser:1\talice\nuser:2\tbob\n");
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["rm", "group:1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["scan", "group:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+}
+
+#[test]
+fn cli_local_compact_and_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+        for i in 0..100 {
+            store.set("key1".to_owned(), format!("value{}", i)).unwrap();
+        }
+    }
+    let log_size = || fs::metadata(temp_dir.path().join("wal.log")).unwrap().len();
+    let size_before = log_size();
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys: 1"))
+        .stdout(contains(format!("total log bytes: {}", size_before)));
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(log_size() < size_before);
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("stale bytes: 0"));
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value99\n");
+}
+
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("server.toml");
+    fs::write(&config, "addr = \"127.0.0.1:4008\"\nengine = \"kvs\"\nthreads = 2\npool = \"shared-queue\"\n").unwrap();
+    // The address on the command line wins over the one in the file.
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::new(cargo_bin!("kvs-server"))
+        .args(["--config", config.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    let engine = fs::read_to_string(temp_dir.path().join(".engine")).unwrap();
+    assert_eq!(engine, "kvs");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_invalid_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("server.toml");
+    fs::write(&config, "threads = \"eight\"\n").unwrap();
+    Command::new(cargo_bin!("kvs-server"))
+        .args(["--config", config.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("server.toml"));
+}
+
+#[test]
+fn cli_queue_capacity_needs_shared_queue() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::new(cargo_bin!("kvs-server"))
+        .args(["--pool", "rayon", "--queue-capacity", "10"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("shared-queue"));
+}
+
+#[test]
+fn cli_log_file() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let log_file = temp_dir.path().join("kvs.log");
+    let mut server = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--log-file", log_file.to_str().unwrap()])
+        .args(["--log-file-max-size", "100"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    server.kill().expect("server exited before killed");
+    let output = server.wait_with_output().expect("failed to wait on server");
+
+    // Every line is over half the maximum size, so each one rotates the file.
+    assert!(output.stderr.is_empty());
+    let current = fs::read_to_string(&log_file).unwrap();
+    assert!(current.contains("Listening on 127.0.0.1:4010"), "log file: {}", current);
+    let rotated = fs::read_to_string(temp_dir.path().join("kvs.log.1")).unwrap();
+    assert!(rotated.contains("Thread pool"), "rotated log file: {}", rotated);
+    let oldest = fs::read_to_string(temp_dir.path().join("kvs.log.3")).unwrap();
+    assert!(oldest.contains("kvs-server 0.1.0"), "oldest log file: {}", oldest);
+}
+
+#[test]
+fn cli_stats_interval() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let log_file = temp_dir.path().join("kvs.log");
+    let mut server = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--log-file", log_file.to_str().unwrap()])
+        .args(["--stats-interval", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    for i in 0..3 {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(["set", &format!("key{}", i), "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_millis(2500));
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+
+    // The requests may have been split over two intervals, but some line after
+    // them has the totals.
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains("0 connections open, 3 requests handled, "), "log file: {}", log);
+    assert!(log.contains(" in the last 1s"), "log file: {}", log);
+}
@@ -0,0 +1,1069 @@
+use assert_cmd::cargo_bin;
+use clap::ValueEnum;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AccessLog, AnyEngine, Engine, FlushMode, Health, KvStore, KvsClient, KvsClientPool, KvsEngine, KvsError,
+    KvsServer, ReconnectPolicy, Result, StoreConfig, WireProtocol,
+};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn wait_for_server(addr: SocketAddr) {
+    for _ in 0..100 {
+        if KvsClient::connect(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server did not start");
+}
+
+// Starts a `KvsServer` on a free local port in a background thread.
+fn start_server(temp_dir: &TempDir, threads: u32) -> SocketAddr {
+    let addr = free_addr();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(threads).unwrap();
+    thread::spawn(move || KvsServer::new(engine, pool).run(addr));
+    wait_for_server(addr);
+    addr
+}
+
+// A `kvs-server` process that is killed when dropped.
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().expect("failed to wait on server");
+    }
+}
+
+// Starts the `kvs-server` binary so that it can be killed and restarted.
+fn spawn_server_process(temp_dir: &TempDir, addr: SocketAddr) -> ServerProcess {
+    let child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", &addr.to_string()])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let server = ServerProcess(child);
+    wait_for_server(addr);
+    server
+}
+
+#[test]
+fn client_pool_concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, 8);
+    let pool = KvsClientPool::connect(addr, 4)?;
+
+    thread::scope(|s| {
+        for thread_id in 0..16 {
+            let pool = &pool;
+            s.spawn(move || {
+                for i in 0..20 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    pool.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(pool.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+                pool.remove(format!("key{}-0", thread_id)).unwrap();
+            });
+        }
+    });
+
+    assert_eq!(pool.get("key0-0".to_owned())?, None);
+    assert_eq!(pool.get("key15-19".to_owned())?, Some("value19".to_owned()));
+    assert_eq!(pool.size(), 4);
+    assert_eq!(pool.connections_opened(), 4);
+    Ok(())
+}
+
+#[test]
+fn client_pool_validates_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, 4);
+    let pool = KvsClientPool::connect(addr, 1)?.with_validation_interval(Duration::ZERO);
+
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(pool.connections_opened(), 1);
+    Ok(())
+}
+
+#[test]
+fn client_reconnects_after_server_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let server = spawn_server_process(&temp_dir, addr);
+
+    let mut client =
+        KvsClient::connect(addr)?.with_reconnect(ReconnectPolicy::new(5, Duration::from_millis(50)));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(server);
+    let _server = spawn_server_process(&temp_dir, addr);
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_without_reconnect_fails_after_server_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let server = spawn_server_process(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(server);
+    let _server = spawn_server_process(&temp_dir, addr);
+
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn client_does_not_retry_a_write_that_may_have_been_applied() -> Result<()> {
+    // A server that reads a request and hangs up without answering.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let requests = Arc::new(AtomicUsize::new(0));
+    let received = Arc::clone(&requests);
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            if stream.read(&mut [0; 1024]).is_ok_and(|n| n > 0) {
+                received.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+
+    let mut client =
+        KvsClient::connect(addr)?.with_reconnect(ReconnectPolicy::new(5, Duration::from_millis(10)));
+    let result = client.set("key1".to_owned(), "value1".to_owned());
+    assert!(matches!(result, Err(KvsError::Connection(_))), "unexpected result: {:?}", result);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // A read is safe to send again.
+    assert!(client.get("key1".to_owned()).is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[test]
+fn client_read_timeout() -> Result<()> {
+    // A server that accepts connections but never responds.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let _streams: Vec<_> = listener.incoming().collect();
+    });
+
+    let mut client = KvsClient::connect_with_timeout(addr, Duration::from_secs(1))?;
+    client.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+    let start = Instant::now();
+    let result = client.get("key1".to_owned());
+    assert!(matches!(result, Err(KvsError::Timeout)), "unexpected result: {:?}", result);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    Ok(())
+}
+
+// A `KvStore` that takes a while to answer reads of keys starting with "slow".
+#[derive(Clone)]
+struct SlowEngine(KvStore);
+
+impl KvsEngine for SlowEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key.starts_with("slow") {
+            thread::sleep(Duration::from_millis(300));
+        }
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+#[test]
+fn late_response_is_not_read_by_the_next_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SlowEngine(KvStore::open(temp_dir.path())?);
+    engine.0.set("slow-a", "value-of-a")?;
+    engine.0.set("b", "value-of-b")?;
+    let addr = free_addr();
+    thread::spawn(move || KvsServer::new(engine, SharedQueueThreadPool::new(2).unwrap()).run(addr));
+    wait_for_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let result = client.get("slow-a".to_owned());
+    assert!(matches!(result, Err(KvsError::Timeout)), "unexpected result: {:?}", result);
+
+    // Let the response to the first request arrive before sending the next.
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.get("b".to_owned())?, Some("value-of-b".to_owned()));
+    assert_eq!(client.get("b".to_owned())?, Some("value-of-b".to_owned()));
+    Ok(())
+}
+
+// Returns the value of the sample named `name` in Prometheus text output.
+fn sample(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("missing sample {}", name))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn server_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, 4);
+    let mut client = KvsClient::connect(addr)?;
+
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.get("key1".to_owned())?;
+    client.get("missing".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("missing".to_owned()).is_err());
+    client.ping()?;
+
+    let metrics = client.metrics()?;
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{op="set"}"#), 3.0);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{op="get"}"#), 2.0);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{op="remove"}"#), 2.0);
+    assert_eq!(sample(&metrics, r#"kvs_errors_total{op="set"}"#), 0.0);
+    assert_eq!(sample(&metrics, r#"kvs_errors_total{op="remove"}"#), 1.0);
+    assert_eq!(sample(&metrics, r#"kvs_request_duration_seconds_bucket{op="set",le="+Inf"}"#), 3.0);
+    assert_eq!(sample(&metrics, r#"kvs_request_duration_seconds_count{op="get"}"#), 2.0);
+    assert!(sample(&metrics, r#"kvs_request_duration_seconds_sum{op="set"}"#) > 0.0);
+    Ok(())
+}
+
+#[test]
+fn server_access_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("access.log");
+    let addr = free_addr();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_access_log(AccessLog::File(log_path.clone()))?;
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+
+    // Each line is written before its response is sent, so the log is complete by now.
+    let log = fs::read_to_string(&log_path)?;
+    let lines: Vec<_> = log
+        .lines()
+        .map(|line| line.split(' ').skip(1).take(3).collect::<Vec<_>>().join(" "))
+        .collect();
+    assert_eq!(lines, ["set key1 ok", "get key1 ok", "rm key1 ok", "rm key1 err"]);
+    assert!(log.lines().all(|line| line.ends_with("us")));
+    Ok(())
+}
+
+#[test]
+fn server_rejects_oversized_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_max_request_size(1024);
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    let mut healthy = KvsClient::connect(addr)?;
+    healthy.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.set("key2".to_owned(), "x".repeat(64 * 1024)).is_err());
+    // The offending connection is closed...
+    assert!(client.get("key1".to_owned()).is_err());
+    // ...while other connections keep working.
+    assert_eq!(healthy.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(healthy.get("key2".to_owned())?, None);
+    // A request just under the limit is still accepted.
+    healthy.set("key3".to_owned(), "x".repeat(900))?;
+    Ok(())
+}
+
+#[test]
+fn server_drops_unterminated_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_max_request_size(1024 * 1024);
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    // A value that never ends, sent until the server hangs up.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set":{"key":"key1","value":""#)?;
+    let chunk = vec![b'x'; 64 * 1024];
+    let mut sent = 0;
+    while stream.write_all(&chunk).is_ok() {
+        sent += chunk.len();
+        assert!(sent <= 1024 * 1024 * 1024, "the server kept reading");
+    }
+
+    let mut reader = serde_json::Deserializer::from_reader(stream.try_clone()?).into_iter::<serde_json::Value>();
+    let response = reader.next().unwrap()?;
+    assert_eq!(response["Err"], "Request exceeds the limit of 1048576 bytes");
+    assert!(reader.next().is_none_or(|next| next.is_err()));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn server_builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let access_log = temp_dir.path().join("access.log");
+    let mut server = KvsServer::builder(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .auth("secret")
+        .max_value_len(5)
+        .read_timeout(Duration::from_millis(200))
+        .access_log(AccessLog::File(access_log.clone()))
+        .build()?;
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.get("key1".to_owned()).is_err());
+    client.authenticate("secret".to_owned())?;
+    client.set("key1".to_owned(), "value".to_owned())?;
+    assert!(client.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("value".to_owned()));
+    assert!(fs::read_to_string(&access_log)?.contains(" set key1 ok "));
+
+    // A request that stalls halfway is cut off by the read timeout.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Get":{"key":"#)?;
+    let start = Instant::now();
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    assert!(buf.is_empty());
+    assert!(start.elapsed() < Duration::from_secs(2), "closed after {:?}", start.elapsed());
+    Ok(())
+}
+
+fn start_server_with_auth(temp_dir: &TempDir, token: &str) -> SocketAddr {
+    let addr = free_addr();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(2).unwrap()).with_auth(token);
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+    addr
+}
+
+#[test]
+fn client_authenticates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_auth(&temp_dir, "secret");
+
+    let mut client = KvsClient::connect(addr)?;
+    client.authenticate("secret".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn unauthenticated_requests_are_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_auth(&temp_dir, "secret");
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    let err = client.set("key1".to_owned(), "value1".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Authentication required");
+
+    let err = client.authenticate("wrong".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid authentication token");
+    assert!(client.get("key1".to_owned()).is_err());
+
+    // The rejected set was not applied.
+    client.authenticate("secret".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn server_with_any_engine() -> Result<()> {
+    for &kind in Engine::value_variants() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = AnyEngine::open(kind, temp_dir.path())?;
+        assert_eq!(engine.kind(), kind);
+        let addr = free_addr();
+        let mut server: KvsServer<AnyEngine, _> =
+            KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+        thread::spawn(move || server.run(addr));
+        wait_for_server(addr);
+
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn server_buffer_sizes() -> Result<()> {
+    let value = "x".repeat(256 * 1024);
+    for (read, write) in [(16, 16), (1024 * 1024, 1024 * 1024)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr = free_addr();
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+            .with_buffer_sizes(read, write);
+        thread::spawn(move || server.run(addr));
+        wait_for_server(addr);
+
+        // Every response must arrive whether it is smaller or larger than the buffers.
+        let mut client = KvsClient::connect(addr)?;
+        for i in 0..4 {
+            client.set(format!("key{}", i), value.clone())?;
+            assert_eq!(client.get(format!("key{}", i))?.as_ref(), Some(&value));
+            client.ping()?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn nodelay_is_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, 2);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.nodelay()?);
+    client.set_nodelay(false)?;
+    assert!(!client.nodelay()?);
+    client.ping()?;
+
+    let client = KvsClient::connect_with_timeout(addr, Duration::from_secs(1))?;
+    assert!(client.nodelay()?);
+    Ok(())
+}
+
+#[test]
+fn server_nodelay_is_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for nodelay in [true, false] {
+        let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(1)?).with_nodelay(nodelay);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let served = thread::spawn(move || -> Result<bool> {
+            let (stream, _) = listener.accept()?;
+            let accepted = stream.try_clone()?;
+            server.handle_stream(stream)?;
+            Ok(accepted.nodelay()?)
+        });
+
+        let mut client = KvsClient::connect(addr)?;
+        client.ping()?;
+        client.close()?;
+        assert_eq!(served.join().unwrap()?, nodelay);
+    }
+    Ok(())
+}
+
+#[test]
+fn resp_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_protocol(WireProtocol::Resp);
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // Sends `request` and returns the reply, which is one line, or two for a
+    // non-null bulk string.
+    let mut reply = |request: &[u8]| -> Result<String> {
+        stream.write_all(request)?;
+        let mut reply = String::new();
+        reader.read_line(&mut reply)?;
+        if let Some(len) = reply.strip_prefix('$').and_then(|len| len.trim_end().parse::<usize>().ok()) {
+            let mut value = vec![0; len + 2];
+            reader.read_exact(&mut value)?;
+            reply.push_str(&String::from_utf8(value)?);
+        }
+        Ok(reply)
+    };
+    assert_eq!(reply(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nva\r\nue\r\n")?, "+OK\r\n");
+    assert_eq!(reply(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n")?, "$6\r\nva\r\nue\r\n");
+    assert_eq!(reply(b"*2\r\n$3\r\nget\r\n$4\r\nkey2\r\n")?, "$-1\r\n");
+    // The elements of an array reply are read by sending nothing.
+    assert_eq!(reply(b"MGET key2 key1\r\n")?, "*2\r\n");
+    assert_eq!(reply(b"")?, "$-1\r\n");
+    assert_eq!(reply(b"")?, "$6\r\nva\r\nue\r\n");
+    assert_eq!(reply(b"DEL key1 key2\r\n")?, ":1\r\n");
+    assert_eq!(reply(b"GET key1\r\n")?, "$-1\r\n");
+    assert_eq!(reply(b"PING\r\n")?, "+PONG\r\n");
+    assert_eq!(reply(b"GET\r\n")?, "-ERR wrong number of arguments for 'get' command\r\n");
+    assert_eq!(reply(b"INCR key1\r\n")?, "-ERR unknown command 'INCR'\r\n");
+
+    // A malformed command gets an error and the connection is closed.
+    stream.write_all(b"*1\r\n:1\r\n")?;
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest)?;
+    assert!(rest.starts_with("-ERR Protocol error"), "{}", rest);
+    Ok(())
+}
+
+#[test]
+fn remove_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, 2);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+    // The connection is still usable afterwards.
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.remove("key1".to_owned())?;
+
+    let output = Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key1", "--addr", &addr.to_string()])
+        .output()?;
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8(output.stderr)?, "Key not found\n");
+    Ok(())
+}
+
+#[test]
+fn refused_connection() {
+    // Nothing listens on a port that was just released.
+    let addr = free_addr();
+    let result = KvsClient::connect(addr);
+    assert!(matches!(result, Err(KvsError::Connection(_))), "unexpected result: {:?}", result.err());
+    let result = KvsClient::connect_with_timeout(addr, Duration::from_secs(1));
+    assert!(matches!(result, Err(KvsError::Connection(_))), "unexpected result: {:?}", result.err());
+}
+
+#[test]
+fn shutdown_handle() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let config = StoreConfig {
+        flush_mode: FlushMode::Background,
+        ..StoreConfig::default()
+    };
+    let engine = KvStore::open_with_config(temp_dir.path(), config)?;
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    // An idle connection doesn't keep the server from shutting down.
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    handle.shutdown();
+    let start = Instant::now();
+    while !server.is_finished() {
+        assert!(start.elapsed() < Duration::from_secs(5), "server did not shut down");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.join().unwrap()?;
+
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn reload_engine() -> Result<()> {
+    let (old_dir, new_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let addr = free_addr();
+    let old_store = KvStore::open(old_dir.path())?;
+    let mut server = KvsServer::new(old_store.clone(), SharedQueueThreadPool::new(2)?);
+    let handle = server.reload_handle();
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    let mut old_client = KvsClient::connect(addr)?;
+    old_client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let new_store = KvStore::open(new_dir.path())?;
+    handle.reload(new_store.clone())?;
+
+    // New connections are served by the new engine, and open ones by the old.
+    let mut new_client = KvsClient::connect(addr)?;
+    assert_eq!(new_client.get("key1".to_owned())?, None);
+    new_client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(old_client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(old_client.get("key2".to_owned())?, None);
+
+    assert_eq!(new_store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(old_store.get("key2")?, None);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn server_exits_on_sigterm() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    wait_for_server(addr);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) }, 0);
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            child.kill()?;
+            panic!("server did not exit after SIGTERM");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr)?;
+    assert!(stderr.contains("Received SIGTERM, shutting down"), "stderr: {}", stderr);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn server_backlog_and_accept_backoff() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", &addr.to_string(), "--backlog", "2"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut server = ServerProcess(child);
+    wait_for_server(addr);
+
+    // Leave the server no file descriptors, so that every `accept` fails
+    // with EMFILE and the connections stay queued.
+    thread::sleep(Duration::from_millis(100));
+    let limit = libc::rlimit { rlim_cur: 3, rlim_max: 3 };
+    let pid = server.0.id() as libc::pid_t;
+    assert_eq!(unsafe { libc::prlimit(pid, libc::RLIMIT_NOFILE, &limit, std::ptr::null_mut()) }, 0);
+
+    // Connecting succeeds until the queue is full, which takes one more
+    // connection than the backlog on Linux, plus the one the `accept` the
+    // server was blocked in gets with the descriptor it had reserved.
+    let mut queued = Vec::new();
+    while queued.len() < 10 {
+        match TcpStream::connect_timeout(&addr, Duration::from_millis(500)) {
+            Ok(stream) => queued.push(stream),
+            Err(_) => break,
+        }
+    }
+    assert!(queued.len() <= 4, "{} connections were queued", queued.len());
+    thread::sleep(Duration::from_millis(1000));
+
+    // The accept loop waits longer and longer between attempts rather than
+    // spinning, which would log thousands of errors by now.
+    let mut stderr = server.0.stderr.take().unwrap();
+    drop(server);
+    drop(queued);
+    let mut log = String::new();
+    stderr.read_to_string(&mut log)?;
+    let retries = log.matches("Too many open files (os error 24), retrying in").count();
+    assert!((1..=20).contains(&retries), "{} retries: {}", retries, log);
+    Ok(())
+}
+
+#[test]
+fn client_get_many() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir, 2);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = vec!["key3".to_owned(), "key2".to_owned(), "key1".to_owned()];
+    let expected = vec![Some("value3".to_owned()), None, Some("value1".to_owned())];
+    assert_eq!(client.get_many(keys.clone())?, expected);
+    assert_eq!(client.get_many(Vec::new())?, Vec::new());
+    // Free the connection's worker for the pool.
+    drop(client);
+
+    let pool = KvsClientPool::connect(addr, 1)?;
+    assert_eq!(pool.get_many(keys)?, expected);
+    Ok(())
+}
+
+#[test]
+fn server_info() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir, 2);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let info = client.server_info()?;
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.engine, "kvs");
+    assert!(info.uptime_secs < 60);
+    // The set and the info request itself.
+    assert_eq!(info.total_requests, 2);
+    Ok(())
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn server_info_sled() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let engine = AnyEngine::open(Engine::Sled, temp_dir.path())?;
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    assert_eq!(KvsClient::connect(addr)?.server_info()?.engine, "sled");
+    Ok(())
+}
+
+#[test]
+fn client_close() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    // A single worker, which the closed connection has to give back before
+    // the next one is served.
+    let addr = start_server(&temp_dir, 1);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.close()?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.close()
+}
+
+#[test]
+fn server_size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_max_key_len(8)
+        .with_max_value_len(16);
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set("k".repeat(8), "v".repeat(16))?;
+    match client.set("k".repeat(9), "value".to_owned()) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Key is 9 bytes long, the maximum is 8"),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match client.set("key".to_owned(), "v".repeat(17)) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Value is 17 bytes long, the maximum is 16"),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(client.get("k".repeat(8))?, Some("v".repeat(16)));
+    assert_eq!(client.get("k".repeat(9))?, None);
+    assert_eq!(client.get("key".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn servers_share_rayon_pool() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    assert_eq!(pool.threads(), 4);
+    let temp_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let addrs = [free_addr(), free_addr()];
+    for (temp_dir, &addr) in temp_dirs.iter().zip(&addrs) {
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, pool.clone());
+        thread::spawn(move || server.run(addr));
+        wait_for_server(addr);
+    }
+
+    let mut clients = [KvsClient::connect(addrs[0])?, KvsClient::connect(addrs[1])?];
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        assert_eq!(client.get("key1".to_owned())?, Some(format!("value{}", i)));
+    }
+    assert_eq!(pool.threads(), 4);
+    Ok(())
+}
+
+#[test]
+fn server_busy() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    // One worker, and room for one more connection to wait for it.
+    let pool = SharedQueueThreadPool::with_queue_capacity(1, 1)?;
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, pool);
+    thread::spawn(move || server.run(addr));
+    // The connection of `wait_for_server` may still be in the queue.
+    let first = loop {
+        match KvsClient::connect(addr).and_then(|mut client| client.ping().map(|()| client)) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    let mut second = KvsClient::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+
+    // The error may be lost if the server closes the socket with the request
+    // unread, which resets the connection.
+    let mut third = KvsClient::connect(addr)?;
+    third.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let start = Instant::now();
+    match third.ping() {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Server busy"),
+        Err(KvsError::Connection(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // The waiting connection is served once the worker is free.
+    drop(first);
+    second.set_read_timeout(Some(Duration::from_secs(5)))?;
+    second.ping()?;
+    Ok(())
+}
+
+#[test]
+fn server_busy_with_naive_pool() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    // A single thread, which the first connection keeps.
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, NaiveThreadPool::new(1)?);
+    thread::spawn(move || server.run(addr));
+    let _first = loop {
+        match KvsClient::connect(addr).and_then(|mut client| client.ping().map(|()| client)) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let mut second = KvsClient::connect(addr)?;
+    second.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let start = Instant::now();
+    match second.ping() {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Server busy"),
+        Err(KvsError::Connection(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(start.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
+
+#[test]
+fn server_max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(4)?)
+        .with_max_connections(2);
+    thread::spawn(move || server.run(addr));
+    let mut first = loop {
+        match KvsClient::connect(addr) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let mut second = KvsClient::connect(addr)?;
+    second.ping()?;
+
+    // The connection is accepted, then refused with an error. The error may be
+    // lost if the server closes the socket with the request unread, which
+    // resets the connection.
+    let mut third = KvsClient::connect(addr)?;
+    match third.get("key1".to_owned()) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Too many connections"),
+        Err(KvsError::Connection(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(first.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Closing a connection makes room for another one.
+    drop(first);
+    let start = Instant::now();
+    let mut fourth = loop {
+        let mut client = KvsClient::connect(addr)?;
+        if client.ping().is_ok() {
+            break client;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "no connection was accepted");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(fourth.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn server_closes_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_idle_timeout(Duration::from_millis(300));
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    // A client that keeps sending requests stays connected.
+    let mut busy = KvsClient::connect(addr)?;
+    busy.set("key1".to_owned(), "value1".to_owned())?;
+    let mut idle = TcpStream::connect(addr)?;
+    idle.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    let mut reader = serde_json::Deserializer::from_reader(idle.try_clone()?).into_iter::<serde_json::Value>();
+    assert!(reader.next().is_some());
+
+    for _ in 0..8 {
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(busy.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    // The idle one has been closed by now.
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut rest = Vec::new();
+    assert_eq!(idle.read_to_end(&mut rest)?, 0);
+    Ok(())
+}
+
+#[test]
+fn server_listens_on_several_addresses() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addrs = [free_addr(), free_addr()];
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run(&addrs[..]));
+    wait_for_server(addrs[0]);
+
+    let mut first = KvsClient::connect(addrs[0])?;
+    let mut second = KvsClient::connect(addrs[1])?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    second.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(first.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Shutting down stops every accept loop.
+    handle.shutdown();
+    server.join().unwrap()?;
+    for addr in addrs {
+        assert!(KvsClient::connect(addr).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn client_contains() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir, 2);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("big".to_owned(), "x".repeat(1 << 20))?;
+    assert!(client.contains("big".to_owned())?);
+    assert!(!client.contains("missing".to_owned())?);
+
+    // The answer is a bare `true`, not the megabyte-sized value.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Contains":{"key":"big"}}"#)?;
+    let mut reader = serde_json::Deserializer::from_reader(stream.try_clone()?).into_iter::<serde_json::Value>();
+    let response = reader.next().unwrap()?;
+    assert_eq!(serde_json::to_string(&response)?, r#"{"Bool":true}"#);
+    Ok(())
+}
+
+// A `KvStore` whose compaction is simulated by a flag, so that it lasts as
+// long as the test needs.
+#[derive(Clone)]
+struct CompactingEngine {
+    store: KvStore,
+    compacting: Arc<AtomicBool>,
+}
+
+impl KvsEngine for CompactingEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn is_busy(&self) -> bool {
+        self.compacting.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn server_health() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let compacting = Arc::new(AtomicBool::new(false));
+    let engine = CompactingEngine {
+        store: KvStore::open(temp_dir.path())?,
+        compacting: compacting.clone(),
+    };
+    let addr = free_addr();
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?).with_auth("secret");
+    thread::spawn(move || server.run(addr));
+    wait_for_server(addr);
+
+    // Probes don't need the token.
+    let mut client = KvsClient::connect(addr)?;
+    let healthy = Health {
+        ready: true,
+        engine_ok: true,
+    };
+    assert_eq!(client.health()?, healthy);
+    compacting.store(true, Ordering::SeqCst);
+    assert_eq!(
+        client.health()?,
+        Health {
+            ready: false,
+            engine_ok: true,
+        }
+    );
+    compacting.store(false, Ordering::SeqCst);
+    assert_eq!(client.health()?, healthy);
+
+    let output = Command::new(cargo_bin!("kvs-client"))
+        .args(["health", "--addr", &addr.to_string()])
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?, "ready: true\nengine_ok: true\n");
+    Ok(())
+}
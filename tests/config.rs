@@ -0,0 +1,110 @@
+use kvs::thread_pool::ThreadPoolKind;
+use kvs::{Engine, ServerConfig, WireProtocol};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+#[test]
+fn load_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("server.toml");
+    fs::write(
+        &path,
+        r#"
+# Settings for the test server
+addr = "127.0.0.1:5000"
+engine = "kvs"
+threads = 8 # one per core
+pool = "shared-queue"
+queue-capacity = 64
+access-log = true
+access-log-file = 'C:\logs\access.log'
+max-request-size = 1_048_576
+auth-token = "a \"secret\" # token"
+protocol = "resp"
+log-file = "kvs.log"
+"#,
+    )
+    .unwrap();
+
+    let config = ServerConfig::load(&path).unwrap();
+    assert_eq!(
+        config,
+        ServerConfig {
+            addr: vec!["127.0.0.1:5000".parse().unwrap()],
+            engine: Some(Engine::Kvs),
+            threads: Some(8),
+            pool: Some(ThreadPoolKind::SharedQueue),
+            queue_capacity: Some(64),
+            access_log: Some(true),
+            access_log_file: Some(PathBuf::from(r"C:\logs\access.log")),
+            max_request_size: Some(1 << 20),
+            max_key_len: None,
+            max_value_len: None,
+            max_connections: None,
+            backlog: None,
+            idle_timeout: None,
+            stats_interval: None,
+            auth_token: Some(r#"a "secret" # token"#.to_owned()),
+            protocol: Some(WireProtocol::Resp),
+            log_file: Some(PathBuf::from("kvs.log")),
+            log_file_max_size: None,
+        }
+    );
+}
+
+#[test]
+fn command_line_overrides_config_file() {
+    let file = ServerConfig::from_toml("addr = \"127.0.0.1:5000\"\nthreads = 8\npool = \"naive\"\n").unwrap();
+    let cli = ServerConfig {
+        addr: vec!["127.0.0.1:6000".parse().unwrap()],
+        pool: Some(ThreadPoolKind::Rayon),
+        protocol: Some(WireProtocol::Json),
+        ..ServerConfig::default()
+    };
+
+    let config = file.merge(cli);
+    assert_eq!(config.addr, vec!["127.0.0.1:6000".parse().unwrap()]);
+    assert_eq!(config.threads, Some(8));
+    assert_eq!(config.pool, Some(ThreadPoolKind::Rayon));
+    assert_eq!(config.protocol, Some(WireProtocol::Json));
+    assert_eq!(config.engine, None);
+}
+
+#[test]
+fn several_addresses() {
+    let config = ServerConfig::from_toml("addr = [\"127.0.0.1:5000\", \"[::1]:5000\"] # both\n").unwrap();
+    let expected: Vec<SocketAddr> = vec!["127.0.0.1:5000".parse().unwrap(), "[::1]:5000".parse().unwrap()];
+    assert_eq!(config.addr, expected);
+    let config = ServerConfig::from_toml("addr = []").unwrap();
+    assert!(config.addr.is_empty());
+
+    // Addresses on the command line replace all of those in the file.
+    let cli = ServerConfig {
+        addr: vec!["127.0.0.1:6000".parse().unwrap()],
+        ..ServerConfig::default()
+    };
+    let file = ServerConfig::from_toml("addr = [\"127.0.0.1:5000\", \"[::1]:5000\"]").unwrap();
+    assert_eq!(file.merge(cli.clone()).addr, cli.addr);
+}
+
+#[test]
+fn invalid_config_files() {
+    for toml in [
+        "[server]\naddr = \"127.0.0.1:5000\"",
+        "addr",
+        "addr = \"127.0.0.1:5000",
+        "addr = 127.0.0.1:5000",
+        "addr = [\"127.0.0.1:5000\" \"127.0.0.1:5001\"]",
+        "addr = [\"127.0.0.1:5000\",",
+        "threads = 8 8",
+        "threads = 8\nthreads = 9",
+        "threads = \"eight\"",
+        "engine = \"rocksdb\"",
+        "pool = \"shared_queue\"",
+        "workers = 8",
+    ] {
+        assert!(ServerConfig::from_toml(toml).is_err(), "accepted {:?}", toml);
+    }
+}
@@ -0,0 +1,52 @@
+use kvs::{Engine, KvsError, Result, detect_engine, persist_engine};
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn fresh_directory_has_no_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    assert_eq!(detect_engine(temp_dir.path())?, None);
+    persist_engine(temp_dir.path(), Engine::Kvs)?;
+    assert_eq!(fs::read_to_string(temp_dir.path().join(".engine"))?, "kvs");
+    assert_eq!(detect_engine(temp_dir.path())?, Some(Engine::Kvs));
+    Ok(())
+}
+
+#[test]
+fn matching_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    persist_engine(temp_dir.path(), Engine::Kvs)?;
+    persist_engine(temp_dir.path(), Engine::Kvs)?;
+    assert_eq!(detect_engine(temp_dir.path())?, Some(Engine::Kvs));
+    Ok(())
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn mismatching_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    persist_engine(temp_dir.path(), Engine::Sled)?;
+    assert!(matches!(persist_engine(temp_dir.path(), Engine::Kvs), Err(KvsError::EngineMismatch)));
+    assert_eq!(detect_engine(temp_dir.path())?, Some(Engine::Sled));
+    Ok(())
+}
+
+#[test]
+fn legacy_json_engine_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join(".engine"), "\"Kvs\"")?;
+    assert_eq!(detect_engine(temp_dir.path())?, Some(Engine::Kvs));
+
+    // Persisting rewrites the file in the plain form.
+    persist_engine(temp_dir.path(), Engine::Kvs)?;
+    assert_eq!(fs::read_to_string(temp_dir.path().join(".engine"))?, "kvs");
+    Ok(())
+}
+
+#[test]
+fn unknown_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join(".engine"), "rocksdb").unwrap();
+    assert!(matches!(detect_engine(temp_dir.path()), Err(KvsError::StringError(_))));
+    assert!(persist_engine(temp_dir.path(), Engine::Kvs).is_err());
+}
@@ -0,0 +1,47 @@
+use kvs::{ErrorCode, KvsError};
+use std::io;
+
+#[test]
+fn error_codes() {
+    let utf8_error = String::from_utf8(vec![0xff]).unwrap_err();
+    let serde_error = serde_json::from_str::<String>("").unwrap_err();
+    let cases = [
+        (KvsError::from(io::Error::other("disk")), ErrorCode::Io, "io"),
+        (KvsError::from(serde_error), ErrorCode::Serialization, "serialization"),
+        (KvsError::from(utf8_error), ErrorCode::Utf8, "utf8"),
+        (KvsError::KeyNotFound, ErrorCode::KeyNotFound, "key_not_found"),
+        (KvsError::UnexpectedCommandType, ErrorCode::UnexpectedCommandType, "unexpected_command_type"),
+        (KvsError::EngineMismatch, ErrorCode::EngineMismatch, "engine_mismatch"),
+        (KvsError::Timeout, ErrorCode::Timeout, "timeout"),
+        (KvsError::Connection("refused".to_owned()), ErrorCode::Connection, "connection"),
+        (
+            KvsError::UnsupportedFormat { found: 2, expected: 1 },
+            ErrorCode::UnsupportedFormat,
+            "unsupported_format",
+        ),
+        (KvsError::AlreadyLocked, ErrorCode::AlreadyLocked, "already_locked"),
+        (KvsError::ReadOnly, ErrorCode::ReadOnly, "read_only"),
+        (KvsError::KeyTooLong { len: 2, max: 1 }, ErrorCode::KeyTooLong, "key_too_long"),
+        (KvsError::ValueTooLong { len: 2, max: 1 }, ErrorCode::ValueTooLong, "value_too_long"),
+        (KvsError::TruncatedDump, ErrorCode::TruncatedDump, "truncated_dump"),
+        (KvsError::ChecksumMismatch, ErrorCode::ChecksumMismatch, "checksum_mismatch"),
+        (KvsError::Parse("invalid digit".to_owned()), ErrorCode::Parse, "parse"),
+        (KvsError::StringError("message".to_owned()), ErrorCode::Other, "other"),
+    ];
+    for (err, code, name) in cases {
+        assert_eq!(err.code(), code, "{:?}", err);
+        assert_eq!(ErrorCode::from(&err), code);
+        assert_eq!(code.to_string(), name);
+        let json = serde_json::to_string(&code).unwrap();
+        assert_eq!(json, format!("\"{}\"", name));
+        assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), code);
+    }
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_error_code() {
+    let err = KvsError::from(sled::Error::Unsupported("test".to_owned()));
+    assert_eq!(err.code(), ErrorCode::Sled);
+    assert_eq!(serde_json::to_string(&ErrorCode::Sled).unwrap(), "\"sled\"");
+}
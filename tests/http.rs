@@ -0,0 +1,118 @@
+#![cfg(feature = "http")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsServer, Result, WireProtocol};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Starts an HTTP server on a free local port in a background thread.
+fn start_server(temp_dir: &TempDir, auth_token: Option<&str>) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut server = KvsServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+    )
+    .with_protocol(WireProtocol::Http);
+    if let Some(token) = auth_token {
+        server = server.with_auth(token);
+    }
+    thread::spawn(move || server.run(addr));
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server did not start");
+}
+
+// Sends a single request on a new connection and returns the status code and body.
+fn request(addr: SocketAddr, method: &str, path: &str, headers: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        headers,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    Ok((status, body))
+}
+
+#[test]
+fn routes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None);
+
+    assert_eq!(request(addr, "GET", "/kv/key1", "", "")?, (404, "Key not found".to_owned()));
+    assert_eq!(request(addr, "PUT", "/kv/key1", "", "value\n1")?, (204, String::new()));
+    assert_eq!(request(addr, "GET", "/kv/key1", "", "")?, (200, "value\n1".to_owned()));
+    assert_eq!(request(addr, "PUT", "/kv/a%20b%2Fc", "", "spaced")?.0, 204);
+    assert_eq!(request(addr, "GET", "/kv/a%20b%2fc", "", "")?, (200, "spaced".to_owned()));
+    assert_eq!(request(addr, "DELETE", "/kv/key1", "", "")?, (204, String::new()));
+    assert_eq!(request(addr, "DELETE", "/kv/key1", "", "")?, (404, "Key not found".to_owned()));
+    assert_eq!(request(addr, "GET", "/kv/key1", "", "")?.0, 404);
+    assert_eq!(request(addr, "POST", "/kv/key1", "", "value")?.0, 405);
+    assert_eq!(request(addr, "GET", "/other", "", "")?.0, 404);
+    assert!(request(addr, "GET", "/metrics", "", "")?.1.contains("kvs_requests_total{op=\"get\"} 4"));
+    Ok(())
+}
+
+#[test]
+fn keep_alive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    stream.write_all(b"PUT /kv/key1 HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue1")?;
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    assert_eq!(status, "HTTP/1.1 204 No Content\r\n");
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    assert_eq!(line, "\r\n");
+
+    stream.write_all(b"GET /kv/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    reader.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nvalue1"));
+    Ok(())
+}
+
+#[test]
+fn bearer_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, Some("secret"));
+
+    assert_eq!(request(addr, "PUT", "/kv/key1", "", "value1")?.0, 401);
+    let wrong = "Authorization: Bearer wrong\r\n";
+    assert_eq!(request(addr, "PUT", "/kv/key1", wrong, "value1")?.0, 401);
+    let right = "Authorization: Bearer secret\r\n";
+    assert_eq!(request(addr, "PUT", "/kv/key1", right, "value1")?.0, 204);
+    assert_eq!(request(addr, "GET", "/kv/key1", right, "")?, (200, "value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn malformed_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"GET /kv/key1\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    Ok(())
+}
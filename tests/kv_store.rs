@@ -1,6 +1,15 @@
-use kvs::{KvStore, Result};
+// Most tests pass owned keys, as they did before `KvStore` accepted `&str`, so
+// they also check that such calls keep working.
+#![allow(clippy::unnecessary_to_owned, clippy::needless_borrows_for_generic_args)]
+
+use kvs::{CompactionPolicy, Event, FlushMode, KvStore, KvsError, Result, ShardedKvStore, StoreConfig};
+use rand::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,6 +90,38 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should get, overwrite and remove values by `&str` keys
+#[test]
+fn get_stored_value_by_str() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set("key2", "value2")?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_key_by_str() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    assert!(store.remove("key2").is_err());
+    assert!(store.remove("key1").is_ok());
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -132,15 +173,16 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,7 +190,11 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every thread has
+    // dropped its handle and with it the lock on the store.
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -209,3 +255,1495 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(
+        store.scan_prefix("user:")?,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan_prefix("")?.len(), 3);
+    assert!(store.scan_prefix("missing")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn stats_and_manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let before = store.stats()?;
+    assert_eq!(before.live_keys, 1);
+    assert!(before.stale_bytes > 0);
+    assert!(before.stale_bytes < before.total_log_bytes);
+
+    store.compact()?;
+    let after = store.stats()?;
+    assert_eq!(after.live_keys, 1);
+    assert_eq!(after.stale_bytes, 0);
+    assert_eq!(after.total_log_bytes, before.total_log_bytes - before.stale_bytes);
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+
+    // Stats survive a reopen.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?, after);
+    Ok(())
+}
+
+// Large values must read back correctly before and after the log is replaced
+// by a compaction, which also exercises remapping with the `mmap` feature.
+#[test]
+fn large_values_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = |i: usize| format!("{}", i).repeat(64 * 1024);
+
+    for i in 0..8 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+    for i in 0..8 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+
+    store.set("key0".to_owned(), value(100))?;
+    store.compact()?;
+    store.set("key8".to_owned(), value(8))?;
+
+    assert_eq!(store.get("key0".to_owned())?, Some(value(100)));
+    for i in 1..9 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        value_cache_entries: 2,
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // The second read of a key is served from the cache.
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+    // Writes invalidate the cached value.
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 2));
+
+    // Reading two other keys evicts the least recently used one.
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (3, 4));
+    Ok(())
+}
+
+#[test]
+fn open_from_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set(format!("key{}", i), format!("value{}", i + 1))?;
+    }
+    store.compact()?;
+    assert!(temp_dir.path().join("wal.hint").exists());
+
+    // Writes after the compaction are only in the log tail.
+    for i in 0..10 {
+        store.remove(format!("key{}", i))?;
+        store.set(format!("key{}", i + 50), "updated".to_owned())?;
+    }
+    store.set("new".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let from_hint = (store.scan_prefix("")?, store.stats()?);
+    drop(store);
+
+    // Without the hint the whole log is replayed, which must give the same state.
+    fs::remove_file(temp_dir.path().join("wal.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    let from_log = (store.scan_prefix("")?, store.stats()?);
+    assert_eq!(from_hint, from_log);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key55".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stale_hint_file_is_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    // A hint describing more log than exists must not be trusted.
+    fs::write(temp_dir.path().join("wal.log"), "")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Neither must a hint that does not end on a record boundary.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    fs::write(
+        temp_dir.path().join("wal.hint"),
+        r#"{"log_len":5,"stale_bytes":0,"entries":[]}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn hint_on_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        hint_on_close: true,
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key1".to_owned())?;
+    let stats = store.stats()?;
+    drop(store);
+    assert!(temp_dir.path().join("wal.hint").exists());
+
+    // Break the first record, which is stale: replaying the whole log would
+    // fail, so a successful open shows the index came from the hint.
+    let log_path = temp_dir.path().join("wal.log");
+    let mut log = fs::read(&log_path)?;
+    log[0] = b'#';
+    fs::write(&log_path, &log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?, stats);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Writes after the hint, by a store without the option, are replayed from
+    // the log tail.
+    store.set("key2".to_owned(), "updated".to_owned())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stale_hint_on_close_is_ignored() -> Result<()> {
+    let (temp_dir, other_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let config = StoreConfig {
+        hint_on_close: true,
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // Replace the log with a shorter one the hint doesn't describe.
+    let other = KvStore::open(other_dir.path())?;
+    other.set("key1".to_owned(), "other".to_owned())?;
+    drop(other);
+    fs::copy(other_dir.path().join("wal.log"), temp_dir.path().join("wal.log"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.live_keys, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn preallocated_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("wal.log");
+    let config = StoreConfig {
+        preallocate_bytes: Some(64 * 1024),
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key1".to_owned())?;
+    let stats = store.stats()?;
+    drop(store);
+    // The file is grown ahead of the records, which stop short of its end.
+    assert!(fs::metadata(&log_path)?.len() > stats.total_log_bytes);
+
+    // Replaying the log stops at the records, with or without preallocation.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?, stats);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.stats()?, stats);
+
+    // New records go right after the old ones rather than after the zeros.
+    store.set("key2".to_owned(), "updated".to_owned())?;
+    store.compact()?;
+    store.set("key3".to_owned(), "updated".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.stats()?.live_keys, 99);
+    Ok(())
+}
+
+#[test]
+fn max_age() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        max_age: Some(Duration::from_secs(3600)),
+        hint_on_close: true,
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // A recent log is kept.
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // One last written two hours ago is discarded, hint file included...
+    let log = fs::File::options().write(true).open(temp_dir.path().join("wal.log"))?;
+    log.set_modified(SystemTime::now() - Duration::from_secs(2 * 3600))?;
+    drop(log);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats()?.total_log_bytes, 0);
+    // ...and the store is usable afterwards.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn store_survives_panic_under_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let panicking = store.clone();
+    let result = thread::spawn(move || {
+        panic_control::disable_hook_in_current_thread();
+        panicking.transaction(|txn| {
+            txn.set("key2".to_owned(), "value2".to_owned()).unwrap();
+            panic!("panic while holding the lock");
+        })
+    })
+    .join();
+    assert!(result.is_err());
+
+    // Other threads keep working, and see what was written before the panic.
+    let other = store.clone();
+    thread::spawn(move || -> Result<()> {
+        assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+        other.set("key3".to_owned(), "value3".to_owned())?;
+        other.remove("key1".to_owned())
+    })
+    .join()
+    .unwrap()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.watch_prefix("user:".to_owned());
+
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("other".to_owned(), "ignored".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    assert!(store.remove("user:2".to_owned()).is_err());
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::Set {
+            key: "user:1".to_owned(),
+            value: "alice".to_owned()
+        }
+    );
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::Remove {
+            key: "user:1".to_owned()
+        }
+    );
+    assert!(events.try_recv().is_err());
+
+    // Dropped receivers are unsubscribed without affecting writes.
+    drop(events);
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut expected = HashMap::new();
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        expected.insert(format!("key{}", i), format!("value{}", i));
+    }
+    for i in (0..100).step_by(3) {
+        store.remove(format!("key{}", i))?;
+        expected.remove(&format!("key{}", i));
+    }
+
+    let mut iter = store.iter()?;
+    // Changes made while iterating are not seen.
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "updated".to_owned())?;
+    let mut pairs = HashMap::new();
+    let mut last = None;
+    for entry in iter.by_ref() {
+        let (key, value) = entry?;
+        assert!(last < Some(key.clone()), "keys are not sorted");
+        last = Some(key.clone());
+        pairs.insert(key, value);
+    }
+    assert_eq!(pairs, expected);
+    assert!(iter.next().is_none());
+
+    assert_eq!(store.iter()?.count(), expected.len() - 1);
+    assert!(KvStore::open(TempDir::new().unwrap().path())?.iter()?.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn snapshot_is_isolated_from_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    // Compaction replaces the log the snapshot reads from.
+    store.compact()?;
+
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3")?, None);
+    let entries = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        [
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert_eq!(snapshot.len(), 2);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn namespaces_are_independent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users");
+    let orders = store.namespace("orders");
+
+    users.set("1".to_owned(), "alice".to_owned())?;
+    users.set("2".to_owned(), "bob".to_owned())?;
+    orders.set("1".to_owned(), "book".to_owned())?;
+    store.set("1".to_owned(), "plain".to_owned())?;
+
+    assert_eq!(users.get("1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(orders.get("1".to_owned())?, Some("book".to_owned()));
+    assert_eq!(orders.get("2".to_owned())?, None);
+    assert!(orders.remove("2".to_owned()).is_err());
+
+    assert_eq!(users.clear()?, 2);
+    assert_eq!(users.get("1".to_owned())?, None);
+    assert_eq!(orders.get("1".to_owned())?, Some("book".to_owned()));
+    assert_eq!(store.get("1".to_owned())?, Some("plain".to_owned()));
+
+    // Namespaced keys survive a reopen.
+    drop((users, orders, store));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespace("orders").get("1".to_owned())?, Some("book".to_owned()));
+    assert_eq!(store.namespace("users").clear()?, 0);
+    Ok(())
+}
+
+#[test]
+fn concurrent_stress() -> Result<()> {
+    const THREADS: u64 = 16;
+    const OPS: usize = 1000;
+    const KEYS: usize = 32;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // Events are sent under the store's lock, so they arrive in the order the
+    // writes were applied, which is what the final state must reflect.
+    let events = store.watch_prefix(String::new());
+
+    thread::scope(|s| {
+        for thread_id in 0..THREADS {
+            let store = store.clone();
+            s.spawn(move || {
+                let mut rng = StdRng::seed_from_u64(thread_id);
+                for i in 0..OPS {
+                    let key = format!("key{}", rng.random_range(0..KEYS));
+                    match rng.random_range(0..10) {
+                        0..4 => {
+                            // Values are large enough to trigger compactions along the way.
+                            let padding = "x".repeat(rng.random_range(0..2000));
+                            let value = format!("{}-{}-{}", thread_id, i, padding);
+                            store.set(key, value).unwrap();
+                        }
+                        4..6 => match store.remove(key) {
+                            Ok(()) | Err(KvsError::KeyNotFound) => {}
+                            Err(e) => panic!("remove failed: {}", e),
+                        },
+                        _ => {
+                            store.get(key).unwrap();
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let mut expected = HashMap::new();
+    for event in events.try_iter() {
+        match event {
+            Event::Set { key, value } => expected.insert(key, value),
+            Event::Remove { key } => expected.remove(&key),
+        };
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..KEYS {
+            let key = format!("key{}", i);
+            assert_eq!(store.get(key.clone())?, expected.get(&key).cloned(), "{}", key);
+        }
+        assert_eq!(store.stats()?.live_keys, expected.len());
+        Ok(())
+    };
+    check(&store)?;
+
+    // The log replays to the same state.
+    drop(events);
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+#[test]
+fn leftover_compaction_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let junk = "x".repeat(64 * 1024);
+    fs::write(temp_dir.path().join("wal.log.compact"), &junk)?;
+    fs::write(temp_dir.path().join("wal.hint.compact"), &junk)?;
+
+    // Orphans from a crashed compaction are removed on open.
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("wal.log.compact").exists());
+    assert!(!temp_dir.path().join("wal.hint.compact").exists());
+
+    // A compaction file that shows up later is overwritten, not appended to.
+    fs::write(temp_dir.path().join("wal.log.compact"), &junk)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+
+    let log = fs::read(temp_dir.path().join("wal.log"))?;
+    let records = serde_json::Deserializer::from_slice(&log)
+        .into_iter::<serde_json::Value>()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .expect("log contains invalid records");
+    assert_eq!(records.len(), 2);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let padding = "x".repeat(1024);
+    for i in 0..20_000 {
+        store.set(format!("key{}", i), format!("{}{}", i, padding))?;
+    }
+    // Move half of the keys, so that the compaction drops their old versions.
+    for i in (0..20_000).step_by(2) {
+        store.set(format!("key{}", i), format!("{}{}", i + 1, padding))?;
+    }
+
+    let compacting = AtomicBool::new(true);
+    let (compaction_time, reads, max_latency) = thread::scope(|s| {
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                let (compacting, padding) = (&compacting, &padding);
+                s.spawn(move || {
+                    let mut rng = rand::rng();
+                    let (mut reads, mut max_latency) = (0, Duration::ZERO);
+                    while compacting.load(Ordering::SeqCst) {
+                        let i = rng.random_range(0..20_000);
+                        let start = Instant::now();
+                        let value = store.get(format!("key{}", i)).unwrap();
+                        max_latency = max_latency.max(start.elapsed());
+                        let expected = if i % 2 == 0 { i + 1 } else { i };
+                        assert_eq!(value, Some(format!("{}{}", expected, padding)), "key{}", i);
+                        reads += 1;
+                    }
+                    (reads, max_latency)
+                })
+            })
+            .collect();
+        let start = Instant::now();
+        store.compact().unwrap();
+        let compaction_time = start.elapsed();
+        compacting.store(false, Ordering::SeqCst);
+        let results: Vec<_> = readers.into_iter().map(|r| r.join().unwrap()).collect();
+        let reads: usize = results.iter().map(|r| r.0).sum();
+        let max_latency = results.iter().map(|r| r.1).max().unwrap();
+        (compaction_time, reads, max_latency)
+    });
+    assert!(store.last_compaction().is_some());
+    assert!(reads > 0);
+    assert!(
+        max_latency < compaction_time / 2,
+        "a read took {:?} during a {:?} compaction",
+        max_latency,
+        compaction_time
+    );
+    Ok(())
+}
+
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+    for i in 0..20_000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    // Leave some stale data for the compaction to drop.
+    for i in 0..100 {
+        store.remove(format!("key{}", i))?;
+    }
+
+    let (compaction_time, max_latency) = thread::scope(|s| {
+        let writers: Vec<_> = (0..4)
+            .map(|thread_id| {
+                let store = store.clone();
+                s.spawn(move || {
+                    let mut max_latency = Duration::ZERO;
+                    for i in 0..200 {
+                        let start = Instant::now();
+                        store.set(format!("new{}-{}", thread_id, i), format!("{}", i)).unwrap();
+                        max_latency = max_latency.max(start.elapsed());
+                    }
+                    max_latency
+                })
+            })
+            .collect();
+        let start = Instant::now();
+        store.compact().unwrap();
+        let compaction_time = start.elapsed();
+        let max_latency = writers.into_iter().map(|w| w.join().unwrap()).max().unwrap();
+        (compaction_time, max_latency)
+    });
+    assert!(
+        max_latency < compaction_time / 2,
+        "a write took {:?} during a {:?} compaction",
+        max_latency,
+        compaction_time
+    );
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for i in 0..200 {
+                let key = format!("new{}-{}", thread_id, i);
+                assert_eq!(store.get(key)?, Some(format!("{}", i)));
+            }
+        }
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key19999".to_owned())?, Some(value.clone()));
+        assert_eq!(store.stats()?.live_keys, 19_900 + 800);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+#[test]
+fn get_or_insert_with_runs_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let calls = AtomicUsize::new(0);
+    let barrier = Barrier::new(8);
+
+    let values: Vec<String> = thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let (store, calls, barrier) = (store.clone(), &calls, &barrier);
+                s.spawn(move || {
+                    barrier.wait();
+                    store
+                        .get_or_insert_with("key".to_owned(), || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            format!("value{}", thread_id)
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(values.iter().all(|value| *value == values[0]));
+    assert_eq!(store.get("key".to_owned())?, Some(values[0].clone()));
+    // An existing value is returned without calling the closure.
+    assert_eq!(store.get_or_insert_with("key".to_owned(), || unreachable!())?, values[0]);
+    Ok(())
+}
+
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let values: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        b"a\0b\0".to_vec(),
+        vec![0xff, 0xfe, 0x80, 0x00, 0xc3],
+        (0..=255).collect(),
+    ];
+    for (i, value) in values.iter().enumerate() {
+        store.set_bytes(format!("key{}", i), value)?;
+    }
+    store.set("text".to_owned(), "value".to_owned())?;
+
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(store.get_bytes(&format!("key{}", i))?.as_ref(), Some(value));
+    }
+    assert_eq!(store.get_bytes("text")?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("missing")?, None);
+    // Valid UTF-8 reads back as a string; anything else is an error.
+    assert_eq!(store.get("key2".to_owned())?, Some("a\0b\0".to_owned()));
+    assert!(store.get("key3".to_owned()).is_err());
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.get_bytes("key3")?, None);
+
+    // Binary values survive a reopen and a compaction.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    assert_eq!(store.get_bytes("key4")?.as_ref(), Some(&values[4]));
+    assert_eq!(store.get_bytes("key3")?, None);
+    Ok(())
+}
+
+#[test]
+fn binary_values_are_stored_raw() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        preallocate_bytes: Some(64 * 1024),
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let value: Vec<u8> = (0..3000).map(|i| if i % 2 == 0 { 0xff } else { 0 }).collect();
+    store.set_bytes("key".to_owned(), &value)?;
+    let stats = store.stats()?;
+    assert!(stats.total_log_bytes < 3100, "{} bytes", stats.total_log_bytes);
+
+    // A value ending in NUL bytes isn't mistaken for preallocated space.
+    store.set_bytes("zeros".to_owned(), &[1, 0, 0])?;
+    let mut streamed = Vec::new();
+    assert!(store.get_to_writer("key", &mut streamed)?);
+    assert_eq!(streamed, value);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get_bytes("zeros")?, Some(vec![1, 0, 0]));
+    assert_eq!(store.get_bytes("key")?, Some(value));
+    Ok(())
+}
+
+#[test]
+fn buffered_writes_are_flushed_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        flush_mode: FlushMode::Background,
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    // Buffered writes are visible to reads before they are flushed.
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+
+    // Dropping one of several handles leaves the writes buffered; dropping
+    // the last one flushes them.
+    let other = store.clone();
+    drop(store);
+    other.set("key100".to_owned(), "value100".to_owned())?;
+    drop(other);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+    for i in 2..=100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn get_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        history_depth: 2,
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 1..=3 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_versions("key")?, vec!["value3", "value2"]);
+    assert_eq!(store.get_versions("other")?, vec!["value"]);
+    assert!(store.get_versions("missing")?.is_empty());
+
+    // The history survives a reopen, with and without a hint file.
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.get_versions("key")?, vec!["value3", "value2"]);
+    store.compact()?;
+    store.set("key".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get_versions("key")?, vec!["value4", "value3"]);
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get_versions("key")?, vec!["value4", "value3"]);
+
+    // Removing a key discards its history.
+    store.remove("key".to_owned())?;
+    store.set("key".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get_versions("key")?, vec!["value5"]);
+
+    // Without a history only the current value is kept.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("other".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_versions("other")?, vec!["value2"]);
+    Ok(())
+}
+
+#[test]
+fn get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let start = store.stats()?.total_log_bytes;
+    store.set("key", "value1")?;
+    let between = store.stats()?.total_log_bytes;
+    store.set("other", "value")?;
+    store.set("key", "value2")?;
+
+    assert_eq!(store.get_at("key", start)?, None);
+    assert_eq!(store.get_at("key", between)?, Some("value1".to_owned()));
+    // A position inside the second write doesn't see it yet.
+    assert_eq!(store.get_at("key", store.stats()?.total_log_bytes - 1)?, Some("value1".to_owned()));
+    assert_eq!(store.get_at("key", u64::MAX)?, Some("value2".to_owned()));
+    assert_eq!(store.get_at("other", between)?, None);
+    assert_eq!(store.get("key")?, Some("value2".to_owned()));
+
+    let removed = store.stats()?.total_log_bytes;
+    store.remove("key")?;
+    assert_eq!(store.get_at("key", removed)?, Some("value2".to_owned()));
+    assert_eq!(store.get_at("key", u64::MAX)?, None);
+    Ok(())
+}
+
+#[test]
+fn named_stores_share_a_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |name: &str| {
+        let config = StoreConfig {
+            name: Some(name.to_owned()),
+            ..StoreConfig::default()
+        };
+        KvStore::open_with_config(temp_dir.path(), config)
+    };
+    let users = open("users")?;
+    let orders = open("orders")?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    orders.set("key".to_owned(), "order".to_owned())?;
+    orders.set("other".to_owned(), "order".to_owned())?;
+    users.remove("key".to_owned())?;
+    users.set("key".to_owned(), "user2".to_owned())?;
+    users.compact()?;
+
+    assert_eq!(users.get("key".to_owned())?, Some("user2".to_owned()));
+    assert_eq!(users.get("other".to_owned())?, None);
+    assert_eq!(orders.get("key".to_owned())?, Some("order".to_owned()));
+    assert!(temp_dir.path().join("users.log").exists());
+    assert!(temp_dir.path().join("users.hint").exists());
+    assert!(temp_dir.path().join("orders.log").exists());
+    assert!(!temp_dir.path().join("wal.log").exists());
+
+    drop(users);
+    drop(orders);
+    assert_eq!(open("users")?.get("key".to_owned())?, Some("user2".to_owned()));
+    assert_eq!(open("orders")?.get("other".to_owned())?, Some("order".to_owned()));
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = ["key3", "key2", "key1", "key3"].map(str::to_owned).to_vec();
+    assert_eq!(
+        store.get_many(keys)?,
+        vec![Some("value3".to_owned()), None, Some("value1".to_owned()), Some("value3".to_owned())]
+    );
+    assert!(store.get_many(Vec::<String>::new())?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let version_path = temp_dir.path().join("wal.version");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(fs::read_to_string(&version_path)?, "1\n");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A store from before the version was recorded is upgraded.
+    fs::remove_file(&version_path)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(fs::read_to_string(&version_path)?, "1\n");
+
+    // A store written by a future version is left alone.
+    let log = fs::read(temp_dir.path().join("wal.log"))?;
+    fs::write(&version_path, "2\n")?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat { found: 2, expected: 1 }) => {}
+        result => panic!("unexpected result: {:?}", result.map(|_| ())),
+    }
+    assert_eq!(fs::read(temp_dir.path().join("wal.log"))?, log);
+    assert_eq!(fs::read_to_string(&version_path)?, "2\n");
+
+    fs::write(&version_path, "garbage")?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+#[test]
+fn second_writer_is_locked_out() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::AlreadyLocked) => {}
+        result => panic!("unexpected result: {:?}", result.map(|_| ())),
+    }
+
+    // A read-only store can be opened alongside the writer, but not written to.
+    let config = StoreConfig {
+        read_only: true,
+        ..StoreConfig::default()
+    };
+    let reader = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(reader.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    drop(reader);
+
+    // The lock is released when the writer is dropped.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn get_to_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // Large enough to span many reads, with characters that are escaped in the log.
+    let value: String = (0..200_000).map(|i| format!("line {} \"é\" \\ ✓\t\u{1}\n", i)).collect();
+    let bytes: Vec<u8> = (0..1_000_001).map(|i| (i * 7 % 256) as u8).collect();
+    store.set("text".to_owned(), value.clone())?;
+    store.set_bytes("bytes".to_owned(), &bytes)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let mut buf = Vec::new();
+        assert!(store.get_to_writer("text", &mut buf)?);
+        assert!(buf == value.as_bytes());
+
+        let mut buf = Vec::new();
+        assert!(store.get_to_writer("bytes", &mut buf)?);
+        assert!(buf == bytes);
+
+        let mut buf = Vec::new();
+        assert!(!store.get_to_writer("missing", &mut buf)?);
+        assert!(buf.is_empty());
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+#[test]
+fn take() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.take("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.take("key1".to_owned())?, None);
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    // The removal is persisted.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("staging".to_owned(), "value1".to_owned())?;
+    store.set_bytes("bytes".to_owned(), &[0, 159, 255])?;
+
+    store.rename("staging".to_owned(), "production".to_owned())?;
+    assert_eq!(store.get("staging".to_owned())?, None);
+    assert_eq!(store.get("production".to_owned())?, Some("value1".to_owned()));
+
+    // A missing source is an error and leaves the destination alone.
+    assert!(matches!(
+        store.rename("staging".to_owned(), "production".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("production".to_owned())?, Some("value1".to_owned()));
+
+    // An existing destination is overwritten, and bytes stay bytes.
+    store.rename("bytes".to_owned(), "production".to_owned())?;
+    assert_eq!(store.get_bytes("production")?, Some(vec![0, 159, 255]));
+    assert_eq!(store.get_bytes("bytes")?, None);
+
+    store.rename("production".to_owned(), "production".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("production")?, Some(vec![0, 159, 255]));
+    assert_eq!(store.get("staging".to_owned())?, None);
+    assert_eq!(store.get_bytes("bytes")?, None);
+    Ok(())
+}
+
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        max_key_len: Some(8),
+        max_value_len: Some(16),
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+
+    store.set("k".repeat(8), "v".repeat(16))?;
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(KvsError::KeyTooLong { len: 9, max: 8 })
+    ));
+    assert!(matches!(
+        store.set("key".to_owned(), "v".repeat(17)),
+        Err(KvsError::ValueTooLong { len: 17, max: 16 })
+    ));
+    store.set_bytes("bytes".to_owned(), &[0; 16])?;
+    assert!(matches!(
+        store.set_bytes("bytes".to_owned(), &[0; 17]),
+        Err(KvsError::ValueTooLong { len: 17, max: 16 })
+    ));
+    assert!(matches!(
+        store.rename("k".repeat(8), "k".repeat(9)),
+        Err(KvsError::KeyTooLong { .. })
+    ));
+
+    // Nothing over the limits was written.
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.stale_bytes, 0);
+    assert_eq!(store.get("k".repeat(8))?, Some("v".repeat(16)));
+    assert_eq!(store.get_bytes("bytes")?, Some(vec![0; 16]));
+    Ok(())
+}
+
+#[test]
+fn get_or_and_get_parsed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("count".to_owned(), "42".to_owned())?;
+    store.set("enabled".to_owned(), "true".to_owned())?;
+    store.set("name".to_owned(), "kvs".to_owned())?;
+
+    assert_eq!(store.get_or("name", "default")?, "kvs");
+    assert_eq!(store.get_or("missing", "default")?, "default");
+
+    assert_eq!(store.get_parsed::<u32>("count")?, Some(42));
+    assert_eq!(store.get_parsed::<bool>("enabled")?, Some(true));
+    assert_eq!(store.get_parsed::<u32>("missing")?, None);
+    assert!(matches!(store.get_parsed::<u32>("name"), Err(KvsError::Parse(_))));
+    assert!(matches!(store.get_parsed::<u8>("count"), Ok(Some(42))));
+    assert!(matches!(store.get_parsed::<i8>("enabled"), Err(KvsError::Parse(_))));
+    Ok(())
+}
+
+#[test]
+fn stale_ratio_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        compaction_policy: CompactionPolicy::StaleRatio(0.5),
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let value = "v".repeat(100);
+    for i in 0..1000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+
+    // A little garbage in a large store is left alone.
+    store.set("key0".to_owned(), value.clone())?;
+    let stats = store.stats()?;
+    assert!(stats.stale_bytes > 0);
+
+    // The store is compacted once half of the log is stale, and not before.
+    let mut compacted = false;
+    for i in 1..2000 {
+        let before = store.stats()?;
+        assert!(before.stale_bytes as f64 / (before.total_log_bytes as f64) <= 0.5);
+        store.set(format!("key{}", i % 1000), value.clone())?;
+        if store.stats()?.stale_bytes < before.stale_bytes {
+            assert!(before.stale_bytes as f64 / (before.total_log_bytes as f64) > 0.45);
+            compacted = true;
+            break;
+        }
+    }
+    assert!(compacted);
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+#[test]
+fn transaction_has_no_lost_updates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handles: Vec<_> = (0..2)
+        .map(|_| {
+            let (store, barrier) = (store.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..500 {
+                    store
+                        .transaction(|txn| -> Result<()> {
+                            let counter: u32 = txn.get("counter".to_owned())?.unwrap().parse().unwrap();
+                            txn.set("counter".to_owned(), (counter + 1).to_string())
+                        })
+                        .unwrap()
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("1000".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn background_flush_thread() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        flush_mode: FlushMode::Background,
+        flush_every_ms: Some(50),
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // A reader that opens the log afterwards sees the write once it has been
+    // flushed, without the store being flushed or dropped.
+    let reader_config = StoreConfig {
+        read_only: true,
+        ..StoreConfig::default()
+    };
+    let start = Instant::now();
+    loop {
+        let reader = KvStore::open_with_config(temp_dir.path(), reader_config.clone())?;
+        if reader.get("key1".to_owned())? == Some("value1".to_owned()) {
+            break;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "write was not flushed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(store);
+    Ok(())
+}
+
+#[test]
+fn compaction_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_compaction(), None);
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let before = store.stats()?.total_log_bytes;
+    store.compact()?;
+
+    let report = store.last_compaction().expect("no compaction report");
+    assert_eq!(report.bytes_before, before);
+    assert!(report.bytes_after < report.bytes_before);
+    assert_eq!(report.bytes_after, store.stats()?.total_log_bytes);
+    assert_eq!(report.keys_retained, 99);
+    Ok(())
+}
+
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        history_depth: 3,
+        ..StoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+        }
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let log_path = temp_dir.path().join("wal.log");
+    let log_len = fs::metadata(&log_path)?.len();
+
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(fs::metadata(&log_path)?.len(), log_len);
+    assert_eq!(estimate.log_bytes, log_len);
+    assert_eq!(estimate.stale_bytes, store.stats()?.stale_bytes);
+    assert_eq!(estimate.live_bytes + estimate.stale_bytes, estimate.log_bytes);
+
+    // Compaction copies the live records as they are, so the estimate is exact.
+    store.compact()?;
+    assert_eq!(estimate.projected_bytes, fs::metadata(&log_path)?.len());
+    let after = store.compaction_estimate()?;
+    assert_eq!(after.stale_bytes, 0);
+    assert_eq!(after.projected_bytes, after.log_bytes);
+    Ok(())
+}
+
+#[test]
+fn str_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key2".to_owned(), "value2")?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get(String::from("key2"))?, Some("value2".to_owned()));
+    assert!(store.contains_key("key1")?);
+    store.remove("key1")?;
+    assert_eq!(store.get("key1")?, None);
+    assert!(matches!(store.remove("key1"), Err(KvsError::KeyNotFound)));
+
+    store.transaction(|txn| {
+        txn.set("key3", "value3")?;
+        assert_eq!(txn.get("key3")?, Some("value3".to_owned()));
+        txn.remove("key2")
+    })??;
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    let events = store.watch_prefix("key");
+    store.set_bytes("key4", b"bytes")?;
+    assert_eq!(store.get_bytes("key4")?, Some(b"bytes".to_vec()));
+    assert_eq!(store.get_or_insert_with("key5", || "value5".to_owned())?, "value5");
+    assert_eq!(store.get_many(["key3", "key5", "missing"])?.len(), 3);
+    assert_eq!(store.get_versions("key5")?, vec!["value5".to_owned()]);
+    let mut value = Vec::new();
+    assert!(store.get_to_writer("key5", &mut value)?);
+    store.rename("key5", "key6")?;
+    assert_eq!(store.take("key6")?, Some("value5".to_owned()));
+    assert_eq!(events.try_iter().count(), 5);
+    Ok(())
+}
+
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    let shards: Vec<usize> = (0..100).map(|key_id| store.shard_for(&format!("key{}", key_id))).collect();
+    thread::scope(|s| {
+        for t in 0..4 {
+            let store = &store;
+            s.spawn(move || {
+                for key_id in (t..100).step_by(4) {
+                    store.set(format!("key{}", key_id), format!("value{}", key_id)).unwrap();
+                }
+            });
+        }
+    });
+    store.remove("key0".to_owned())?;
+    assert!(matches!(store.remove("key0".to_owned()), Err(KvsError::KeyNotFound)));
+    drop(store);
+
+    // Keys map to the same shards after reopening, and each one is in the log
+    // of its shard.
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    for (key_id, &shard) in shards.iter().enumerate().skip(1) {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.shard_for(&key), shard);
+        assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+    for shard in 0..4 {
+        let config = StoreConfig {
+            name: Some(format!("wal-{}", shard)),
+            read_only: true,
+            ..StoreConfig::default()
+        };
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        for (key_id, &owner) in shards.iter().enumerate().skip(1) {
+            let value = store.get(format!("key{}", key_id))?;
+            assert_eq!(value.is_some(), owner == shard);
+        }
+    }
+
+    assert!(ShardedKvStore::open(temp_dir.path(), 8).is_err());
+    assert!(ShardedKvStore::open(temp_dir.path(), 0).is_err());
+    Ok(())
+}
+
+#[test]
+fn debug_and_display() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let stale_bytes = store.stats()?.stale_bytes;
+    let path = temp_dir.path().join("wal.log");
+
+    assert_eq!(
+        format!("{:?}", store),
+        format!("KvStore {{ path: {:?}, live_keys: 2, stale_bytes: {} }}", path, stale_bytes)
+    );
+    assert_eq!(
+        store.to_string(),
+        format!("KvStore at {} (2 keys, {} stale bytes)", path.display(), stale_bytes)
+    );
+
+    // Formatting the store while its lock is held doesn't deadlock.
+    let inside = store.transaction(|_| format!("{:?} / {}", store, store))?;
+    assert_eq!(inside, "KvStore { .. } / KvStore (locked)");
+    Ok(())
+}
+
+#[test]
+fn export_and_bulk_load() -> Result<()> {
+    let (source_dir, target_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let source = KvStore::open(source_dir.path())?;
+    for i in 0..1000 {
+        source.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    source.set("key0".to_owned(), "new value".to_owned())?;
+    source.remove("key1".to_owned())?;
+    source.set_bytes("bytes".to_owned(), &[0, 159, 255])?;
+
+    let mut dump = Vec::new();
+    assert_eq!(source.export(&mut dump)?, 1000);
+
+    let target = KvStore::open(target_dir.path())?;
+    target.set("key2".to_owned(), "old value".to_owned())?;
+    assert_eq!(target.bulk_load(dump.as_slice())?, 1000);
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some("new value".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+        assert_eq!(store.get_bytes("bytes")?, Some(vec![0, 159, 255]));
+        Ok(())
+    };
+    check(&target)?;
+
+    // Only the overwritten key is stale, and compaction works on the loaded log.
+    let stats = target.stats()?;
+    assert_eq!(stats.live_keys, 1000);
+    assert!(stats.stale_bytes > 0 && stats.stale_bytes < 50);
+    target.compact()?;
+    assert_eq!(target.stats()?.stale_bytes, 0);
+    check(&target)?;
+    drop(target);
+    check(&KvStore::open(target_dir.path())?)?;
+    Ok(())
+}
+
+#[test]
+fn bulk_load_broken_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let dump_path = temp_dir.path().join("backup.dump");
+    store.export(fs::File::create(&dump_path)?)?;
+    drop(store);
+    let dump = fs::read(&dump_path)?;
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(target_dir.path())?;
+    store.set("key0".to_owned(), "old value".to_owned())?;
+    let log_len = |dir: &TempDir| fs::metadata(dir.path().join("wal.log")).map(|m| m.len());
+    let len = log_len(&target_dir)?;
+
+    // Cut mid-record, at a record boundary, and without the trailer.
+    let last_line = dump[..dump.len() - 1].iter().rposition(|&b| b == b'\n').unwrap() + 1;
+    for cut in [dump.len() / 2, last_line, dump.len() - 1] {
+        fs::write(&dump_path, &dump[..cut])?;
+        let result = store.bulk_load(fs::File::open(&dump_path)?);
+        assert!(matches!(result, Err(KvsError::TruncatedDump)), "{:?}", result);
+    }
+
+    // Alter a value without breaking the JSON.
+    let altered = String::from_utf8(dump.clone()).unwrap().replace("value42", "value24");
+    let result = store.bulk_load(altered.as_bytes());
+    assert!(matches!(result, Err(KvsError::ChecksumMismatch)), "{:?}", result);
+
+    // Nothing was loaded, and the log is as it was.
+    assert_eq!(log_len(&target_dir)?, len);
+    assert_eq!(store.get("key0".to_owned())?, Some("old value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(target_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The intact dump loads.
+    assert_eq!(store.bulk_load(dump.as_slice())?, 100);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert!(store.bulk_load(&b"{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n"[..]).is_err());
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Request, Response, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Records every log message so that tests can check what the server logged.
+struct CapturingLogger(Mutex<Vec<(Level, String)>>);
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+        self.0.lock().unwrap().push((record.level(), message));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+fn errors() -> Vec<String> {
+    let records = LOGGER.0.lock().unwrap();
+    records
+        .iter()
+        .filter(|(level, _)| *level == Level::Error)
+        .map(|(_, message)| message.clone())
+        .collect()
+}
+
+#[test]
+fn client_disconnect_is_not_an_error() -> Result<()> {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    // A single worker handles the connections one after another, in order.
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(1)?);
+    thread::spawn(move || server.run(addr));
+    let mut client = loop {
+        match KvsClient::connect(addr) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    // A client that goes away after one request.
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+
+    // A client that goes away in the middle of a request.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Get":{"key":"#)?;
+    drop(stream);
+
+    // A client that sends garbage, which is answered rather than an error.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"garbage")?;
+    let response = serde_json::Deserializer::from_reader(&stream).into_iter::<Response>().next();
+    assert!(matches!(response, Some(Ok(Response::Err(_)))));
+    drop(stream);
+
+    // Once the next client is served, the worker is done with the others.
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let errors = errors();
+    assert!(errors.is_empty(), "unexpected errors: {:?}", errors);
+    Ok(())
+}
+
+#[test]
+fn handle_in_memory_stream() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(1)?);
+    let requests = [
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        Request::Get { key: "key1".to_owned() },
+        Request::Get { key: "key2".to_owned() },
+        Request::Remove { key: "key2".to_owned() },
+    ];
+    let mut input = Vec::new();
+    for request in &requests {
+        serde_json::to_writer(&mut input, request)?;
+    }
+
+    let mut output = Vec::new();
+    server.handle(input.as_slice(), &mut output)?;
+    let responses = serde_json::Deserializer::from_slice(&output)
+        .into_iter::<Response>()
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    assert!(matches!(
+        responses.as_slice(),
+        [Response::Ok(None), Response::Ok(Some(value)), Response::Ok(None), Response::KeyNotFound] if value == "value1"
+    ));
+    Ok(())
+}
+
+#[test]
+fn malformed_requests_keep_the_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(1)?);
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    let get = Request::Get { key: "key1".to_owned() };
+    // JSON that isn't a request, and bytes that aren't JSON at all, up to the
+    // end of their line, including a request in them.
+    let mut input = serde_json::to_vec(&set)?;
+    input.extend_from_slice(br#"{"Frobnicate":{"key":"key1"}}"#);
+    serde_json::to_writer(&mut input, &get)?;
+    input.extend_from_slice(br#"garbage {"Remove":{"key":"key1"}} ]]"#);
+    input.push(b'\n');
+    serde_json::to_writer(&mut input, &get)?;
+
+    let mut output = Vec::new();
+    server.handle(input.as_slice(), &mut output)?;
+    let responses = serde_json::Deserializer::from_slice(&output)
+        .into_iter::<Response>()
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    assert!(
+        matches!(
+            responses.as_slice(),
+            [
+                Response::Ok(None),
+                Response::Err(e1),
+                Response::Ok(Some(v1)),
+                Response::Err(e2),
+                Response::Ok(Some(v2)),
+            ] if e1.starts_with("Malformed request: unknown variant `Frobnicate`")
+                && e2.starts_with("Malformed request: expected value")
+                && v1 == "value1"
+                && v2 == "value1"
+        ),
+        "{:?}",
+        responses
+    );
+    Ok(())
+}
+
+#[test]
+fn request_id_runs_request_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(1)?);
+    let with_id = |id, request| Request::WithId {
+        client: 7,
+        id,
+        request: Box::new(request),
+    };
+    let requests = [
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        with_id(1, Request::Remove { key: "key1".to_owned() }),
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value2".to_owned(),
+        },
+        // A retry gets the first response and doesn't remove the new value.
+        with_id(1, Request::Remove { key: "key1".to_owned() }),
+        with_id(2, Request::Remove { key: "key2".to_owned() }),
+        with_id(2, Request::Remove { key: "key2".to_owned() }),
+    ];
+    let mut input = Vec::new();
+    for request in &requests {
+        serde_json::to_writer(&mut input, request)?;
+    }
+
+    let mut output = Vec::new();
+    server.handle(input.as_slice(), &mut output)?;
+    let responses = serde_json::Deserializer::from_slice(&output)
+        .into_iter::<Response>()
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    assert!(matches!(
+        responses.as_slice(),
+        [
+            Response::Ok(None),
+            Response::Ok(None),
+            Response::Ok(None),
+            Response::Ok(None),
+            Response::KeyNotFound,
+            Response::KeyNotFound,
+        ]
+    ));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn request_ids_are_kept_apart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(1)?);
+    let set = |client, value: &str| Request::WithId {
+        client,
+        id: 1,
+        request: Box::new(Request::Set {
+            key: "key1".to_owned(),
+            value: value.to_owned(),
+        }),
+    };
+    let get = |client| Request::WithId {
+        client,
+        id: 2,
+        request: Box::new(Request::Get { key: "key1".to_owned() }),
+    };
+    let requests = [
+        set(1, "value1"),
+        get(1),
+        // Another client's id 1 is a request of its own.
+        set(2, "value2"),
+        get(2),
+        // The same id with a different request isn't answered from the cache.
+        set(1, "value3"),
+        get(1),
+    ];
+    let mut input = Vec::new();
+    for request in &requests {
+        serde_json::to_writer(&mut input, request)?;
+    }
+
+    let mut output = Vec::new();
+    server.handle(input.as_slice(), &mut output)?;
+    let responses = serde_json::Deserializer::from_slice(&output)
+        .into_iter::<Response>()
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    assert!(
+        matches!(
+            responses.as_slice(),
+            [
+                Response::Ok(None),
+                Response::Ok(Some(v1)),
+                Response::Ok(None),
+                Response::Ok(Some(v2)),
+                Response::Err(e),
+                Response::Ok(Some(v3)),
+            ] if v1 == "value1"
+                && v2 == "value2"
+                && e == "Request id reused for a different request"
+                && v3 == "value1"
+        ),
+        "{:?}",
+        responses
+    );
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A `KvStore` whose first `set` panics.
+#[derive(Clone)]
+struct PanicOnceEngine {
+    store: KvStore,
+    panicked: Arc<AtomicBool>,
+}
+
+impl KvsEngine for PanicOnceEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if !self.panicked.swap(true, Ordering::SeqCst) {
+            panic_control::disable_hook_in_current_thread();
+            panic!("boom");
+        }
+        self.store.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+}
+
+#[test]
+fn request_id_retry_after_panic_runs_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = PanicOnceEngine {
+        store: store.clone(),
+        panicked: Arc::new(AtomicBool::new(false)),
+    };
+    let server = Arc::new(KvsServer::new(engine, SharedQueueThreadPool::new(1)?));
+    let mut input = Vec::new();
+    let request = Request::WithId {
+        client: 7,
+        id: 1,
+        request: Box::new(Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        }),
+    };
+    serde_json::to_writer(&mut input, &request)?;
+
+    let first = {
+        let (server, input) = (Arc::clone(&server), input.clone());
+        thread::spawn(move || server.handle(input.as_slice(), Vec::new()))
+    };
+    assert!(first.join().is_err());
+
+    // The retry runs the request instead of waiting for the attempt that panicked.
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let result = server.handle(input.as_slice(), &mut output);
+        sender.send(result.map(|_| output)).unwrap();
+    });
+    let output = receiver.recv_timeout(Duration::from_secs(5)).expect("the retry hung")?;
+    assert!(matches!(serde_json::from_slice(&output)?, Response::Ok(None)));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
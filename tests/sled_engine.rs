@@ -0,0 +1,188 @@
+#![cfg(feature = "sled")]
+
+use kvs::{Event, FlushMode, KvsEngine, KvsError, Result, SledKvsEngine, StoreConfig};
+use std::sync::Barrier;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn background_flush_persists_after_explicit_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        flush_mode: FlushMode::Background,
+        flush_every_ms: Some(60_000),
+        ..StoreConfig::default()
+    };
+    let db = SledKvsEngine::open_with_config(temp_dir.path(), config.clone())?;
+    db.set("key1".to_owned(), "value1".to_owned())?;
+    db.set("key2".to_owned(), "value2".to_owned())?;
+    db.remove("key2".to_owned())?;
+    db.flush()?;
+    drop(db);
+
+    let db = SledKvsEngine::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(db.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(db.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn small_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StoreConfig {
+        sled_cache_capacity: Some(64 * 1024),
+        ..StoreConfig::default()
+    };
+    let db = SledKvsEngine::open_with_config(temp_dir.path(), config)?;
+    for i in 0..1000 {
+        db.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..1000 {
+        assert_eq!(db.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    db.remove("key0".to_owned())?;
+    assert_eq!(db.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = SledKvsEngine::open(temp_dir.path())?;
+    let events = db.watch_prefix("user:".to_owned())?;
+
+    db.set("other".to_owned(), "ignored".to_owned())?;
+    db.set("user:1".to_owned(), "alice".to_owned())?;
+    db.remove("user:1".to_owned())?;
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::Set {
+            key: "user:1".to_owned(),
+            value: "alice".to_owned()
+        }
+    );
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::Remove {
+            key: "user:1".to_owned()
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn get_or_insert_with_runs_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    let calls = AtomicUsize::new(0);
+    let barrier = Barrier::new(8);
+
+    let values: Vec<String> = thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let (store, calls, barrier) = (store.clone(), &calls, &barrier);
+                s.spawn(move || {
+                    barrier.wait();
+                    store
+                        .get_or_insert_with("key".to_owned(), || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            format!("value{}", thread_id)
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(values.iter().all(|value| *value == values[0]));
+    assert_eq!(store.get("key".to_owned())?, Some(values[0].clone()));
+    // An existing value is returned without calling the closure.
+    assert_eq!(store.get_or_insert_with("key".to_owned(), || unreachable!())?, values[0]);
+    Ok(())
+}
+
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    let values: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        b"a\0b\0".to_vec(),
+        vec![0xff, 0xfe, 0x80, 0x00, 0xc3],
+        (0..=255).collect(),
+    ];
+    for (i, value) in values.iter().enumerate() {
+        store.set_bytes(format!("key{}", i), value)?;
+    }
+    store.set("text".to_owned(), "value".to_owned())?;
+
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(store.get_bytes(&format!("key{}", i))?.as_ref(), Some(value));
+    }
+    assert_eq!(store.get_bytes("text")?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("missing")?, None);
+    // Valid UTF-8 reads back as a string; anything else is an error.
+    assert_eq!(store.get("key2".to_owned())?, Some("a\0b\0".to_owned()));
+    assert!(store.get("key3".to_owned()).is_err());
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.get_bytes("key3")?, None);
+    Ok(())
+}
+
+#[test]
+fn named_engines_share_a_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |name: &str| {
+        let config = StoreConfig {
+            name: Some(name.to_owned()),
+            ..StoreConfig::default()
+        };
+        SledKvsEngine::open_with_config(temp_dir.path(), config)
+    };
+    let users = open("users")?;
+    let orders = open("orders")?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    orders.set("key".to_owned(), "order".to_owned())?;
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key".to_owned())?, Some("order".to_owned()));
+    assert!(temp_dir.path().join("users").is_dir());
+    assert!(temp_dir.path().join("orders").is_dir());
+    Ok(())
+}
+
+#[test]
+fn take() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.take("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.take("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("staging".to_owned(), "value1".to_owned())?;
+    store.set("production".to_owned(), "value0".to_owned())?;
+
+    store.rename("staging".to_owned(), "production".to_owned())?;
+    assert_eq!(store.get("staging".to_owned())?, None);
+    assert_eq!(store.get("production".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.rename("staging".to_owned(), "other".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("other".to_owned())?, None);
+    Ok(())
+}
@@ -1,12 +1,14 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
 use kvs::Result;
 
 use crossbeam_utils::sync::WaitGroup;
 
-fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+fn spawn_counter<P: ThreadPool>(pool: &P) -> Result<()> {
     const TASK_NUM: usize = 20;
     const ADD_COUNT: usize = 1000;
 
@@ -43,28 +45,283 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
         })
     }
 
-    spawn_counter(pool)
+    spawn_counter(&pool)
 }
 
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
-    spawn_counter(pool)
+    spawn_counter(&pool)
 }
 
 #[test]
 fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     let pool = SharedQueueThreadPool::new(4)?;
-    spawn_counter(pool)
+    spawn_counter(&pool)
 }
 
 #[test]
 fn rayon_thread_pool_spawn_counter() -> Result<()> {
     let pool = RayonThreadPool::new(4)?;
-    spawn_counter(pool)
+    spawn_counter(&pool)
 }
 
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+
+    pool.resize(8)?;
+    assert_eq!(pool.size(), 8);
+    spawn_counter(&pool)?;
+
+    pool.resize(1)?;
+    assert_eq!(pool.size(), 1);
+    spawn_counter(&pool)?;
+
+    // Dropping the pool must join the remaining worker cleanly.
+    drop(pool);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_join() -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    let pool = SharedQueueThreadPool::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    // The pool is still usable after joining.
+    spawn_counter(&pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_metrics() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(|| thread::sleep(Duration::from_millis(20)));
+    }
+
+    let metrics = pool.metrics();
+    assert!(metrics.queued > 0);
+    assert!(metrics.busy <= 2);
+
+    pool.join();
+    let metrics = pool.metrics();
+    assert_eq!(metrics.queued, 0);
+    assert_eq!(metrics.busy, 0);
+    assert_eq!(metrics.processed, TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_bounds_concurrency() -> Result<()> {
+    const LIMIT: usize = 3;
+    const TASK_NUM: usize = 30;
+
+    let pool = NaiveThreadPool::new(LIMIT as u32)?;
+    let wg = WaitGroup::new();
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..TASK_NUM {
+        let running = Arc::clone(&running);
+        let max_running = Arc::clone(&max_running);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            drop(wg);
+        })
+    }
+
+    wg.wait();
+    assert!(max_running.load(Ordering::SeqCst) <= LIMIT);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_handler() -> Result<()> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let pool = SharedQueueThreadPool::new(1)?.with_panic_handler(move |id, payload| {
+        let message = payload.downcast_ref::<&str>().copied().unwrap_or_default();
+        sender.send((id, thread::current().name().map(str::to_owned), message.to_owned())).unwrap();
+    });
+
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+
+    let (id, name, message) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(id, 0);
+    assert_eq!(name.as_deref(), Some("kvs-worker-0"));
+    assert_eq!(message, "boom");
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_panicking_panic_handler() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?.with_panic_handler(|_, _| panic!("handler boom"));
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+    pool.join();
+
+    // The worker survived and still runs jobs.
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    pool.spawn(move || sender.send(()).unwrap());
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    pool.join();
+    assert_eq!(pool.metrics().busy, 0);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_config() -> Result<()> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let panics = Arc::new(AtomicUsize::new(0));
+    let handler_panics = Arc::clone(&panics);
+    let pool = RayonThreadPool::with_config(2, "test-rayon-", move |_| {
+        handler_panics.fetch_add(1, Ordering::SeqCst);
+    })?;
+
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    pool.spawn(move || {
+        sender.send(thread::current().name().map(str::to_owned)).unwrap();
+    });
+
+    let name = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(name.unwrap().starts_with("test-rayon-"));
+    for _ in 0..100 {
+        if panics.load(Ordering::SeqCst) == 1 {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("panic handler was not invoked");
+}
+
+#[test]
+fn shared_queue_thread_pool_queue_capacity() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_capacity(1, 2)?;
+    let (started, started_receiver) = crossbeam_channel::bounded(0);
+    let (release, release_receiver) = crossbeam_channel::unbounded::<()>();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        release_receiver.recv().ok();
+    });
+    started_receiver.recv().unwrap();
+
+    // The worker is busy, so jobs queue up until the queue is full.
+    assert!(!pool.is_full());
+    pool.spawn(|| {});
+    assert!(!pool.is_full());
+    pool.spawn(|| {});
+    assert!(pool.is_full());
+
+    drop(release);
+    pool.join();
+    assert!(!pool.is_full());
+    assert_eq!(pool.metrics().processed, 3);
+    assert!(!RayonThreadPool::new(1)?.is_full());
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_is_full() -> Result<()> {
+    let pool = NaiveThreadPool::new(1)?;
+    let (release, release_receiver) = crossbeam_channel::unbounded::<()>();
+    assert!(!pool.is_full());
+    pool.spawn(move || {
+        release_receiver.recv().ok();
+    });
+    // The running job holds the only permit, so `spawn` would block.
+    assert!(pool.is_full());
+
+    drop(release);
+    let start = Instant::now();
+    while pool.is_full() {
+        assert!(start.elapsed() < Duration::from_secs(5), "the permit was never returned");
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_timeout() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?.with_shutdown_timeout(Duration::from_millis(100));
+    let (started, started_receiver) = crossbeam_channel::bounded(0);
+    let (release, release_receiver) = crossbeam_channel::unbounded::<()>();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        release_receiver.recv().ok();
+    });
+    started_receiver.recv().unwrap();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let job_counter = Arc::clone(&counter);
+    pool.spawn(move || {
+        job_counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    // The stuck job is abandoned, but the other worker still finishes.
+    let start = Instant::now();
+    drop(pool);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(100), "drop returned after {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "drop returned after {:?}", elapsed);
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+    drop(release);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_timeout_with_full_queue() -> Result<()> {
+    let pool =
+        SharedQueueThreadPool::with_queue_capacity(1, 1)?.with_shutdown_timeout(Duration::from_millis(100));
+    let (started, started_receiver) = crossbeam_channel::bounded(0);
+    let (release, release_receiver) = crossbeam_channel::unbounded::<()>();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        release_receiver.recv().ok();
+    });
+    started_receiver.recv().unwrap();
+    pool.spawn(|| {});
+    assert!(pool.is_full());
+
+    // There is no room in the queue for the `Terminate` message.
+    let start = Instant::now();
+    drop(pool);
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(1), "drop returned after {:?}", elapsed);
+    drop(release);
+    Ok(())
+}